@@ -0,0 +1,343 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use super::{BackendError, ClipboardBackend};
+use crate::{Message, Selection};
+
+/// Argument replaced by the MIME type of the message.
+const TYPE_PLACEHOLDER: &str = "{type}";
+
+/// Clipboard accessed through external commands.
+///
+/// The copy commands receive the message on their stdin, the paste command prints the
+/// clipboard on its stdout. Text messages are converted to UTF-8 before being copied.
+#[derive(Debug, Clone)]
+pub struct CommandBackend {
+    copy: Vec<String>,
+    /// Copy to the primary selection
+    primary: Option<Vec<String>>,
+    paste: Option<Vec<String>>,
+    /// Whether the copy command can store any content type
+    any_type: bool,
+}
+
+impl CommandBackend {
+    /// Run `copy` and `paste` programs with their arguments. `{type}` arguments are
+    /// replaced by the MIME type of the message.
+    pub fn new(copy: Vec<String>, paste: Option<Vec<String>>, any_type: bool) -> Self {
+        Self {
+            copy,
+            primary: None,
+            paste,
+            any_type,
+        }
+    }
+
+    /// Copy to the primary selection with the `primary` program and its arguments.
+    pub fn with_primary(mut self, primary: Vec<String>) -> Self {
+        self.primary = Some(primary);
+        self
+    }
+
+    /// Run shell commands. The MIME type of the message is exposed as
+    /// `COPIEPATE_CONTENT_TYPE`, the commands are trusted to handle any content type. The
+    /// backend has no primary selection unless one is set with `with_shell_primary`.
+    pub fn shell(copy: &str, paste: Option<&str>) -> Self {
+        Self::new(shell(copy), paste.map(shell), true)
+    }
+
+    /// Copy to the primary selection with the `primary` shell command, run with
+    /// `COPIEPATE_SELECTION` set to `primary`.
+    pub fn with_shell_primary(self, primary: &str) -> Self {
+        self.with_primary(shell(primary))
+    }
+
+    /// Wayland clipboard with `wl-copy` and `wl-paste`.
+    pub fn wayland() -> Self {
+        Self::new(
+            args(&["wl-copy", "--type", TYPE_PLACEHOLDER]),
+            Some(args(&["wl-paste", "--no-newline"])),
+            true,
+        )
+        .with_primary(args(&["wl-copy", "--primary", "--type", TYPE_PLACEHOLDER]))
+    }
+
+    /// X11 clipboard with `xclip`.
+    pub fn xclip() -> Self {
+        Self::new(
+            args(&["xclip", "-selection", "clipboard", "-t", TYPE_PLACEHOLDER]),
+            Some(args(&["xclip", "-selection", "clipboard", "-o"])),
+            true,
+        )
+        .with_primary(args(&[
+            "xclip",
+            "-selection",
+            "primary",
+            "-t",
+            TYPE_PLACEHOLDER,
+        ]))
+    }
+
+    /// X11 clipboard with `xsel`, text only.
+    pub fn xsel() -> Self {
+        Self::new(
+            args(&["xsel", "--clipboard", "--input"]),
+            Some(args(&["xsel", "--clipboard", "--output"])),
+            false,
+        )
+        .with_primary(args(&["xsel", "--primary", "--input"]))
+    }
+
+    /// tmux paste buffer, text only. `load-buffer` is the stdin counterpart of
+    /// `set-buffer`.
+    pub fn tmux() -> Self {
+        Self::new(
+            args(&["tmux", "load-buffer", "-"]),
+            Some(args(&["tmux", "save-buffer", "-"])),
+            false,
+        )
+    }
+
+    fn command(
+        command_line: &[String],
+        mime_type: &str,
+        selection: Selection,
+    ) -> Result<Command, BackendError> {
+        let (program, arguments) = command_line
+            .split_first()
+            .ok_or_else(|| BackendError::Config(String::from("empty command")))?;
+        let mut command = Command::new(program);
+        command
+            .args(arguments.iter().map(|argument| {
+                if argument == TYPE_PLACEHOLDER {
+                    mime_type
+                } else {
+                    argument
+                }
+            }))
+            .env("COPIEPATE_CONTENT_TYPE", mime_type)
+            .env("COPIEPATE_SELECTION", selection_name(selection));
+        Ok(command)
+    }
+
+    fn copy(
+        command_line: &[String],
+        message: &Message,
+        selection: Selection,
+    ) -> Result<(), BackendError> {
+        let content = if message.is_text() {
+            let text = message.to_text().ok_or_else(|| {
+                BackendError::Unsupported(format!(
+                    "can't decode charset '{}' as text",
+                    message.charset.as_deref().unwrap_or_default()
+                ))
+            })?;
+            text.into_bytes()
+        } else {
+            message.content.clone()
+        };
+
+        // Clipboard tools such as wl-copy and xclip keep running in the background to serve
+        // the clipboard: their output must not be captured, or waiting for it would block.
+        let mut child = Self::command(command_line, &message.mime_type(), selection)?
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        // Wait for the command even if it stopped reading its stdin, so that it is reaped
+        // and its exit status reported rather than the write error. Commands exiting
+        // successfully without reading their whole stdin chose to ignore it.
+        let written = match child.stdin.take() {
+            Some(mut stdin) => stdin.write_all(&content),
+            None => Err(std::io::Error::other("failed to open command stdin")),
+        };
+
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(BackendError::Command {
+                command: command_line.join(" "),
+                status,
+            });
+        }
+        match written {
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ClipboardBackend for CommandBackend {
+    fn set_contents(&mut self, message: &Message) -> Result<(), BackendError> {
+        Self::copy(&self.copy, message, Selection::Clipboard)
+    }
+
+    fn set_primary(&mut self, message: &Message) -> Result<(), BackendError> {
+        let primary = self.primary.as_ref().ok_or_else(|| {
+            BackendError::Unsupported(String::from(
+                "the clipboard backend has no primary selection",
+            ))
+        })?;
+        Self::copy(primary, message, Selection::Primary)
+    }
+
+    fn supports_primary(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    fn get_contents(&mut self) -> Result<Message, BackendError> {
+        let paste = self.paste.as_ref().ok_or_else(|| {
+            BackendError::Config(String::from("no command to read the clipboard"))
+        })?;
+        let output = Self::command(paste, crate::DEFAULT_CONTENT_TYPE, Selection::Clipboard)?
+            .stdin(Stdio::null())
+            .output()?;
+        if !output.status.success() {
+            return Err(BackendError::Command {
+                command: paste.join(" "),
+                status: output.status,
+            });
+        }
+
+        let message = Message::new(output.stdout).with_content_type(crate::DEFAULT_CONTENT_TYPE);
+        Ok(match std::str::from_utf8(&message.content) {
+            Ok(_) => message.with_charset("utf-8"),
+            Err(_) => message,
+        })
+    }
+
+    fn supports(&self, mime_type: &str) -> bool {
+        self.any_type || mime_type.starts_with("text/")
+    }
+}
+
+fn selection_name(selection: Selection) -> &'static str {
+    match selection {
+        Selection::Clipboard => "clipboard",
+        Selection::Primary => "primary",
+        Selection::Both => "both",
+    }
+}
+
+fn shell(command: &str) -> Vec<String> {
+    vec![String::from("sh"), String::from("-c"), command.into()]
+}
+
+fn args(arguments: &[&str]) -> Vec<String> {
+    arguments.iter().map(|&argument| argument.into()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::TempDir;
+
+    /// Shell backend storing the clipboard in the `clipboard` file of a temporary directory.
+    fn file_backend(name: &str) -> (CommandBackend, TempDir) {
+        let dir = TempDir::new(name);
+        let path = dir.join("clipboard");
+        let path_str = path.to_str().unwrap();
+        let backend = CommandBackend::shell(
+            &format!("cat > '{path_str}'; echo \"$COPIEPATE_CONTENT_TYPE\" > '{path_str}.type'"),
+            Some(&format!("cat '{path_str}'")),
+        );
+        (backend, dir)
+    }
+
+    #[test]
+    fn test_shell_roundtrip() {
+        let (mut backend, dir) = file_backend("roundtrip");
+        let message = Message::text(String::from("Test message"));
+        backend.set_contents(&message).unwrap();
+
+        assert_eq!(message, backend.get_contents().unwrap());
+        let content_type = std::fs::read_to_string(dir.join("clipboard.type")).unwrap();
+        assert_eq!("text/plain\n", content_type);
+    }
+
+    #[test]
+    fn test_shell_binary_content() {
+        let (mut backend, dir) = file_backend("binary");
+        let message =
+            Message::new(vec![0x89, b'P', b'N', b'G', 0xff]).with_content_type("image/png");
+        assert!(backend.supports(&message.mime_type()));
+        backend.set_contents(&message).unwrap();
+
+        assert_eq!(
+            message.content,
+            std::fs::read(dir.join("clipboard")).unwrap()
+        );
+        let content_type = std::fs::read_to_string(dir.join("clipboard.type")).unwrap();
+        assert_eq!("image/png\n", content_type);
+    }
+
+    #[test]
+    fn test_text_converted_to_utf8() {
+        let (mut backend, dir) = file_backend("latin1");
+        let message = Message::new(vec![b'c', 0xe9]).with_charset("iso-8859-1");
+        backend.set_contents(&message).unwrap();
+
+        assert_eq!(
+            "c\u{e9}",
+            std::fs::read_to_string(dir.join("clipboard")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_failed_command() {
+        let mut backend = CommandBackend::shell("cat > /dev/null; exit 3", None);
+        let message = Message::text(String::from("Test message"));
+        assert!(matches!(
+            backend.set_contents(&message),
+            Err(BackendError::Command { .. })
+        ));
+        assert!(matches!(
+            backend.get_contents(),
+            Err(BackendError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_command_ignoring_stdin() {
+        // Larger than pipe buffers, the command exits before the content is written
+        let message = Message::new(vec![b'a'; 1024 * 1024]);
+        CommandBackend::shell("exit 0", None)
+            .set_contents(&message)
+            .unwrap();
+        assert!(matches!(
+            CommandBackend::shell("exit 3", None).set_contents(&message),
+            Err(BackendError::Command { .. })
+        ));
+    }
+
+    #[test]
+    fn test_shell_primary_opt_in() {
+        let mut backend = CommandBackend::shell("cat > /dev/null", None);
+        let message = Message::text(String::from("Test message"));
+        assert!(!backend.supports_primary());
+        assert!(matches!(
+            backend.set_primary(&message),
+            Err(BackendError::Unsupported(_))
+        ));
+
+        let (backend, dir) = file_backend("primary");
+        let mut backend = backend.with_shell_primary(&format!(
+            "echo \"$COPIEPATE_SELECTION\" > '{}'",
+            dir.join("clipboard").display()
+        ));
+        assert!(backend.supports_primary());
+        backend.set_primary(&message).unwrap();
+        assert_eq!(
+            "primary\n",
+            std::fs::read_to_string(dir.join("clipboard")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_text_only_backend() {
+        assert!(CommandBackend::xsel().supports("text/html"));
+        assert!(!CommandBackend::xsel().supports("image/png"));
+        assert!(CommandBackend::wayland().supports("image/png"));
+    }
+}
@@ -0,0 +1,22 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum BackendError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid backend configuration: {0}")]
+    Config(String),
+
+    #[error("Clipboard error: {0}")]
+    Clipboard(String),
+
+    #[error("Command `{command}` failed with {status}")]
+    Command {
+        command: String,
+        status: std::process::ExitStatus,
+    },
+
+    #[error("Unsupported content: {0}")]
+    Unsupported(String),
+}
@@ -0,0 +1,154 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{BackendError, ClipboardBackend};
+use crate::Message;
+
+/// Headless clipboard, for servers without any display.
+#[derive(Debug, Clone)]
+pub enum FileBackend {
+    /// Overwrite a file with each message
+    File(PathBuf),
+    /// Write each message to a new file named after its reception time
+    Directory(PathBuf),
+    /// Write messages to stdout, each followed by a NUL byte
+    Stdout,
+}
+
+impl FileBackend {
+    /// Write-only backends can't be read with `copiepate --paste`.
+    fn read(&self) -> Result<Vec<u8>, BackendError> {
+        match self {
+            FileBackend::File(path) => Ok(fs::read(path)?),
+            FileBackend::Directory(path) => {
+                // File names are timestamps, the most recent one is the last one
+                let latest = fs::read_dir(path)?
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+                    .map(|entry| entry.path())
+                    .max()
+                    .ok_or_else(|| {
+                        BackendError::Clipboard(format!("no message in {}", path.display()))
+                    })?;
+                Ok(fs::read(latest)?)
+            }
+            FileBackend::Stdout => Err(BackendError::Clipboard(String::from(
+                "messages written to stdout can't be read back",
+            ))),
+        }
+    }
+}
+
+impl ClipboardBackend for FileBackend {
+    fn set_contents(&mut self, message: &Message) -> Result<(), BackendError> {
+        let content = if message.is_text() {
+            message
+                .to_text()
+                .ok_or_else(|| {
+                    BackendError::Unsupported(format!(
+                        "can't decode charset '{}' as text",
+                        message.charset.as_deref().unwrap_or_default()
+                    ))
+                })?
+                .into_bytes()
+        } else {
+            message.content.clone()
+        };
+
+        match self {
+            FileBackend::File(path) => fs::write(path, content)?,
+            FileBackend::Directory(path) => {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let name = format!(
+                    "{}.{:09}.{}",
+                    timestamp.as_secs(),
+                    timestamp.subsec_nanos(),
+                    extension(&message.mime_type())
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(path.join(name))?
+                    .write_all(&content)?;
+            }
+            FileBackend::Stdout => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&content)?;
+                stdout.write_all(b"\0")?;
+                stdout.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_contents(&mut self) -> Result<Message, BackendError> {
+        let message = Message::new(self.read()?).with_content_type(crate::DEFAULT_CONTENT_TYPE);
+        Ok(match std::str::from_utf8(&message.content) {
+            Ok(_) => message.with_charset("utf-8"),
+            Err(_) => message,
+        })
+    }
+
+    fn supports(&self, _mime_type: &str) -> bool {
+        true
+    }
+}
+
+/// File extension of a MIME type, such as `png` for `image/png`.
+fn extension(mime_type: &str) -> &str {
+    match mime_type.split_once('/') {
+        Some(("text", "plain")) => "txt",
+        Some((_, subtype)) if subtype.chars().all(|c| c.is_ascii_alphanumeric()) => subtype,
+        _ => "bin",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::TempDir;
+
+    #[test]
+    fn test_file_roundtrip() {
+        let dir = TempDir::new("file");
+        let mut backend = FileBackend::File(dir.join("clipboard"));
+        let message = Message::text(String::from("Test message"));
+        backend.set_contents(&message).unwrap();
+        assert_eq!(message, backend.get_contents().unwrap());
+    }
+
+    #[test]
+    fn test_directory() {
+        let dir = TempDir::new("directory");
+        let mut backend = FileBackend::Directory(dir.path().to_path_buf());
+
+        let image = Message::new(vec![0x89, b'P', b'N', b'G']).with_content_type("image/png");
+        backend.set_contents(&image).unwrap();
+        let text = Message::text(String::from("Test message"));
+        backend.set_contents(&text).unwrap();
+
+        let mut names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(2, names.len());
+        assert!(names[0].ends_with(".png"));
+        assert!(names[1].ends_with(".txt"));
+        assert_eq!(text, backend.get_contents().unwrap());
+    }
+
+    #[test]
+    fn test_extension() {
+        assert_eq!("txt", extension("text/plain"));
+        assert_eq!("html", extension("text/html"));
+        assert_eq!("png", extension("image/png"));
+        assert_eq!("bin", extension("image/svg+xml"));
+    }
+}
@@ -0,0 +1,250 @@
+//! Clipboards the server can write messages to.
+use std::{path::PathBuf, str::FromStr};
+
+use crate::Message;
+
+pub use self::{
+    command::CommandBackend,
+    error::BackendError,
+    file::FileBackend,
+    osc52::{Osc52Backend, Passthrough, DEFAULT_OSC52_MAX_SIZE},
+    provider::ClipboardProviderBackend,
+};
+
+mod command;
+mod error;
+mod file;
+mod osc52;
+mod provider;
+
+#[cfg(test)]
+mod testing {
+    use std::path::{Path, PathBuf};
+
+    /// Temporary directory unique to a test, removed with its content once dropped.
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "copiepate-{name}-{}-{:016x}",
+                std::process::id(),
+                rand::random::<u64>()
+            ));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.0
+        }
+
+        pub fn join(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+}
+
+/// Clipboard of the server.
+pub trait ClipboardBackend {
+    /// Write a message to the clipboard.
+    fn set_contents(&mut self, message: &Message) -> Result<(), BackendError>;
+
+    /// Write a message to the primary selection.
+    fn set_primary(&mut self, _message: &Message) -> Result<(), BackendError> {
+        Err(BackendError::Unsupported(String::from(
+            "the clipboard backend has no primary selection",
+        )))
+    }
+
+    /// Whether the backend has a primary selection.
+    fn supports_primary(&self) -> bool {
+        false
+    }
+
+    /// Read the content of the clipboard.
+    fn get_contents(&mut self) -> Result<Message, BackendError>;
+
+    /// Whether the clipboard can store content of this MIME type, only text by default.
+    fn supports(&self, mime_type: &str) -> bool {
+        mime_type.starts_with("text/")
+    }
+}
+
+impl<B: ClipboardBackend + ?Sized> ClipboardBackend for Box<B> {
+    fn set_contents(&mut self, message: &Message) -> Result<(), BackendError> {
+        (**self).set_contents(message)
+    }
+
+    fn set_primary(&mut self, message: &Message) -> Result<(), BackendError> {
+        (**self).set_primary(message)
+    }
+
+    fn supports_primary(&self) -> bool {
+        (**self).supports_primary()
+    }
+
+    fn get_contents(&mut self) -> Result<Message, BackendError> {
+        (**self).get_contents()
+    }
+
+    fn supports(&self, mime_type: &str) -> bool {
+        (**self).supports(mime_type)
+    }
+}
+
+/// Built-in clipboard backends, selected by the `backend` configuration key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Detect the backend from the environment
+    Auto,
+    /// System clipboard, through the `clipboard` crate
+    Clipboard,
+    /// Wayland clipboard, through `wl-copy` and `wl-paste`
+    Wayland,
+    /// X11 clipboard, through `xclip`
+    Xclip,
+    /// X11 clipboard, through `xsel`
+    Xsel,
+    /// tmux paste buffer
+    Tmux,
+    /// Custom copy and paste commands
+    Command,
+    /// Terminal clipboard, through OSC 52 escape sequences
+    Osc52,
+    /// Overwrite a file with each message
+    File,
+    /// Write each message to a new file in a directory
+    Directory,
+    /// Write messages to stdout, delimited by NUL bytes
+    Stdout,
+}
+
+/// Settings of the built-in backends.
+#[derive(Debug, Clone, Default)]
+pub struct BackendOptions {
+    /// Shell command writing its stdin to the clipboard, for the `command` backend
+    pub copy_command: Option<String>,
+    /// Shell command printing the clipboard, for the `command` backend
+    pub paste_command: Option<String>,
+    /// Shell command writing its stdin to the primary selection, for the `command`
+    /// backend, which has no primary selection otherwise
+    pub primary_command: Option<String>,
+    /// Terminal OSC 52 sequences are written to, stdout if not set
+    pub osc52_tty: Option<PathBuf>,
+    /// Multiplexer passthrough of OSC 52 sequences, detected if not set
+    pub osc52_passthrough: Option<Passthrough>,
+    /// Maximum size of OSC 52 sequences
+    pub osc52_max_size: Option<usize>,
+    /// File or directory messages are written to, for the `file` and `directory` backends
+    pub output_path: Option<PathBuf>,
+}
+
+impl BackendOptions {
+    fn output_path(&self) -> Result<PathBuf, BackendError> {
+        self.output_path.clone().ok_or_else(|| {
+            BackendError::Config(String::from(
+                "the file and directory backends require an output_path",
+            ))
+        })
+    }
+}
+
+impl BackendKind {
+    /// Backend of the current session: Wayland if `WAYLAND_DISPLAY` is set, X11 if
+    /// `DISPLAY` is set, tmux if `TMUX` is set, and the system clipboard otherwise.
+    pub fn detect() -> Self {
+        let is_set = |name| std::env::var_os(name).is_some_and(|value| !value.is_empty());
+        if is_set("WAYLAND_DISPLAY") {
+            BackendKind::Wayland
+        } else if is_set("DISPLAY") {
+            BackendKind::Clipboard
+        } else if is_set("TMUX") {
+            BackendKind::Tmux
+        } else {
+            BackendKind::Clipboard
+        }
+    }
+
+    /// Create the backend.
+    pub fn build(
+        self,
+        options: &BackendOptions,
+    ) -> Result<Box<dyn ClipboardBackend>, BackendError> {
+        let backend: Box<dyn ClipboardBackend> = match self {
+            BackendKind::Auto => {
+                let kind = Self::detect();
+                log::debug!("Detected clipboard backend: {kind:?}");
+                return kind.build(options);
+            }
+            BackendKind::Clipboard => Box::new(ClipboardProviderBackend::system()?),
+            BackendKind::Wayland => Box::new(CommandBackend::wayland()),
+            BackendKind::Xclip => Box::new(CommandBackend::xclip()),
+            BackendKind::Xsel => Box::new(CommandBackend::xsel()),
+            BackendKind::Tmux => Box::new(CommandBackend::tmux()),
+            BackendKind::Command => {
+                let copy_command = options.copy_command.as_deref().ok_or_else(|| {
+                    BackendError::Config(String::from(
+                        "the command backend requires a copy_command",
+                    ))
+                })?;
+                let backend = CommandBackend::shell(copy_command, options.paste_command.as_deref());
+                match &options.primary_command {
+                    Some(primary_command) => Box::new(backend.with_shell_primary(primary_command)),
+                    None => Box::new(backend),
+                }
+            }
+            BackendKind::Osc52 => Box::new(Osc52Backend::new(
+                options.osc52_tty.clone(),
+                options
+                    .osc52_passthrough
+                    .unwrap_or_else(Passthrough::detect),
+                options.osc52_max_size.unwrap_or(DEFAULT_OSC52_MAX_SIZE),
+            )),
+            BackendKind::File => Box::new(FileBackend::File(options.output_path()?)),
+            BackendKind::Directory => {
+                let path = options.output_path()?;
+                if !path.is_dir() {
+                    return Err(BackendError::Config(format!(
+                        "{} is not a directory",
+                        path.display()
+                    )));
+                }
+                Box::new(FileBackend::Directory(path))
+            }
+            BackendKind::Stdout => Box::new(FileBackend::Stdout),
+        };
+        Ok(backend)
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = BackendError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "auto" => Ok(BackendKind::Auto),
+            "clipboard" => Ok(BackendKind::Clipboard),
+            "wayland" => Ok(BackendKind::Wayland),
+            "xclip" => Ok(BackendKind::Xclip),
+            "xsel" => Ok(BackendKind::Xsel),
+            "tmux" => Ok(BackendKind::Tmux),
+            "command" => Ok(BackendKind::Command),
+            "osc52" => Ok(BackendKind::Osc52),
+            "file" => Ok(BackendKind::File),
+            "directory" => Ok(BackendKind::Directory),
+            "stdout" => Ok(BackendKind::Stdout),
+            _ => Err(BackendError::Config(format!(
+                "unknown clipboard backend '{name}', expected one of: \
+                auto, clipboard, wayland, xclip, xsel, tmux, command, osc52, file, directory, \
+                stdout"
+            ))),
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use std::{fs::OpenOptions, io::Write, path::PathBuf, str::FromStr};
+
+use base64::Engine;
+
+use super::{BackendError, ClipboardBackend};
+use crate::Message;
+
+/// Default maximum size of an OSC 52 sequence, most terminals ignore larger sequences.
+pub const DEFAULT_OSC52_MAX_SIZE: usize = 100_000;
+
+/// Size of the chunks of a sequence wrapped for GNU screen, which limits the size of its
+/// passthrough sequences.
+const SCREEN_CHUNK_SIZE: usize = 76;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// Wrap sequences so that a terminal multiplexer forwards them to the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Passthrough {
+    None,
+    Tmux,
+    Screen,
+}
+
+impl Passthrough {
+    /// Multiplexer the server is running in, from the `TMUX` and `TERM` environment
+    /// variables.
+    pub fn detect() -> Self {
+        if std::env::var_os("TMUX").is_some_and(|value| !value.is_empty()) {
+            Passthrough::Tmux
+        } else if std::env::var("TERM").is_ok_and(|term| term.starts_with("screen")) {
+            Passthrough::Screen
+        } else {
+            Passthrough::None
+        }
+    }
+
+    fn wrap(&self, sequence: &[u8]) -> Vec<u8> {
+        match self {
+            Passthrough::None => sequence.to_vec(),
+            Passthrough::Tmux => {
+                // Escape characters of the wrapped sequence are doubled
+                let mut wrapped = vec![ESC, b'P'];
+                wrapped.extend_from_slice(b"tmux;");
+                for &byte in sequence {
+                    if byte == ESC {
+                        wrapped.push(ESC);
+                    }
+                    wrapped.push(byte);
+                }
+                wrapped.extend_from_slice(&[ESC, b'\\']);
+                wrapped
+            }
+            Passthrough::Screen => {
+                let mut wrapped = Vec::new();
+                for chunk in sequence.chunks(SCREEN_CHUNK_SIZE) {
+                    wrapped.extend_from_slice(&[ESC, b'P']);
+                    wrapped.extend_from_slice(chunk);
+                    wrapped.extend_from_slice(&[ESC, b'\\']);
+                }
+                wrapped
+            }
+        }
+    }
+}
+
+impl FromStr for Passthrough {
+    type Err = BackendError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "auto" => Ok(Self::detect()),
+            "none" => Ok(Passthrough::None),
+            "tmux" => Ok(Passthrough::Tmux),
+            "screen" => Ok(Passthrough::Screen),
+            _ => Err(BackendError::Config(format!(
+                "unknown OSC 52 passthrough '{name}', expected one of: auto, none, tmux, screen"
+            ))),
+        }
+    }
+}
+
+/// Terminal clipboard, set with OSC 52 escape sequences. Text only, the terminal
+/// clipboard can't be read back.
+#[derive(Debug, Clone)]
+pub struct Osc52Backend {
+    /// Terminal to write sequences to, stdout if not set
+    tty: Option<PathBuf>,
+    passthrough: Passthrough,
+    /// Maximum size of a sequence, passthrough wrapping excluded
+    max_size: usize,
+}
+
+impl Osc52Backend {
+    pub fn new(tty: Option<PathBuf>, passthrough: Passthrough, max_size: usize) -> Self {
+        Self {
+            tty,
+            passthrough,
+            max_size,
+        }
+    }
+
+    /// Escape sequence setting the clipboard to `content`.
+    fn sequence(&self, content: &[u8]) -> Result<Vec<u8>, BackendError> {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(content);
+        let mut sequence = vec![ESC];
+        sequence.extend_from_slice(b"]52;c;");
+        sequence.extend_from_slice(encoded.as_bytes());
+        sequence.push(BEL);
+
+        if sequence.len() > self.max_size {
+            return Err(BackendError::Unsupported(format!(
+                "OSC 52 sequence of {} bytes exceeds the maximum size {}",
+                sequence.len(),
+                self.max_size
+            )));
+        }
+        Ok(self.passthrough.wrap(&sequence))
+    }
+}
+
+impl ClipboardBackend for Osc52Backend {
+    fn set_contents(&mut self, message: &Message) -> Result<(), BackendError> {
+        let text = message.to_text().ok_or_else(|| {
+            BackendError::Unsupported(format!(
+                "can't decode charset '{}' as text",
+                message.charset.as_deref().unwrap_or_default()
+            ))
+        })?;
+        let sequence = self.sequence(text.as_bytes())?;
+
+        match &self.tty {
+            Some(tty) => {
+                let mut tty = OpenOptions::new().write(true).open(tty)?;
+                tty.write_all(&sequence)?;
+                tty.flush()?;
+            }
+            None => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&sequence)?;
+                stdout.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_contents(&mut self) -> Result<Message, BackendError> {
+        Err(BackendError::Clipboard(String::from(
+            "the terminal clipboard can't be read with OSC 52",
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::TempDir;
+
+    #[test]
+    fn test_sequence() {
+        let backend = Osc52Backend::new(None, Passthrough::None, DEFAULT_OSC52_MAX_SIZE);
+        assert_eq!(
+            b"\x1b]52;c;aGVsbG8=\x07".as_slice(),
+            backend.sequence(b"hello").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_tmux_passthrough() {
+        let backend = Osc52Backend::new(None, Passthrough::Tmux, DEFAULT_OSC52_MAX_SIZE);
+        assert_eq!(
+            b"\x1bPtmux;\x1b\x1b]52;c;aGVsbG8=\x07\x1b\\".as_slice(),
+            backend.sequence(b"hello").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_screen_passthrough() {
+        let backend = Osc52Backend::new(None, Passthrough::Screen, DEFAULT_OSC52_MAX_SIZE);
+        let sequence = backend.sequence(&[b'a'; 100]).unwrap();
+        // 100 bytes are encoded in 136 bytes, the 144 bytes sequence is split in 2 chunks
+        assert_eq!(144 + 2 * 4, sequence.len());
+        assert!(sequence.starts_with(b"\x1bP\x1b]52;c;"));
+        assert!(sequence.ends_with(b"\x07\x1b\\"));
+    }
+
+    #[test]
+    fn test_max_size() {
+        let backend = Osc52Backend::new(None, Passthrough::None, 16);
+        assert!(backend.sequence(b"hello").is_ok());
+        assert!(matches!(
+            backend.sequence(b"hello world"),
+            Err(BackendError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_write_to_tty() {
+        let dir = TempDir::new("osc52");
+        let tty = dir.join("tty");
+        std::fs::write(&tty, b"").unwrap();
+        let mut backend =
+            Osc52Backend::new(Some(tty.clone()), Passthrough::None, DEFAULT_OSC52_MAX_SIZE);
+        backend
+            .set_contents(&Message::text(String::from("hello")))
+            .unwrap();
+
+        assert_eq!(
+            b"\x1b]52;c;aGVsbG8=\x07".as_slice(),
+            std::fs::read(&tty).unwrap()
+        );
+    }
+}
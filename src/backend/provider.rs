@@ -0,0 +1,137 @@
+use clipboard::{ClipboardContext, ClipboardProvider};
+
+use super::{BackendError, ClipboardBackend};
+use crate::Message;
+
+/// Primary selection of the system clipboard, only X11 has one.
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "android"))))]
+pub type PrimaryContext =
+    clipboard::x11_clipboard::X11ClipboardContext<clipboard::x11_clipboard::Primary>;
+#[cfg(not(all(unix, not(any(target_os = "macos", target_os = "android")))))]
+pub type PrimaryContext = ClipboardContext;
+
+/// Text clipboard of the `clipboard` crate, and its primary selection if any.
+pub struct ClipboardProviderBackend<P: ClipboardProvider, S: ClipboardProvider = P> {
+    provider: P,
+    primary: Option<S>,
+}
+
+impl ClipboardProviderBackend<ClipboardContext, PrimaryContext> {
+    /// Clipboard of the system: X11 on GNU+Linux, with its primary selection, or the
+    /// MacOS and Windows clipboards.
+    pub fn system() -> Result<Self, BackendError> {
+        let error = |e| BackendError::Clipboard(format!("failed to load clipboard provider: {e}"));
+        let provider = ClipboardContext::new().map_err(error)?;
+        #[cfg(all(unix, not(any(target_os = "macos", target_os = "android"))))]
+        let primary = Some(PrimaryContext::new().map_err(error)?);
+        #[cfg(not(all(unix, not(any(target_os = "macos", target_os = "android")))))]
+        let primary = None;
+        Ok(Self { provider, primary })
+    }
+}
+
+impl<P: ClipboardProvider> ClipboardProviderBackend<P> {
+    pub fn new(provider: P) -> Self {
+        Self {
+            provider,
+            primary: None,
+        }
+    }
+}
+
+impl<P: ClipboardProvider, S: ClipboardProvider> ClipboardProviderBackend<P, S> {
+    /// Write to the primary selection with `primary`.
+    pub fn with_primary(provider: P, primary: S) -> Self {
+        Self {
+            provider,
+            primary: Some(primary),
+        }
+    }
+}
+
+/// Text content of a message.
+fn to_text(message: &Message) -> Result<String, BackendError> {
+    message.to_text().ok_or_else(|| {
+        BackendError::Unsupported(format!(
+            "can't decode charset '{}' as text",
+            message.charset.as_deref().unwrap_or_default()
+        ))
+    })
+}
+
+impl<P: ClipboardProvider, S: ClipboardProvider> ClipboardBackend
+    for ClipboardProviderBackend<P, S>
+{
+    fn set_contents(&mut self, message: &Message) -> Result<(), BackendError> {
+        self.provider
+            .set_contents(to_text(message)?)
+            .map_err(|e| BackendError::Clipboard(e.to_string()))
+    }
+
+    fn set_primary(&mut self, message: &Message) -> Result<(), BackendError> {
+        let primary = self.primary.as_mut().ok_or_else(|| {
+            BackendError::Unsupported(String::from(
+                "the clipboard backend has no primary selection",
+            ))
+        })?;
+        primary
+            .set_contents(to_text(message)?)
+            .map_err(|e| BackendError::Clipboard(e.to_string()))
+    }
+
+    fn supports_primary(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    fn get_contents(&mut self) -> Result<Message, BackendError> {
+        self.provider
+            .get_contents()
+            .map(Message::text)
+            .map_err(|e| BackendError::Clipboard(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MemoryContext(String);
+
+    impl ClipboardProvider for MemoryContext {
+        fn new() -> Result<Self, Box<dyn Error>> {
+            Ok(Self::default())
+        }
+
+        fn get_contents(&mut self) -> Result<String, Box<dyn Error>> {
+            Ok(self.0.clone())
+        }
+
+        fn set_contents(&mut self, content: String) -> Result<(), Box<dyn Error>> {
+            self.0 = content;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_primary() {
+        let message = Message::text(String::from("Test message"));
+        let mut backend = ClipboardProviderBackend::new(MemoryContext::default());
+        assert!(!backend.supports_primary());
+        assert!(matches!(
+            backend.set_primary(&message),
+            Err(BackendError::Unsupported(_))
+        ));
+
+        let mut backend = ClipboardProviderBackend::with_primary(
+            MemoryContext::default(),
+            MemoryContext::default(),
+        );
+        assert!(backend.supports_primary());
+        backend.set_primary(&message).unwrap();
+        assert_eq!("Test message", backend.primary.as_ref().unwrap().0);
+        assert_eq!("", backend.get_contents().unwrap().to_text().unwrap());
+    }
+}
@@ -1,23 +1,62 @@
 use std::{
     io::{Read, Write},
-    net::TcpStream,
+    net::{TcpStream, ToSocketAddrs},
 };
 
-use chacha20poly1305::aead::Aead;
 use chacha20poly1305::Key;
 use chacha20poly1305::KeyInit;
+use rand::rngs::OsRng;
 use thiserror::Error;
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
 
 use crate::{
-    Cipher, NetFrame,
-    NetFrameType::{self, CopyMessage},
-    Nonce, CLOSE_PAYLOAD,
+    handshake::{self, AuthMethod, Authenticator, ClientHello, Role, ServerHello},
+    kdf::{KdfError, KdfParams},
+    pairing::{self, PairingCode, PairingError},
+    ssh::{KnownHosts, SshError, SshIdentity},
+    transport::{self, Pipe, Stream, Transport},
+    Capabilities, Cipher, FrameError, FrameSizeType, Message, NetFrame,
+    NetFrameType::{self, Ack, Clipboard, CopyMessage, ExecMessage, GetClipboard},
+    Nonce, ProtocolVersionType, SecretKey, Status, StatusCode, Timeout, Timeouts, CLOSE_PAYLOAD,
+    DEFAULT_MAX_FRAME_SIZE, DEFAULT_MAX_PAYLOAD_SIZE, KEY_SIZE, MIN_PROTOCOL_VERSION,
 };
 
+#[cfg(feature = "async")]
+pub use self::asynchronous::{AsyncClient, AsyncSession};
+
+#[cfg(feature = "async")]
+mod asynchronous;
+
 pub struct Client<'a> {
     pub address: &'a str,
+    /// Maximum size of a frame read from the server
+    pub max_frame_size: FrameSizeType,
+    /// Maximum size of a message sent to the server
+    pub max_payload_size: usize,
+    /// Oldest protocol version accepted from the server
+    pub min_protocol_version: ProtocolVersionType,
+    /// Connect, handshake and read timeouts, the idle timeout is not used by clients
+    pub timeouts: Timeouts,
+    /// Connect to the address, or speak to the server through a command
+    pub transport: Transport,
+    /// ID of the key in the server keyring, the server default key is used if `None`
+    pub key_id: Option<String>,
+    /// Authenticate with an SSH key instead of the pre-shared key
+    pub ssh_identity: Option<SshIdentity>,
+    /// Host keys the server signs the handshake with when authenticating with an SSH key
+    pub known_hosts: KnownHosts,
+    /// Parameters the pre-shared key was derived from a passphrase with, checked by the
+    /// server against the parameters of its own key
+    pub kdf: Option<KdfParams>,
+    /// Pre-shared key, wiped from memory once dropped
+    key: SecretKey,
+    /// Cipher of the session, derived from the pre-shared key once the connection is opened
     cipher: Cipher,
     state: crate::ConnectionState,
+    /// Protocol version negotiated when opening the connection
+    version: ProtocolVersionType,
+    /// Capabilities supported by both peers
+    capabilities: Capabilities,
 }
 
 #[derive(Error, Debug)]
@@ -28,6 +67,12 @@ pub enum ClientError {
     #[error("Error parsing message")]
     ParsingError,
 
+    #[error("Malformed frame: {0}")]
+    Frame(FrameError),
+
+    #[error("Payload of {size} bytes exceeds the maximum payload size {max}")]
+    PayloadTooLarge { size: usize, max: usize },
+
     #[error("Invalid state {0}")]
     InvalidState(String),
 
@@ -36,38 +81,379 @@ pub enum ClientError {
 
     #[error("Encryption error: {0}")]
     Encryption(chacha20poly1305::aead::Error),
+
+    #[error("Message rejected by server with code {code}: {reason}")]
+    Rejected { code: StatusCode, reason: String },
+
+    #[error("Unsupported by server: {0}")]
+    Unsupported(String),
+
+    #[error("Key exchange failed: {0}")]
+    KeyExchange(String),
+
+    #[error("Server authentication failed: {0}")]
+    Authentication(String),
+
+    #[error("Timed out {0}")]
+    Timeout(String),
+
+    #[error("Invalid key ID: {0}")]
+    InvalidKeyId(String),
+
+    #[error("SSH authentication failed: {0}")]
+    Ssh(#[from] SshError),
+
+    #[error("Pairing failed: {0}")]
+    Pairing(#[from] PairingError),
+
+    #[error("Invalid key derivation parameters: {0}")]
+    Kdf(#[from] KdfError),
+}
+
+impl From<FrameError> for ClientError {
+    fn from(error: FrameError) -> Self {
+        match error {
+            FrameError::Io(e) => ClientError::Io(e),
+            e => ClientError::Frame(e),
+        }
+    }
+}
+
+impl ClientError {
+    /// Report IO timeouts as `Timeout`, while doing `context`.
+    fn or_timeout(self, context: &str) -> Self {
+        match self {
+            ClientError::Io(e) if crate::is_timeout(&e) => ClientError::Timeout(context.into()),
+            e => e,
+        }
+    }
 }
 
 // TODO: handle multi parsing: encrypted vs non encrytped frames
 // TODO: create a real state machine that disallow invalid state transisions at compile time.
 impl<'a> Client<'a> {
     pub fn new(address: &'a str, key: &[u8]) -> Self {
-        let key = Key::from_slice(key).to_owned();
-        let cipher = Cipher::new(&key);
+        let key = crate::secret_key(key);
+        let cipher = Cipher::new(Key::from_slice(&*key));
         Self {
             address,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            timeouts: Timeouts::default(),
+            transport: Transport::Socket,
+            key_id: None,
+            ssh_identity: None,
+            known_hosts: KnownHosts::default(),
+            kdf: None,
+            key,
             cipher,
             state: crate::ConnectionState::New,
+            version: MIN_PROTOCOL_VERSION,
+            capabilities: Capabilities::empty(),
         }
     }
 
+    /// Client authenticating with an SSH key instead of a pre-shared key. The server must
+    /// sign the handshake with one of the `known_hosts` keys of the client address.
+    pub fn with_ssh_identity(
+        address: &'a str,
+        identity: SshIdentity,
+        known_hosts: KnownHosts,
+    ) -> Self {
+        let mut client = Self::new(address, &[0; crate::KEY_SIZE]);
+        client.ssh_identity = Some(identity);
+        client.known_hosts = known_hosts;
+        client
+    }
+
+    /// Open a session to send several messages over a single connection.
+    pub fn session(&mut self) -> Result<Session<'_, 'a>, ClientError> {
+        let stream = self.open()?;
+        Ok(Session {
+            client: self,
+            stream,
+        })
+    }
+
+    /// Send a message to the server clipboard.
     pub fn send(&mut self, message: &[u8]) -> Result<(), ClientError> {
+        self.send_message(&Message::new(message.to_vec()))
+    }
+
+    /// Send a message with its declared charset to the server clipboard.
+    pub fn send_message(&mut self, message: &Message) -> Result<(), ClientError> {
         log::debug!("Sending message to {}", self.address);
-        let mut stream = TcpStream::connect(self.address)?;
+        self.check_payload_size(&message.content)?;
+        let mut session = self.session()?;
+        session.send_message(message)?;
+        session.close()
+    }
 
-        log::trace!("Sending opening Frame");
-        stream.write_all(&NetFrame::open_frame().to_net())?;
+    /// Pair with a server waiting with `code`, returns the new secret shared with the
+    /// server. The key and SSH identity of the client are not used.
+    pub fn pair(&self, code: &PairingCode) -> Result<[u8; KEY_SIZE], ClientError> {
+        log::debug!("Pairing with {}", self.address);
+        let mut stream = self.connect()?;
+        stream.set_read_timeout(self.timeouts.handshake)?;
+        stream.set_write_timeout(self.timeouts.handshake)?;
+        pairing::pair_client(&mut stream, code).map_err(|e| match e {
+            PairingError::Io(e) if crate::is_timeout(&e) => {
+                ClientError::Timeout(String::from("pairing"))
+            }
+            e => ClientError::Pairing(e),
+        })
+    }
+
+    /// Fetch the content of the server clipboard.
+    pub fn fetch(&mut self) -> Result<Message, ClientError> {
+        log::debug!("Fetching clipboard from {}", self.address);
+        let mut session = self.session()?;
+        let content = session.fetch()?;
+        session.close()?;
+        Ok(content)
+    }
 
-        self.handle_open(&self.next_frame(&mut stream)?)?;
+    fn check_fetch(&self) -> Result<(), ClientError> {
+        if !self.capabilities.contains(Capabilities::FETCH) {
+            return Err(ClientError::Unsupported(String::from(
+                "server does not support fetching its clipboard",
+            )));
+        }
+        Ok(())
+    }
+
+    /// Decode the server clipboard sent in response to the request encrypted with `nonce`.
+    fn handle_clipboard(&self, frame: &NetFrame, nonce: &Nonce) -> Result<Message, ClientError> {
+        let content = self.handle_response(frame, nonce, Clipboard)?;
+        log::trace!("Received clipboard content");
+        Message::from_bytes(self.version, &content).map_err(|_| ClientError::ParsingError)
+    }
+
+    fn check_payload_size(&self, message: &[u8]) -> Result<(), ClientError> {
+        if message.len() > self.max_payload_size {
+            return Err(ClientError::PayloadTooLarge {
+                size: message.len(),
+                max: self.max_payload_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Send a message and wait for the server acknowledgement.
+    fn request(
+        &mut self,
+        stream: &mut Stream,
+        m_type: NetFrameType,
+        message: &Message,
+    ) -> Result<(), ClientError> {
+        let payload = self.message_payload(message)?;
+        let nonce = self
+            .write_message(stream, m_type, &payload)
+            .map_err(|e| e.or_timeout("sending the message"))?;
+        if crate::has_acks(self.version) {
+            let response = self
+                .next_frame(stream)
+                .map_err(|e| e.or_timeout("waiting for the server response"))?;
+            self.handle_ack(&response, &nonce)?;
+        }
+        Ok(())
+    }
+
+    /// Encode a message for the negotiated protocol version. Messages declaring a selection
+    /// or a non text content type the server can't receive are refused, instead of being
+    /// written to the wrong selection or as text.
+    fn message_payload(&self, message: &Message) -> Result<Vec<u8>, ClientError> {
+        if message.selection.is_some() && !crate::has_selection(self.version) {
+            return Err(ClientError::Unsupported(format!(
+                "server protocol version {} can't select the clipboard or primary selection",
+                self.version
+            )));
+        }
+        if !message.is_text() && !crate::has_content_type(self.version) {
+            return Err(ClientError::Unsupported(format!(
+                "server protocol version {} only receives text, not '{}'",
+                self.version,
+                message.mime_type()
+            )));
+        }
+        let payload = message.to_bytes(self.version)?;
+        self.check_payload_size(&payload)?;
+        Ok(payload)
+    }
+
+    /// Check the server acknowledgement of the message encrypted with `nonce`.
+    fn handle_ack(&self, frame: &NetFrame, nonce: &Nonce) -> Result<(), ClientError> {
+        let status = Status::from_bytes(&self.handle_response(frame, nonce, Ack)?)?;
+        log::debug!("Message acknowledged by server: {}", status.message);
+        Ok(())
+    }
+
+    fn open(&mut self) -> Result<Stream, ClientError> {
+        let mut stream = self.connect()?;
+        stream.set_read_timeout(self.timeouts.handshake)?;
+        stream.set_write_timeout(self.timeouts.handshake)?;
+        self.handshake(&mut stream)
+            .map_err(|e| e.or_timeout("opening the connection"))?;
+
+        stream.set_read_timeout(self.timeouts.read)?;
+        stream.set_write_timeout(self.timeouts.read)?;
+        Ok(stream)
+    }
+
+    /// Spawn the transport command, or connect to the Unix domain socket of the server,
+    /// or to the first address the server address resolves to that accepts the
+    /// connection.
+    fn connect(&self) -> Result<Stream, ClientError> {
+        if let Transport::Command(command) = &self.transport {
+            return Ok(Stream::Pipe(Pipe::spawn(command)?));
+        }
+        if let Some(path) = transport::unix_socket_path(self.address) {
+            return Ok(transport::connect_unix(path)?);
+        }
+        let timeout = match self.timeouts.connect {
+            None => return Ok(Stream::Tcp(TcpStream::connect(self.address)?)),
+            Some(timeout) => timeout,
+        };
+
+        let mut error = None;
+        for address in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, timeout) {
+                Ok(stream) => return Ok(Stream::Tcp(stream)),
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(match error {
+            Some(e) => ClientError::from(e).or_timeout("connecting to the server"),
+            None => ClientError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("could not resolve {}", self.address),
+            )),
+        })
+    }
+
+    fn handshake(&mut self, stream: &mut Stream) -> Result<(), ClientError> {
+        let (open_frame, secret) = self.open_frame()?;
+        stream.write_all(&open_frame.to_net())?;
+
+        let authenticator =
+            self.handle_open(&self.next_frame(stream)?, &open_frame.payload, secret)?;
         log::trace!("Received open response");
+        if let Some(authenticator) = authenticator {
+            self.authenticate(stream, &authenticator)?;
+        }
+        Ok(())
+    }
+
+    /// Reset the state, cipher, version and capabilities of the previous session, returns
+    /// the client Open frame and the ephemeral secret of the key exchange.
+    fn open_frame(&mut self) -> Result<(NetFrame, EphemeralSecret), ClientError> {
+        self.state = crate::ConnectionState::New;
+        self.cipher = Cipher::new(Key::from_slice(&*self.key));
+        self.version = MIN_PROTOCOL_VERSION;
+        self.capabilities = Capabilities::empty();
+        if let Some(key_id) = &self.key_id {
+            if key_id.is_empty() || key_id.len() > handshake::MAX_KEY_ID_SIZE {
+                return Err(ClientError::InvalidKeyId(format!(
+                    "'{key_id}' must be 1 to {} bytes long",
+                    handshake::MAX_KEY_ID_SIZE
+                )));
+            }
+        }
+        // Clients authenticating with an SSH key don't use their pre-shared key
+        let kdf = match (&self.kdf, &self.ssh_identity) {
+            (Some(kdf), None) => {
+                kdf.validate()?;
+                Some(kdf.clone())
+            }
+            _ => None,
+        };
 
-        self.send_message(&mut stream, CopyMessage, message)?;
+        log::trace!("Sending opening Frame");
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let hello = ClientHello::new(
+            self.min_protocol_version,
+            PublicKey::from(&secret),
+            self.key_id.clone(),
+            self.auth_method(),
+            kdf,
+        );
+        Ok((NetFrame::open_frame(&hello), secret))
+    }
 
+    /// Prove to the server that we know the secret, then check the server proof.
+    fn authenticate(
+        &mut self,
+        stream: &mut Stream,
+        authenticator: &Authenticator,
+    ) -> Result<(), ClientError> {
+        stream.write_all(&self.proof_frame(authenticator)?.to_net())?;
+        self.handle_auth(&self.next_frame(stream)?, authenticator)
+    }
+
+    /// Proof of the knowledge of the secret, or signature of the SSH key.
+    fn proof_frame(&self, authenticator: &Authenticator) -> Result<NetFrame, ClientError> {
+        log::trace!("Sending client proof");
+        let proof = match &self.ssh_identity {
+            None => authenticator.proof(Role::Client).to_vec(),
+            Some(identity) => identity.sign(&authenticator.ssh_challenge(Role::Client))?,
+        };
+        Ok(NetFrame::new(self.version, NetFrameType::Auth, proof))
+    }
+
+    fn auth_method(&self) -> AuthMethod {
+        match self.ssh_identity {
+            None => AuthMethod::Secret,
+            Some(_) => AuthMethod::Ssh,
+        }
+    }
+
+    /// Check the server proof, or the signature of its host key.
+    fn handle_auth(
+        &self,
+        frame: &NetFrame,
+        authenticator: &Authenticator,
+    ) -> Result<(), ClientError> {
+        match frame.frame_type {
+            NetFrameType::Auth => (),
+            NetFrameType::Error => {
+                // Connection is not authenticated yet, errors are sent as plaintext
+                let status = Status::from_bytes(&frame.payload)?;
+                return Err(ClientError::Rejected {
+                    code: status.code,
+                    reason: status.message,
+                });
+            }
+            frame_type => {
+                return Err(ClientError::InvalidState(format!(
+                    "Unexpected {frame_type:?} frame while authenticating"
+                )))
+            }
+        }
+
+        if self.ssh_identity.is_some() {
+            let challenge = authenticator.ssh_challenge(Role::Server);
+            self.known_hosts
+                .verify(self.address, &frame.payload, &challenge)
+                .map_err(|e| {
+                    ClientError::Authentication(format!(
+                        "server failed to authenticate with its host key: {e}"
+                    ))
+                })?;
+        } else if !authenticator.verify(Role::Server, &frame.payload) {
+            return Err(ClientError::Authentication(String::from(
+                "server failed to prove it knows the secret",
+            )));
+        }
+        log::trace!("Server authenticated");
+        Ok(())
+    }
+
+    fn close(&mut self, stream: &mut Stream) -> Result<(), ClientError> {
         log::trace!("Sending closing frame");
-        self.send_close(&mut stream)?;
+        self.send_close(stream)?;
 
         stream.flush()?;
-
         Ok(())
     }
 
@@ -75,10 +461,17 @@ impl<'a> Client<'a> {
         &self,
         stream: &mut Stream,
     ) -> Result<NetFrame, ClientError> {
-        Ok(NetFrame::from_net(stream)?)
+        Ok(NetFrame::from_net(stream, self.max_frame_size)?)
     }
 
-    fn handle_open(&mut self, frame: &NetFrame) -> Result<(), ClientError> {
+    /// Handle the server Open frame, returns the authenticator of the connection if the
+    /// protocol version requires mutual authentication.
+    fn handle_open(
+        &mut self,
+        frame: &NetFrame,
+        client_hello: &[u8],
+        secret: EphemeralSecret,
+    ) -> Result<Option<Authenticator>, ClientError> {
         match self.state {
             crate::ConnectionState::New => (),
             _ => {
@@ -88,45 +481,167 @@ impl<'a> Client<'a> {
             }
         }
 
-        let nonce: Nonce = frame
-            .payload
-            .clone()
-            .try_into()
-            .map_err(|_| ClientError::ParsingError)?;
+        match frame.frame_type {
+            NetFrameType::Open => (),
+            NetFrameType::Error => {
+                // Connection is not opened yet, errors are sent as plaintext
+                let status = Status::from_bytes(&frame.payload)?;
+                return Err(ClientError::Rejected {
+                    code: status.code,
+                    reason: status.message,
+                });
+            }
+            frame_type => {
+                return Err(ClientError::InvalidState(format!(
+                    "Unexpected {frame_type:?} frame while opening connection"
+                )))
+            }
+        }
 
-        self.state = crate::ConnectionState::Opened(nonce);
-        Ok(())
+        if frame.protocol_version < self.min_protocol_version {
+            return Err(ClientError::Unsupported(format!(
+                "server protocol version {} is older than the minimum version {}",
+                frame.protocol_version, self.min_protocol_version
+            )));
+        }
+
+        if self.ssh_identity.is_some() && !crate::has_ssh_authentication(frame.protocol_version) {
+            return Err(ClientError::Unsupported(format!(
+                "server protocol version {} does not support SSH authentication",
+                frame.protocol_version
+            )));
+        }
+
+        let hello =
+            ServerHello::from_bytes(&frame.payload).map_err(|_| ClientError::ParsingError)?;
+        self.version = frame.protocol_version;
+        self.capabilities = hello.capabilities.intersection(Capabilities::supported());
+        let mut authenticator = None;
+        if crate::has_key_exchange(self.version) {
+            let shared_secret = self.exchange_keys(&hello, secret)?;
+            if crate::has_mutual_authentication(self.version) {
+                authenticator = Some(Authenticator::new(
+                    Key::from_slice(&*self.key),
+                    &shared_secret,
+                    self.version,
+                    client_hello,
+                    &frame.payload,
+                ));
+            }
+        }
+        log::debug!("Opened connection with protocol version {}", self.version);
+
+        self.state = crate::ConnectionState::Opened(hello.nonce);
+        Ok(authenticator)
+    }
+
+    /// Derive the session cipher from the server ephemeral key, returns the shared secret.
+    fn exchange_keys(
+        &mut self,
+        hello: &ServerHello,
+        secret: EphemeralSecret,
+    ) -> Result<SharedSecret, ClientError> {
+        let server_public_key = hello.public_key.ok_or_else(|| {
+            ClientError::KeyExchange(String::from("server did not send its public key"))
+        })?;
+        let public_key = PublicKey::from(&secret);
+        let shared_secret = secret.diffie_hellman(&server_public_key);
+        if !shared_secret.was_contributory() {
+            return Err(ClientError::KeyExchange(String::from(
+                "server sent a low order public key",
+            )));
+        }
+
+        self.cipher = handshake::session_cipher(
+            Key::from_slice(&*self.key),
+            &shared_secret,
+            &public_key,
+            &server_public_key,
+            &hello.nonce,
+        );
+        Ok(shared_secret)
+    }
+
+    /// Decrypt the server response to the message encrypted with `nonce`.
+    fn handle_response(
+        &self,
+        frame: &NetFrame,
+        nonce: &Nonce,
+        expected: NetFrameType,
+    ) -> Result<Vec<u8>, ClientError> {
+        let payload = frame.decrypt(&self.cipher, &nonce.reply());
+
+        match (frame.frame_type, payload) {
+            (NetFrameType::Error, Ok(payload)) => {
+                let status = Status::from_bytes(&payload)?;
+                Err(ClientError::Rejected {
+                    code: status.code,
+                    reason: status.message,
+                })
+            }
+            // The server encrypts its errors with its own secret, if it differs from ours
+            // the server most likely failed to decrypt our message as well.
+            (NetFrameType::Error, Err(_)) => Err(ClientError::Rejected {
+                code: StatusCode::DecryptionFailed,
+                reason: String::from(
+                    "Server could not decrypt the message. \
+                    Make sure client and server are using the same secret.",
+                ),
+            }),
+            (frame_type, Ok(payload)) if frame_type == expected => Ok(payload),
+            (frame_type, Err(e)) if frame_type == expected => Err(ClientError::Decryption(e)),
+            (frame_type, _) => Err(ClientError::InvalidState(format!(
+                "Unexpected {frame_type:?} frame while waiting for server response"
+            ))),
+        }
     }
 
     fn send_close<T: Write>(&mut self, stream: &mut T) -> Result<(), ClientError> {
+        stream.write_all(&self.close_frame()?.to_net())?;
+        Ok(())
+    }
+
+    fn close_frame(&mut self) -> Result<NetFrame, ClientError> {
         let nonce = self.opened_conn_nounce()?;
 
-        let cipher_payload = self
-            .cipher
-            .encrypt(nonce.cipher_nonce(), CLOSE_PAYLOAD.as_slice())
-            .map_err(ClientError::Encryption)?;
-        stream.write_all(&NetFrame::close_frame(cipher_payload).to_net())?;
+        let close_frame = NetFrame::encrypted(
+            self.version,
+            NetFrameType::Close,
+            &self.cipher,
+            &nonce,
+            &CLOSE_PAYLOAD,
+        )
+        .map_err(ClientError::Encryption)?;
         self.state = crate::ConnectionState::Closed;
-        Ok(())
+        Ok(close_frame)
     }
 
-    fn send_message<T: Write>(
+    fn write_message<T: Write>(
         &mut self,
         stream: &mut T,
         m_type: NetFrameType,
         message: &[u8],
-    ) -> Result<(), ClientError> {
+    ) -> Result<Nonce, ClientError> {
+        let (message_frame, nonce) = self.message_frame(m_type, message)?;
+        stream.write_all(&message_frame.to_net())?;
+        Ok(nonce)
+    }
+
+    /// Encrypt a message with the next nonce of the connection, returns the frame and
+    /// its nonce.
+    fn message_frame(
+        &mut self,
+        m_type: NetFrameType,
+        message: &[u8],
+    ) -> Result<(NetFrame, Nonce), ClientError> {
         let nonce = self.opened_conn_nounce()?;
 
-        let cipher_message = self
-            .cipher
-            .encrypt(nonce.cipher_nonce(), message)
-            .map_err(ClientError::Encryption)?;
-        let message_frame = NetFrame::new(m_type, cipher_message);
+        let message_frame =
+            NetFrame::encrypted(self.version, m_type, &self.cipher, &nonce, message)
+                .map_err(ClientError::Encryption)?;
         log::trace!("Sending payload with size: {}", message_frame.frame_size);
-        stream.write_all(&message_frame.to_net())?;
         self.state = crate::ConnectionState::Opened(nonce.consume());
-        Ok(())
+        Ok((message_frame, nonce))
     }
 
     fn opened_conn_nounce(&mut self) -> Result<Nonce, ClientError> {
@@ -141,3 +656,192 @@ impl<'a> Client<'a> {
         Ok(nonce)
     }
 }
+
+/// Connection opened with [`Client::session`], messages are sent over the same stream
+/// until the session is closed.
+///
+/// Dropping the session closes the connection.
+pub struct Session<'c, 'a> {
+    client: &'c mut Client<'a>,
+    stream: Stream,
+}
+
+impl Session<'_, '_> {
+    /// Send a message to the server clipboard.
+    pub fn send(&mut self, message: &[u8]) -> Result<(), ClientError> {
+        self.send_message(&Message::new(message.to_vec()))
+    }
+
+    /// Send a message with its declared charset to the server clipboard.
+    pub fn send_message(&mut self, message: &Message) -> Result<(), ClientError> {
+        self.client.request(&mut self.stream, CopyMessage, message)
+    }
+
+    /// Execute the server command with a message, without changing its clipboard.
+    pub fn exec(&mut self, message: &[u8]) -> Result<(), ClientError> {
+        let message = Message::new(message.to_vec());
+        self.client.request(&mut self.stream, ExecMessage, &message)
+    }
+
+    /// Fetch the content of the server clipboard.
+    pub fn fetch(&mut self) -> Result<Message, ClientError> {
+        self.client.check_fetch()?;
+        let nonce = self
+            .client
+            .write_message(&mut self.stream, GetClipboard, &[])
+            .map_err(|e| e.or_timeout("sending the message"))?;
+        let response = self
+            .client
+            .next_frame(&mut self.stream)
+            .map_err(|e| e.or_timeout("waiting for the server response"))?;
+        self.client.handle_clipboard(&response, &nonce)
+    }
+
+    /// Close the connection.
+    pub fn close(mut self) -> Result<(), ClientError> {
+        self.client.close(&mut self.stream)
+    }
+}
+
+impl Drop for Session<'_, '_> {
+    fn drop(&mut self) {
+        if matches!(self.client.state, crate::ConnectionState::Opened(_)) {
+            if let Err(e) = self.client.close(&mut self.stream) {
+                log::debug!("Failed to close session: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use ssh_key::{private::Ed25519Keypair, PrivateKey};
+
+    use super::*;
+
+    const TESTING_KEY: &[u8; KEY_SIZE] = b"__WARNING_UNSECURE_KEY_TESTING__";
+
+    /// Pose as a server that accepts any client proof, and answers with `proof`.
+    fn start_impostor(proof: impl FnOnce(&Authenticator) -> Vec<u8> + Send + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let open_frame = NetFrame::from_net(&mut stream, DEFAULT_MAX_FRAME_SIZE).unwrap();
+            let hello = ClientHello::from_bytes(&open_frame.payload).unwrap();
+            let secret = EphemeralSecret::random_from_rng(OsRng);
+            let response = ServerHello {
+                nonce: Nonce::default(),
+                capabilities: Capabilities::supported(),
+                public_key: Some(PublicKey::from(&secret)),
+            }
+            .to_bytes();
+            let shared_secret = secret.diffie_hellman(&hello.public_key.unwrap());
+            let authenticator = Authenticator::new(
+                &Key::default(),
+                &shared_secret,
+                crate::PROTOCOL_VERSION,
+                &open_frame.payload,
+                &response,
+            );
+            let open = NetFrame::new(crate::PROTOCOL_VERSION, NetFrameType::Open, response);
+            stream.write_all(&open.to_net()).unwrap();
+
+            // Skip the client proof, and answer with the proof of the impostor
+            NetFrame::from_net(&mut stream, DEFAULT_MAX_FRAME_SIZE).unwrap();
+            let auth = NetFrame::new(
+                crate::PROTOCOL_VERSION,
+                NetFrameType::Auth,
+                proof(&authenticator),
+            );
+            let _ = stream.write_all(&auth.to_net());
+        });
+        address
+    }
+
+    #[test]
+    fn test_ssh_client_rejects_impostor() {
+        let ssh_key = PrivateKey::from(Ed25519Keypair::from_seed(&[1; 32]));
+        let host_key = PrivateKey::from(Ed25519Keypair::from_seed(&[2; 32]));
+        let impostor_key = SshIdentity::Key(PrivateKey::from(Ed25519Keypair::from_seed(&[3; 32])));
+        let known_hosts: KnownHosts = format!("* {}", host_key.public_key().to_openssh().unwrap())
+            .parse()
+            .unwrap();
+        for address in [
+            // Signs with a host key the client doesn't know
+            start_impostor(move |authenticator| {
+                impostor_key
+                    .sign(&authenticator.ssh_challenge(Role::Server))
+                    .unwrap()
+            }),
+            // Answers with a secret proof instead of a host key signature
+            start_impostor(|authenticator| authenticator.proof(Role::Server).to_vec()),
+        ] {
+            let mut client = Client::with_ssh_identity(
+                &address,
+                SshIdentity::Key(ssh_key.clone()),
+                known_hosts.clone(),
+            );
+            match client.send(b"Secret message") {
+                Err(ClientError::Authentication(_)) => (),
+                r => panic!("Expected authentication failure, got {r:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_frame_resets_session() {
+        let mut client = Client::new("127.0.0.1:2323", TESTING_KEY);
+        client.version = crate::PROTOCOL_VERSION;
+        client.capabilities = Capabilities::supported();
+        client.cipher = Cipher::new(Key::from_slice(b"__WARNING_WRONG_KEY_TESTING_____"));
+        client.open_frame().unwrap();
+
+        assert_eq!(MIN_PROTOCOL_VERSION, client.version);
+        assert_eq!(Capabilities::empty(), client.capabilities);
+        let encrypt = |cipher: &Cipher| {
+            NetFrame::encrypted(
+                MIN_PROTOCOL_VERSION,
+                CopyMessage,
+                cipher,
+                &Nonce::from([0; crate::NOUNCE_SIZE]),
+                b"message",
+            )
+            .unwrap()
+            .payload
+        };
+        assert_eq!(
+            encrypt(&Cipher::new(Key::from_slice(TESTING_KEY))),
+            encrypt(&client.cipher)
+        );
+    }
+
+    #[test]
+    fn test_message_unsupported_by_version() {
+        let mut client = Client::new("127.0.0.1:2323", TESTING_KEY);
+        let text = Message::text(String::from("Test message"));
+        let image = Message::new(vec![0x89, b'P', b'N', b'G']).with_content_type("image/png");
+        let primary = text.clone().with_selection(crate::Selection::Primary);
+
+        client.version = 6;
+        assert!(client.message_payload(&text).is_ok());
+        for message in [&image, &primary] {
+            assert!(matches!(
+                client.message_payload(message),
+                Err(ClientError::Unsupported(_))
+            ));
+        }
+
+        client.version = 7;
+        assert!(client.message_payload(&image).is_ok());
+        assert!(matches!(
+            client.message_payload(&primary),
+            Err(ClientError::Unsupported(_))
+        ));
+
+        client.version = 8;
+        assert!(client.message_payload(&primary).is_ok());
+    }
+}
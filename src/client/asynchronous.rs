@@ -0,0 +1,211 @@
+use std::{future::Future, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use tokio_util::codec::Framed;
+
+use super::{Client, ClientError};
+use crate::{
+    codec::NetFrameCodec,
+    transport::{self, AsyncStream, Transport},
+    Message, NetFrame,
+    NetFrameType::{self, CopyMessage, ExecMessage, GetClipboard},
+};
+
+type FramedStream = Framed<Box<dyn AsyncStream>, NetFrameCodec>;
+
+/// Async counterpart of [`Client`], running on the tokio runtime.
+///
+/// Frames are built and checked by the wrapped client, configure it before converting it
+/// with `AsyncClient::from`.
+pub struct AsyncClient<'a> {
+    client: Client<'a>,
+}
+
+impl<'a> From<Client<'a>> for AsyncClient<'a> {
+    fn from(client: Client<'a>) -> Self {
+        Self { client }
+    }
+}
+
+impl<'a> AsyncClient<'a> {
+    pub fn new(address: &'a str, key: &[u8]) -> Self {
+        Client::new(address, key).into()
+    }
+
+    /// Open a session to send several messages over a single connection.
+    pub async fn session(&mut self) -> Result<AsyncSession<'_, 'a>, ClientError> {
+        let transport = self.open().await?;
+        Ok(AsyncSession {
+            client: &mut self.client,
+            transport,
+        })
+    }
+
+    /// Send a message to the server clipboard.
+    pub async fn send(&mut self, message: &[u8]) -> Result<(), ClientError> {
+        self.send_message(&Message::new(message.to_vec())).await
+    }
+
+    /// Send a message with its declared charset to the server clipboard.
+    pub async fn send_message(&mut self, message: &Message) -> Result<(), ClientError> {
+        log::debug!("Sending message to {}", self.client.address);
+        self.client.check_payload_size(&message.content)?;
+        let mut session = self.session().await?;
+        session.send_message(message).await?;
+        session.close().await
+    }
+
+    /// Fetch the content of the server clipboard.
+    pub async fn fetch(&mut self) -> Result<Message, ClientError> {
+        log::debug!("Fetching clipboard from {}", self.client.address);
+        let mut session = self.session().await?;
+        let content = session.fetch().await?;
+        session.close().await?;
+        Ok(content)
+    }
+
+    async fn open(&mut self) -> Result<FramedStream, ClientError> {
+        let timeouts = self.client.timeouts;
+        let stream = match &self.client.transport {
+            Transport::Command(command) => transport::spawn_async(command)?,
+            Transport::Socket => {
+                with_timeout(timeouts.connect, "connecting to the server", async {
+                    Ok(transport::connect_async(self.client.address).await?)
+                })
+                .await?
+            }
+        };
+        let mut transport = Framed::new(stream, NetFrameCodec::new(self.client.max_frame_size));
+        with_timeout(
+            timeouts.handshake,
+            "opening the connection",
+            handshake(&mut self.client, &mut transport),
+        )
+        .await?;
+        Ok(transport)
+    }
+}
+
+/// Connection opened with [`AsyncClient::session`], messages are sent over the same
+/// stream until the session is closed.
+///
+/// Dropping the session closes the stream without notifying the server, close it with
+/// [`AsyncSession::close`].
+pub struct AsyncSession<'c, 'a> {
+    client: &'c mut Client<'a>,
+    transport: FramedStream,
+}
+
+impl AsyncSession<'_, '_> {
+    /// Send a message to the server clipboard.
+    pub async fn send(&mut self, message: &[u8]) -> Result<(), ClientError> {
+        self.send_message(&Message::new(message.to_vec())).await
+    }
+
+    /// Send a message with its declared charset to the server clipboard.
+    pub async fn send_message(&mut self, message: &Message) -> Result<(), ClientError> {
+        self.request(CopyMessage, message).await
+    }
+
+    /// Execute the server command with a message, without changing its clipboard.
+    pub async fn exec(&mut self, message: &[u8]) -> Result<(), ClientError> {
+        self.request(ExecMessage, &Message::new(message.to_vec()))
+            .await
+    }
+
+    /// Fetch the content of the server clipboard.
+    pub async fn fetch(&mut self) -> Result<Message, ClientError> {
+        self.client.check_fetch()?;
+        let (frame, nonce) = self.client.message_frame(GetClipboard, &[])?;
+        let response = self.exchange(frame).await?;
+        self.client.handle_clipboard(&response, &nonce)
+    }
+
+    /// Close the connection.
+    pub async fn close(mut self) -> Result<(), ClientError> {
+        log::trace!("Sending closing frame");
+        let frame = self.client.close_frame()?;
+        let timeout = self.client.timeouts.read;
+        with_timeout(timeout, "closing the connection", async {
+            Ok(self.transport.send(frame).await?)
+        })
+        .await
+    }
+
+    /// Send a message and wait for the server acknowledgement.
+    async fn request(
+        &mut self,
+        m_type: NetFrameType,
+        message: &Message,
+    ) -> Result<(), ClientError> {
+        let payload = self.client.message_payload(message)?;
+        let (frame, nonce) = self.client.message_frame(m_type, &payload)?;
+        if crate::has_acks(self.client.version) {
+            let response = self.exchange(frame).await?;
+            self.client.handle_ack(&response, &nonce)?;
+        } else {
+            let timeout = self.client.timeouts.read;
+            with_timeout(timeout, "sending the message", async {
+                Ok(self.transport.send(frame).await?)
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Send a request and wait for its response.
+    async fn exchange(&mut self, frame: NetFrame) -> Result<NetFrame, ClientError> {
+        let timeout = self.client.timeouts.read;
+        with_timeout(timeout, "sending the message", async {
+            Ok(self.transport.send(frame).await?)
+        })
+        .await?;
+        with_timeout(
+            timeout,
+            "waiting for the server response",
+            next_frame(&mut self.transport),
+        )
+        .await
+    }
+}
+
+async fn handshake(
+    client: &mut Client<'_>,
+    transport: &mut FramedStream,
+) -> Result<(), ClientError> {
+    let (open_frame, secret) = client.open_frame()?;
+    let client_hello = open_frame.payload.clone();
+    transport.send(open_frame).await?;
+
+    let frame = next_frame(transport).await?;
+    let authenticator = client.handle_open(&frame, &client_hello, secret)?;
+    log::trace!("Received open response");
+    if let Some(authenticator) = authenticator {
+        transport.send(client.proof_frame(&authenticator)?).await?;
+        client.handle_auth(&next_frame(transport).await?, &authenticator)?;
+    }
+    Ok(())
+}
+
+async fn next_frame(transport: &mut FramedStream) -> Result<NetFrame, ClientError> {
+    match transport.next().await {
+        Some(frame) => Ok(frame?),
+        None => Err(ClientError::Io(std::io::Error::from(
+            std::io::ErrorKind::UnexpectedEof,
+        ))),
+    }
+}
+
+/// Fail with `ClientError::Timeout` if `future` does not complete in time.
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    context: &str,
+    future: impl Future<Output = Result<T, ClientError>>,
+) -> Result<T, ClientError> {
+    match timeout {
+        None => future.await,
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| ClientError::Timeout(context.into()))?,
+    }
+}
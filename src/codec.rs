@@ -0,0 +1,138 @@
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    read_frame_size, read_frame_type, read_protocol_version, FrameError, FrameSizeType, NetFrame,
+    HEADER_SIZE,
+};
+
+/// Codec of [`NetFrame`], the async counterpart of `NetFrame::from_net` and
+/// `NetFrame::to_net`.
+#[derive(Debug, Clone)]
+pub(crate) struct NetFrameCodec {
+    max_frame_size: FrameSizeType,
+}
+
+impl NetFrameCodec {
+    pub fn new(max_frame_size: FrameSizeType) -> Self {
+        Self { max_frame_size }
+    }
+}
+
+impl Decoder for NetFrameCodec {
+    type Item = NetFrame;
+    type Error = FrameError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < HEADER_SIZE {
+            src.reserve(HEADER_SIZE - src.len());
+            return Ok(None);
+        }
+
+        let header = &src[..HEADER_SIZE];
+        let protocol_version = read_protocol_version(header)?;
+        let frame_size = read_frame_size(header, self.max_frame_size)?;
+        let frame_type = read_frame_type(header)?;
+        // frame_size is bounded by max_frame_size and at least HEADER_SIZE
+        let size = usize::try_from(frame_size).map_err(|_| FrameError::Oversized {
+            size: frame_size,
+            max: self.max_frame_size,
+        })?;
+        if src.len() < size {
+            src.reserve(size - src.len());
+            return Ok(None);
+        }
+
+        let mut frame = src.split_to(size);
+        frame.advance(HEADER_SIZE);
+        Ok(Some(NetFrame {
+            protocol_version,
+            frame_size,
+            frame_type,
+            payload: frame.to_vec(),
+        }))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None if src.len() < HEADER_SIZE => Err(FrameError::Io(std::io::Error::from(
+                std::io::ErrorKind::UnexpectedEof,
+            ))),
+            None => {
+                let frame_size = read_frame_size(&src[..HEADER_SIZE], self.max_frame_size)?;
+                Err(FrameError::Truncated {
+                    expected: frame_size as usize - HEADER_SIZE,
+                })
+            }
+        }
+    }
+}
+
+impl Encoder<NetFrame> for NetFrameCodec {
+    type Error = FrameError;
+
+    fn encode(&mut self, frame: NetFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&frame.to_net());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{NetFrameType, DEFAULT_MAX_FRAME_SIZE, PROTOCOL_VERSION};
+
+    #[test]
+    fn test_roundtrip() {
+        let mut codec = NetFrameCodec::new(DEFAULT_MAX_FRAME_SIZE);
+        let frame = NetFrame::new(
+            PROTOCOL_VERSION,
+            NetFrameType::CopyMessage,
+            b"hello".to_vec(),
+        );
+        let mut bytes = BytesMut::new();
+        codec.encode(frame, &mut bytes).unwrap();
+        assert_eq!(HEADER_SIZE + 5, bytes.len());
+
+        // Frames are only decoded once complete
+        let mut partial = bytes.split_to(HEADER_SIZE + 2);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(bytes);
+        let decoded = codec.decode(&mut partial).unwrap().unwrap();
+        assert_eq!(NetFrameType::CopyMessage, decoded.frame_type);
+        assert_eq!(b"hello".as_slice(), decoded.payload);
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn test_oversized_frame() {
+        let mut codec = NetFrameCodec::new(HEADER_SIZE as FrameSizeType + 1);
+        let frame = NetFrame::new(
+            PROTOCOL_VERSION,
+            NetFrameType::CopyMessage,
+            b"hello".to_vec(),
+        );
+        let mut bytes = BytesMut::from(frame.to_net().as_slice());
+        assert!(matches!(
+            codec.decode(&mut bytes),
+            Err(FrameError::Oversized { .. })
+        ));
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let mut codec = NetFrameCodec::new(DEFAULT_MAX_FRAME_SIZE);
+        let frame = NetFrame::new(
+            PROTOCOL_VERSION,
+            NetFrameType::CopyMessage,
+            b"hello".to_vec(),
+        );
+        let mut bytes = BytesMut::from(&frame.to_net()[..HEADER_SIZE + 2]);
+        assert!(matches!(
+            codec.decode_eof(&mut bytes),
+            Err(FrameError::Truncated { .. })
+        ));
+    }
+}
@@ -0,0 +1,364 @@
+use std::io::{Error, ErrorKind};
+
+use chacha20poly1305::{Key, KeyInit};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use log::error;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, SharedSecret};
+use zeroize::Zeroizing;
+
+use crate::{
+    kdf::KdfParams, Cipher, Nonce, ProtocolVersionType, SecretKey, KEY_SIZE, NOUNCE_SIZE,
+    PROTOCOL_VERSION, PROTOCOL_VERSION_SIZE,
+};
+
+const CAPABILITIES_SIZE: usize = std::mem::size_of::<u32>();
+const PUBLIC_KEY_SIZE: usize = 32;
+/// Maximum length in bytes of a key ID, which is preceded by its length.
+pub const MAX_KEY_ID_SIZE: usize = u8::MAX as usize;
+const SESSION_KEY_INFO: &[u8] = b"copiepate session key";
+const AUTHENTICATION_KEY_INFO: &[u8] = b"copiepate authentication key";
+/// Prefix of the data signed by clients authenticating with an SSH key, and by the server
+/// host key.
+const SSH_CHALLENGE_PREFIX: &[u8] = b"copiepate ssh authentication";
+pub(crate) const PROOF_SIZE: usize = 32;
+
+/// Optional protocol features, a feature is used only if both peers support it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Request the content of the server clipboard with GetClipboard frames
+    pub const FETCH: Self = Self(1 << 0);
+
+    /// Capabilities supported by this implementation.
+    pub fn supported() -> Self {
+        Self::FETCH
+    }
+
+    pub fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(&self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// How a client proves its identity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum AuthMethod {
+    /// Proof of the knowledge of a pre-shared key
+    #[default]
+    Secret = 0,
+    /// Signature of an SSH key listed in the server authorized keys, the server signs
+    /// with its host key in turn
+    Ssh = 1,
+}
+
+/// Payload of the client Open frame.
+/// | min_version | max_version | capabilities | public_key (since version 4) | key_id_size | key_id (since version 9) | auth_method (since version 10) | kdf (since version 12) |
+///
+/// An empty key ID is no key ID, trailing fields are omitted when they have their
+/// default value.
+///
+/// Legacy clients send an empty Open frame and only speak the protocol version of its
+/// header.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientHello {
+    pub min_version: ProtocolVersionType,
+    pub max_version: ProtocolVersionType,
+    pub capabilities: Capabilities,
+    /// Ephemeral X25519 public key of the client
+    pub public_key: Option<PublicKey>,
+    /// ID of the server key the client authenticates with, the server default key if
+    /// `None`
+    pub key_id: Option<String>,
+    pub auth_method: AuthMethod,
+    /// Parameters the key of the client was derived from a passphrase with
+    pub kdf: Option<KdfParams>,
+}
+
+impl ClientHello {
+    pub fn new(
+        min_version: ProtocolVersionType,
+        public_key: PublicKey,
+        key_id: Option<String>,
+        auth_method: AuthMethod,
+        kdf: Option<KdfParams>,
+    ) -> Self {
+        Self {
+            min_version,
+            max_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+            public_key: Some(public_key),
+            key_id,
+            auth_method,
+            kdf,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes =
+            Vec::with_capacity(2 * PROTOCOL_VERSION_SIZE + CAPABILITIES_SIZE + PUBLIC_KEY_SIZE);
+        bytes.extend_from_slice(&self.min_version.to_le_bytes());
+        bytes.extend_from_slice(&self.max_version.to_le_bytes());
+        bytes.extend_from_slice(&self.capabilities.0.to_le_bytes());
+        if let Some(public_key) = &self.public_key {
+            bytes.extend_from_slice(public_key.as_bytes());
+            // The key ID follows the public key, IDs are checked to fit by the client
+            let key_id = self.key_id.as_deref().unwrap_or_default();
+            let has_auth_method = self.auth_method != AuthMethod::Secret || self.kdf.is_some();
+            if !key_id.is_empty() || has_auth_method {
+                bytes.push(key_id.len() as u8);
+                bytes.extend_from_slice(key_id.as_bytes());
+            }
+            if has_auth_method {
+                bytes.push(self.auth_method as u8);
+            }
+            if let Some(kdf) = &self.kdf {
+                bytes.extend_from_slice(&kdf.to_bytes());
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(bytes);
+        Ok(Self {
+            min_version: reader.read_u32()?,
+            max_version: reader.read_u32()?,
+            capabilities: Capabilities(reader.read_u32()?),
+            public_key: reader.read_public_key()?,
+            key_id: reader.read_key_id()?,
+            auth_method: reader.read_auth_method()?,
+            kdf: reader.read_kdf()?,
+        })
+    }
+
+    /// Highest protocol version supported by both peers, if any.
+    pub fn negotiate(&self, min_version: ProtocolVersionType) -> Option<ProtocolVersionType> {
+        let version = self.max_version.min(PROTOCOL_VERSION);
+        (version >= self.min_version.max(min_version)).then_some(version)
+    }
+}
+
+/// Payload of the server Open frame, the negotiated version is the frame header version.
+/// | nonce | capabilities | public_key (since version 4) |
+///
+/// Legacy servers only send the nonce.
+#[derive(Debug, Clone)]
+pub(crate) struct ServerHello {
+    pub nonce: Nonce,
+    pub capabilities: Capabilities,
+    /// Ephemeral X25519 public key of the server
+    pub public_key: Option<PublicKey>,
+}
+
+impl ServerHello {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(NOUNCE_SIZE + CAPABILITIES_SIZE + PUBLIC_KEY_SIZE);
+        bytes.extend_from_slice(&self.nonce.value);
+        bytes.extend_from_slice(&self.capabilities.0.to_le_bytes());
+        if let Some(public_key) = &self.public_key {
+            bytes.extend_from_slice(public_key.as_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(bytes);
+        let nonce = Nonce::from(reader.read_array::<NOUNCE_SIZE>()?);
+        let capabilities = if reader.is_empty() {
+            Capabilities::empty()
+        } else {
+            Capabilities(reader.read_u32()?)
+        };
+        Ok(Self {
+            nonce,
+            capabilities,
+            public_key: reader.read_public_key()?,
+        })
+    }
+}
+
+/// Derive the cipher of a session from the pre-shared key and the ephemeral key exchange.
+/// Both public keys and the nonce are bound to the session key.
+pub(crate) fn session_cipher(
+    key: &Key,
+    shared_secret: &SharedSecret,
+    client_public_key: &PublicKey,
+    server_public_key: &PublicKey,
+    nonce: &Nonce,
+) -> Cipher {
+    let hkdf = Hkdf::<Sha256>::new(Some(key.as_slice()), shared_secret.as_bytes());
+    let mut info = SESSION_KEY_INFO.to_vec();
+    info.extend_from_slice(client_public_key.as_bytes());
+    info.extend_from_slice(server_public_key.as_bytes());
+    info.extend_from_slice(&nonce.value);
+
+    let mut session_key = Zeroizing::new([0; KEY_SIZE]);
+    hkdf.expand(&info, session_key.as_mut_slice())
+        .expect("Session key length is valid for HKDF");
+    Cipher::new(Key::from_slice(&*session_key))
+}
+
+/// Peer proving that it knows the pre-shared key.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Role {
+    Client,
+    Server,
+}
+
+impl Role {
+    pub fn label(&self) -> &'static [u8] {
+        match self {
+            Role::Client => b"client",
+            Role::Server => b"server",
+        }
+    }
+}
+
+/// Prove and verify the knowledge of the pre-shared key, bound to the handshake
+/// transcript: the negotiated version and both Open payloads.
+pub(crate) struct Authenticator {
+    key: SecretKey,
+    transcript: [u8; 32],
+}
+
+impl Authenticator {
+    pub fn new(
+        key: &Key,
+        shared_secret: &SharedSecret,
+        version: ProtocolVersionType,
+        client_hello: &[u8],
+        server_hello: &[u8],
+    ) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(Some(key.as_slice()), shared_secret.as_bytes());
+        let mut authentication_key = Zeroizing::new([0; KEY_SIZE]);
+        hkdf.expand(AUTHENTICATION_KEY_INFO, authentication_key.as_mut_slice())
+            .expect("Authentication key length is valid for HKDF");
+
+        let transcript = Sha256::new()
+            .chain_update(version.to_le_bytes())
+            .chain_update(client_hello)
+            .chain_update(server_hello)
+            .finalize();
+        Self {
+            key: authentication_key,
+            transcript: transcript.into(),
+        }
+    }
+
+    pub fn proof(&self, role: Role) -> [u8; PROOF_SIZE] {
+        self.mac(role).finalize().into_bytes().into()
+    }
+
+    /// Data signed by the SSH key of `role`, bound to the handshake transcript and to the
+    /// key exchange.
+    pub fn ssh_challenge(&self, role: Role) -> Vec<u8> {
+        let mut challenge = SSH_CHALLENGE_PREFIX.to_vec();
+        challenge.extend_from_slice(&self.proof(role));
+        challenge
+    }
+
+    /// Check a proof in constant time.
+    pub fn verify(&self, role: Role, proof: &[u8]) -> bool {
+        self.mac(role).verify_slice(proof).is_ok()
+    }
+
+    fn mac(&self, role: Role) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(self.key.as_slice())
+            .expect("HMAC accepts keys of any size");
+        mac.update(role.label());
+        mac.update(&self.transcript);
+        mac
+    }
+}
+
+/// Read fixed size fields from a handshake payload.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        if self.bytes.len() < N {
+            error!("Handshake payload too short");
+            return Err(Error::from(ErrorKind::InvalidData));
+        }
+        let (value, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(value.try_into().expect("Slice with incorrect length"))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    /// Public keys are optional trailing fields, absent for older protocol versions.
+    fn read_public_key(&mut self) -> Result<Option<PublicKey>, Error> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(PublicKey::from(self.read_array::<PUBLIC_KEY_SIZE>()?)))
+    }
+
+    /// Key IDs are optional trailing fields, preceded by their length.
+    fn read_key_id(&mut self) -> Result<Option<String>, Error> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let [size] = self.read_array::<1>()?;
+        if self.bytes.len() < size as usize {
+            error!("Handshake payload too short");
+            return Err(Error::from(ErrorKind::InvalidData));
+        }
+        let (key_id, rest) = self.bytes.split_at(size as usize);
+        self.bytes = rest;
+        if key_id.is_empty() {
+            return Ok(None);
+        }
+        String::from_utf8(key_id.to_vec())
+            .map(Some)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "key ID is not valid UTF-8"))
+    }
+
+    /// KDF parameters are optional trailing fields.
+    fn read_kdf(&mut self) -> Result<Option<KdfParams>, Error> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let (kdf, size) = KdfParams::from_bytes(self.bytes)?;
+        self.bytes = &self.bytes[size..];
+        Ok(Some(kdf))
+    }
+
+    /// Authentication methods are optional trailing fields, secrets are used if absent.
+    fn read_auth_method(&mut self) -> Result<AuthMethod, Error> {
+        if self.is_empty() {
+            return Ok(AuthMethod::Secret);
+        }
+        match self.read_array::<1>()? {
+            [0] => Ok(AuthMethod::Secret),
+            [1] => Ok(AuthMethod::Ssh),
+            [method] => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown authentication method {method}"),
+            )),
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! Keys derived from a passphrase with Argon2id, instead of random secrets.
+
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::Engine;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::KEY_SIZE;
+
+/// Default memory cost in KiB.
+pub const DEFAULT_MEMORY_COST: u32 = 19 * 1024;
+/// Default number of passes over the memory.
+pub const DEFAULT_TIME_COST: u32 = 2;
+/// Default number of lanes.
+pub const DEFAULT_PARALLELISM: u32 = 1;
+/// Minimum size of a salt in bytes.
+pub const MIN_SALT_SIZE: usize = 8;
+/// Maximum size of a salt in bytes, which is preceded by its length in Open frames.
+pub const MAX_SALT_SIZE: usize = u8::MAX as usize;
+/// Identifier of Argon2id in Open frames.
+const ARGON2ID: u8 = 1;
+const PARAMS_SIZE: usize = 1 + 3 * std::mem::size_of::<u32>() + 1;
+
+#[derive(Error, Debug)]
+pub enum KdfError {
+    #[error("Salt must be {MIN_SALT_SIZE} to {MAX_SALT_SIZE} bytes long, got {0} bytes")]
+    InvalidSalt(usize),
+
+    #[error("Invalid Argon2 parameters: {0}")]
+    InvalidParams(argon2::Error),
+}
+
+/// Argon2id parameters a key is derived from a passphrase with. Client and server must use
+/// the same parameters and salt to derive the same key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KdfParams {
+    pub salt: Vec<u8>,
+    /// Memory cost in KiB
+    pub memory_cost: u32,
+    /// Number of passes over the memory
+    pub time_cost: u32,
+    /// Number of lanes
+    pub parallelism: u32,
+}
+
+impl KdfParams {
+    /// Default parameters with `salt`.
+    pub fn new(salt: &[u8]) -> Self {
+        Self {
+            salt: salt.to_vec(),
+            memory_cost: DEFAULT_MEMORY_COST,
+            time_cost: DEFAULT_TIME_COST,
+            parallelism: DEFAULT_PARALLELISM,
+        }
+    }
+
+    /// Check the salt and the costs.
+    pub fn validate(&self) -> Result<(), KdfError> {
+        if !(MIN_SALT_SIZE..=MAX_SALT_SIZE).contains(&self.salt.len()) {
+            return Err(KdfError::InvalidSalt(self.salt.len()));
+        }
+        self.argon2().map(|_| ())
+    }
+
+    /// Derive a key from `passphrase`, wiped from memory once dropped.
+    pub fn derive_key(&self, passphrase: &[u8]) -> Result<Zeroizing<[u8; KEY_SIZE]>, KdfError> {
+        self.validate()?;
+        let mut key = Zeroizing::new([0; KEY_SIZE]);
+        self.argon2()?
+            .hash_password_into(passphrase, &self.salt, key.as_mut_slice())
+            .map_err(KdfError::InvalidParams)?;
+        Ok(key)
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>, KdfError> {
+        let params = Params::new(
+            self.memory_cost,
+            self.time_cost,
+            self.parallelism,
+            Some(KEY_SIZE),
+        )
+        .map_err(KdfError::InvalidParams)?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+
+    /// | algorithm (u8) | memory_cost | time_cost | parallelism | salt_size (u8) | salt |
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PARAMS_SIZE + self.salt.len());
+        bytes.push(ARGON2ID);
+        bytes.extend_from_slice(&self.memory_cost.to_le_bytes());
+        bytes.extend_from_slice(&self.time_cost.to_le_bytes());
+        bytes.extend_from_slice(&self.parallelism.to_le_bytes());
+        // Salts are checked to fit by `validate`
+        bytes.push(self.salt.len() as u8);
+        bytes.extend_from_slice(&self.salt);
+        bytes
+    }
+
+    /// Parse parameters, returns the number of bytes read.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<(Self, usize), Error> {
+        if bytes.len() < PARAMS_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "KDF parameters too short",
+            ));
+        }
+        if bytes[0] != ARGON2ID {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown KDF algorithm {}", bytes[0]),
+            ));
+        }
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes(
+                bytes[offset..offset + 4]
+                    .try_into()
+                    .expect("Slice with incorrect length"),
+            )
+        };
+        let salt_size = bytes[PARAMS_SIZE - 1] as usize;
+        let salt = bytes
+            .get(PARAMS_SIZE..PARAMS_SIZE + salt_size)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "KDF salt too short"))?;
+        let params = Self {
+            salt: salt.to_vec(),
+            memory_cost: u32_at(1),
+            time_cost: u32_at(5),
+            parallelism: u32_at(9),
+        };
+        Ok((params, PARAMS_SIZE + salt_size))
+    }
+}
+
+impl fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "argon2id memory_cost={} time_cost={} parallelism={} salt={}",
+            self.memory_cost,
+            self.time_cost,
+            self.parallelism,
+            base64::engine::general_purpose::STANDARD.encode(&self.salt)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(salt: &[u8]) -> KdfParams {
+        KdfParams {
+            memory_cost: 64,
+            time_cost: 1,
+            ..KdfParams::new(salt)
+        }
+    }
+
+    #[test]
+    fn test_derive_key() {
+        let key = params(b"copiepate salt").derive_key(b"passphrase").unwrap();
+        assert_eq!(
+            key,
+            params(b"copiepate salt").derive_key(b"passphrase").unwrap()
+        );
+        assert_ne!(key, params(b"copiepate salt").derive_key(b"other").unwrap());
+        assert_ne!(
+            key,
+            params(b"another salt").derive_key(b"passphrase").unwrap()
+        );
+        assert!(matches!(
+            params(b"short").derive_key(b"passphrase"),
+            Err(KdfError::InvalidSalt(5))
+        ));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let params = params(b"copiepate salt");
+        let mut bytes = params.to_bytes();
+        bytes.push(0);
+        assert_eq!(
+            (params, bytes.len() - 1),
+            KdfParams::from_bytes(&bytes).unwrap()
+        );
+        assert!(KdfParams::from_bytes(&bytes[..PARAMS_SIZE]).is_err());
+    }
+}
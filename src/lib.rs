@@ -1,25 +1,173 @@
+use chacha20poly1305::aead::{Aead, Payload};
 use log::{error, trace};
 use num_derive::{FromPrimitive, ToPrimitive};
 use rand::prelude::*;
-use std::io::{Error, ErrorKind, Read};
+use std::{
+    io::{Error, ErrorKind, Read},
+    net::TcpStream,
+    time::Duration,
+};
+use thiserror::Error;
+use zeroize::Zeroizing;
 
+pub mod backend;
 pub mod client;
+#[cfg(feature = "async")]
+mod codec;
+mod handshake;
+pub mod kdf;
+mod message;
+pub mod pairing;
 pub mod server;
+pub mod ssh;
+pub mod transport;
 
-/// Protocol (wanted):
-/// client ----------- Open[] -----------> server
-/// client <-------- Open[Nounce] -------- server
-/// client ------ Message[[u8]] ------> server [Encrypted with Nounce]
-/// client ------ Message[[u8]] ------> server [Encrypted with Nounce+1]
-/// client ----------- Close[] ----------> server [Encrypted with Nounce+2]
+pub use handshake::Capabilities;
+pub use message::{Message, Selection, DEFAULT_CONTENT_TYPE};
+
+// Protocol (wanted):
+// client ------- Open[ClientHello] ----> server
+// client <------ Open[ServerHello] ----- server [Header with negotiated version]
+// client ---- Message[Message] ------> server [Encrypted with Nounce]
+// client <-------- Ack[Status] --------- server [Encrypted with Reply(Nounce)]
+// client ---- Message[Message] ------> server [Encrypted with Nounce+1]
+// client <------- Error[Status] -------- server [Encrypted with Reply(Nounce+1)]
+// client ---------- GetClipboard[] ----> server [Encrypted with Nounce+2]
+// client <--- Clipboard[Message] ------- server [Encrypted with Reply(Nounce+2)]
+// client ----------- Close[] ----------> server [Encrypted with Nounce+3]
+
+// The client Open frame uses MIN_PROTOCOL_VERSION in its header so that any server can
+// parse it, and advertises the protocol versions and capabilities of the client. The
+// server answers with the highest version supported by both peers, which is then used
+// by every following frame, or with a plaintext Error frame if there is none.
+// Legacy clients send an empty Open frame instead, which is only accepted with a header
+// version older than 4, the first version that negotiates.
+// From version 5 the proofs authenticate the negotiated version, older versions can't
+// detect a downgrade. Peers setting their minimum version to 5 refuse to be downgraded.
+//
+// Protocol versions:
+// 1. Legacy protocol: empty Open frame, no response to messages.
+// 2. Messages are acknowledged by Ack and Error frames.
+// 3. The header of every encrypted frame is authenticated as associated data: a frame
+//    whose header was tampered with fails decryption.
+// 4. Open frames carry ephemeral X25519 public keys. Frames are encrypted with a session
+//    key derived with HKDF from the key exchange and the pre-shared secret, so that
+//    recorded sessions can't be decrypted if the secret leaks.
+// 5. Both peers prove they know the pre-shared secret before any message is exchanged:
+//    client ---------- Auth[Proof] --------> server
+//    client <--------- Auth[Proof] --------- server
+//    Proofs are HMACs of the handshake transcript, a failed proof closes the connection.
+// 6. Message contents are raw bytes preceded by their declared charset.
+// 7. Message contents are also preceded by their MIME content type.
+// 8. Messages name the selection they are written to: clipboard, primary or both.
+// 9. The client Open frame may end with the ID of the key the client authenticates with,
+//    the server picks that key from its keyring instead of its default key.
+// 10. The client Open frame may end with its authentication method. Clients
+//    authenticating with an SSH key send their public key and the signature of the
+//    handshake instead of their proof, and the server answers with its host key and
+//    signature, which the client looks up in its known hosts. Session keys are then
+//    derived from the key exchange only:
+//    client ---- Auth[SSH public key | signature] ----> server
+//    client <--- Auth[SSH host key | signature] ------- server
+// 11. Clients pair with a server waiting with a one-time code, instead of opening a
+//    connection. Both derive a new secret from the code with SPAKE2 and prove they
+//    derived the same key before saving it:
+//    client ---- Pair[SPAKE2 message] ----> server
+//    client <--- Pair[SPAKE2 message] ----- server
+//    client ---------- Auth[Proof] -------> server
+//    client <--------- Auth[Proof] -------- server
+// 12. The client Open frame may end with the Argon2id parameters its key was derived
+//    from a passphrase with, the server rejects the connection if its own key was
+//    derived with other parameters.
 
 // Client states:
 // Start -> Opening -> Opened -> Closed
 
 // Bump protocol version if breaking change is introduced to the network protocol.
-const PROTOCOL_VERSION: u32 = 1;
+pub const PROTOCOL_VERSION: u32 = 12;
+/// Oldest protocol version still supported.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version authenticating the handshake transcript. Using it as the
+/// minimum protocol version prevents a peer in the middle from downgrading connections,
+/// at the cost of refusing older peers such as copiepate 0.2.
+pub const MIN_AUTHENTICATED_PROTOCOL_VERSION: u32 = 5;
 pub const NOUNCE_SIZE: usize = 12;
 pub const KEY_SIZE: usize = 32;
+/// Pre-shared key, wiped from memory once dropped.
+pub(crate) type SecretKey = Zeroizing<[u8; KEY_SIZE]>;
+/// Size of the authentication tag appended to each encrypted payload.
+pub const TAG_SIZE: usize = 16;
+
+/// Default maximum size of a message.
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+/// Room left in a frame for its header and encryption overhead.
+pub const FRAME_OVERHEAD: u64 = 1024;
+/// Default maximum size of a frame, header and encryption overhead included.
+pub const DEFAULT_MAX_FRAME_SIZE: u64 = DEFAULT_MAX_PAYLOAD_SIZE as u64 + FRAME_OVERHEAD;
+/// Default maximum number of connections a server handles at the same time.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+/// Default time to establish a connection to the server.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default time to open and authenticate a connection.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default time to wait for a read or a write on an opened connection.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default time the server waits for the next message of an opened connection.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Socket timeouts of clients and servers, `None` waits for ever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Establish the connection to the server, client only
+    pub connect: Option<Duration>,
+    /// Open and authenticate the connection
+    pub handshake: Option<Duration>,
+    /// Each read and write once the connection is opened: the server response on the
+    /// client, the rest of a frame on the server
+    pub read: Option<Duration>,
+    /// Wait for the next message of an opened connection, server only
+    pub idle: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Some(DEFAULT_CONNECT_TIMEOUT),
+            handshake: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+            read: Some(DEFAULT_READ_TIMEOUT),
+            idle: Some(DEFAULT_IDLE_TIMEOUT),
+        }
+    }
+}
+
+/// Streams whose reads and writes can time out.
+pub(crate) trait Timeout {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), Error>;
+}
+
+impl Timeout for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+/// Whether an IO error is a read or write timeout, reported as `WouldBlock` on Unix.
+fn is_timeout(error: &Error) -> bool {
+    matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Copy `key` to a [`SecretKey`], panics if it isn't `KEY_SIZE` bytes long.
+fn secret_key(key: &[u8]) -> SecretKey {
+    let mut secret = Zeroizing::new([0; KEY_SIZE]);
+    secret.copy_from_slice(key);
+    secret
+}
 
 // deciphered close payload
 pub const CLOSE_PAYLOAD: [u8; 1] = [b'c'];
@@ -46,6 +194,15 @@ impl Nonce {
         Self { value }
     }
 
+    /// Nonce used to encrypt the server response to a frame encrypted with this nonce.
+    /// The most significant bit is flipped so that requests and responses never share
+    /// a nonce.
+    pub fn reply(&self) -> Self {
+        let mut value = self.value;
+        value[0] ^= 0x80;
+        Self { value }
+    }
+
     /// Get Nonce reference digestable by current cipher.
     pub fn cipher_nonce(&self) -> &chacha20poly1305::Nonce {
         chacha20poly1305::Nonce::from_slice(&self.value)
@@ -77,11 +234,13 @@ impl TryFrom<Vec<u8>> for Nonce {
 #[derive(Debug)]
 pub enum ConnectionState {
     New,
+    /// Waiting for the peer to prove it knows the pre-shared key
+    Authenticating(Nonce),
     Opened(Nonce),
     Closed,
 }
 
-#[derive(Debug, Copy, Clone, FromPrimitive, ToPrimitive)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromPrimitive, ToPrimitive)]
 enum NetFrameType {
     /// Open new connection
     Open = 0,
@@ -91,15 +250,150 @@ enum NetFrameType {
     CopyMessage = 2,
     /// Send a non-copy message
     ExecMessage = 3,
+    /// Acknowledge a message
+    Ack = 4,
+    /// Reject a message
+    Error = 5,
+    /// Request the content of the server clipboard
+    GetClipboard = 6,
+    /// Content of the server clipboard
+    Clipboard = 7,
+    /// Proof of knowledge of the pre-shared key
+    Auth = 8,
+    /// SPAKE2 message of a client pairing with a server
+    Pair = 9,
+}
+
+/// Status code carried by Ack and Error frames.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromPrimitive, ToPrimitive)]
+pub enum StatusCode {
+    /// Message handled successfully
+    Ok = 0,
+    /// Unknown status code, sent by a more recent peer
+    Unknown = 1,
+    /// Server could not decrypt the message, secrets are likely different
+    DecryptionFailed = 2,
+    /// Message received in an unexpected connection state
+    InvalidState = 3,
+    /// Server failed to write to its clipboard
+    ClipboardFailed = 4,
+    /// Server failed to execute its command
+    ExecFailed = 5,
+    /// Server failed for any other reason
+    ServerFailed = 6,
+    /// Server does not allow this request
+    Forbidden = 7,
+    /// Message is larger than the server maximum payload size
+    PayloadTooLarge = 8,
+    /// Frame could not be parsed
+    MalformedFrame = 9,
+    /// Client and server have no protocol version in common
+    UnsupportedVersion = 10,
+    /// Connection could not be opened
+    HandshakeFailed = 11,
+    /// Client failed to prove it knows the secret
+    AuthenticationFailed = 12,
+    /// Server can't handle the content of the message
+    UnsupportedContent = 13,
+    /// Server is handling too many connections
+    Busy = 14,
+    /// Client and server keys are derived from a passphrase with different parameters
+    KdfMismatch = 15,
+}
+
+impl std::fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let code = num_traits::ToPrimitive::to_u32(self).unwrap();
+        write!(f, "{code} ({self:?})")
+    }
+}
+
+/// Content of Ack and Error frames.
+/// | code (u32) | message (utf-8) |
+#[derive(Debug, Clone)]
+struct Status {
+    code: StatusCode,
+    message: String,
 }
 
-type ProtocolVersionType = u32;
+impl Status {
+    fn to_bytes(&self) -> Vec<u8> {
+        let code = num_traits::ToPrimitive::to_u32(&self.code).unwrap();
+        let mut bytes = Vec::with_capacity(STATUS_CODE_SIZE + self.message.len());
+        bytes.extend_from_slice(&code.to_le_bytes());
+        bytes.extend_from_slice(self.message.as_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < STATUS_CODE_SIZE {
+            error!("Status payload too short: {} bytes", bytes.len());
+            return Err(Error::from(ErrorKind::InvalidData));
+        }
+        let (code, message) = bytes.split_at(STATUS_CODE_SIZE);
+        let code = u32::from_le_bytes(code.try_into().expect("Slice with incorrect length"));
+        Ok(Self {
+            code: num_traits::FromPrimitive::from_u32(code).unwrap_or(StatusCode::Unknown),
+            message: String::from_utf8_lossy(message).into_owned(),
+        })
+    }
+}
+
+/// Whether messages are acknowledged by Ack and Error frames.
+fn has_acks(protocol_version: ProtocolVersionType) -> bool {
+    protocol_version >= 2
+}
+
+/// Whether frame headers are authenticated as associated data.
+fn has_authenticated_header(protocol_version: ProtocolVersionType) -> bool {
+    protocol_version >= 3
+}
+
+/// Whether clients negotiate the protocol version in their Open frame, instead of
+/// sending an empty one.
+fn has_negotiation(protocol_version: ProtocolVersionType) -> bool {
+    protocol_version >= 4
+}
+
+/// Whether frames are encrypted with a key derived from an ephemeral key exchange.
+fn has_key_exchange(protocol_version: ProtocolVersionType) -> bool {
+    protocol_version >= 4
+}
+
+/// Whether both peers prove they know the pre-shared key with Auth frames.
+fn has_mutual_authentication(protocol_version: ProtocolVersionType) -> bool {
+    protocol_version >= 5
+}
+
+/// Whether messages declare the charset of their content.
+fn has_charset(protocol_version: ProtocolVersionType) -> bool {
+    protocol_version >= 6
+}
+
+/// Whether messages declare the content type of their content.
+fn has_content_type(protocol_version: ProtocolVersionType) -> bool {
+    protocol_version >= 7
+}
+
+/// Whether messages name the selection they are written to.
+fn has_selection(protocol_version: ProtocolVersionType) -> bool {
+    protocol_version >= 8
+}
+
+/// Whether clients may authenticate with an SSH key.
+fn has_ssh_authentication(protocol_version: ProtocolVersionType) -> bool {
+    protocol_version >= 10
+}
+
+pub type ProtocolVersionType = u32;
 type FrameSizeType = u64;
 type NetFrameTypeType = u32;
 
 const PROTOCOL_VERSION_SIZE: usize = std::mem::size_of::<ProtocolVersionType>();
 const FRAME_SIZE_SIZE: usize = std::mem::size_of::<FrameSizeType>();
 const FRAME_TYPE_SIZE: usize = std::mem::size_of::<NetFrameTypeType>();
+const HEADER_SIZE: usize = PROTOCOL_VERSION_SIZE + FRAME_SIZE_SIZE + FRAME_TYPE_SIZE;
+const STATUS_CODE_SIZE: usize = std::mem::size_of::<u32>();
 
 /// Netframe representation on network:
 /// | 1 | 2 | 3 | 4 | 5 | 6 | 7 | 8 |
@@ -122,7 +416,32 @@ struct NetFrame {
     payload: Vec<u8>,
 }
 
-fn read_protocol_version(header: &[u8]) -> Result<u32, Error> {
+/// Errors raised while reading a frame from the network.
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("Unsupported protocol version {0}")]
+    UnsupportedVersion(ProtocolVersionType),
+
+    #[error("Frame size {0} is smaller than the frame header")]
+    Undersized(FrameSizeType),
+
+    #[error("Frame size {size} exceeds the maximum frame size {max}")]
+    Oversized {
+        size: FrameSizeType,
+        max: FrameSizeType,
+    },
+
+    #[error("Unknown frame type {0}")]
+    UnknownType(NetFrameTypeType),
+
+    #[error("Truncated frame, expected {expected} bytes of payload")]
+    Truncated { expected: usize },
+}
+
+fn read_protocol_version(header: &[u8]) -> Result<u32, FrameError> {
     const OFFSET: usize = 0;
     const WIDTH: usize = PROTOCOL_VERSION_SIZE;
     let protocol_version = ProtocolVersionType::from_le_bytes(
@@ -130,34 +449,52 @@ fn read_protocol_version(header: &[u8]) -> Result<u32, Error> {
             .try_into()
             .expect("Slice with incorrect length"),
     );
-    if protocol_version != PROTOCOL_VERSION {
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
         error!(
             "Invalid protocol version message received. \
-            Make sure client and server are using the same version. \
-            Received: {}, Current {}",
-            protocol_version, PROTOCOL_VERSION
+            Make sure client and server are using compatible versions. \
+            Received: {}, Supported {} to {}",
+            protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
         );
-        Err(Error::from(ErrorKind::Unsupported))
+        Err(FrameError::UnsupportedVersion(protocol_version))
     } else {
         Ok(protocol_version)
     }
 }
 
-fn read_frame_size(header: &[u8]) -> Result<u64, Error> {
+fn read_frame_size(header: &[u8], max_frame_size: FrameSizeType) -> Result<u64, FrameError> {
     const OFFSET: usize = PROTOCOL_VERSION_SIZE;
     const WIDTH: usize = FRAME_SIZE_SIZE;
-    Ok(FrameSizeType::from_le_bytes(
+    let frame_size = FrameSizeType::from_le_bytes(
         header[OFFSET..OFFSET + WIDTH]
             .try_into()
             .expect("slice with incorrect length"),
-    ))
+    );
+    if frame_size < HEADER_SIZE as FrameSizeType {
+        error!(
+            "Received frame smaller than its header: {} bytes",
+            frame_size
+        );
+        return Err(FrameError::Undersized(frame_size));
+    }
+    if frame_size > max_frame_size {
+        error!(
+            "Received frame larger than the maximum frame size: {} > {}",
+            frame_size, max_frame_size
+        );
+        return Err(FrameError::Oversized {
+            size: frame_size,
+            max: max_frame_size,
+        });
+    }
+    Ok(frame_size)
 }
 
-fn read_frame_type(payload: &[u8]) -> Result<NetFrameType, Error> {
-    const OFFSET: usize = 0;
+fn read_frame_type(header: &[u8]) -> Result<NetFrameType, FrameError> {
+    const OFFSET: usize = PROTOCOL_VERSION_SIZE + FRAME_SIZE_SIZE;
     const WIDTH: usize = FRAME_TYPE_SIZE;
-    let frame_type_num = u32::from_le_bytes(
-        payload[OFFSET..OFFSET + WIDTH]
+    let frame_type_num = NetFrameTypeType::from_le_bytes(
+        header[OFFSET..OFFSET + WIDTH]
             .try_into()
             .expect("Slice with incorrect length"),
     );
@@ -168,60 +505,142 @@ fn read_frame_type(payload: &[u8]) -> Result<NetFrameType, Error> {
                 "Could not parse frame type. Frame type received: {}",
                 frame_type_num
             );
-            return Err(Error::from(std::io::ErrorKind::InvalidData));
+            return Err(FrameError::UnknownType(frame_type_num));
         }
     };
     Ok(frame_type)
 }
 
 impl NetFrame {
-    fn new(frame_type: NetFrameType, payload: Vec<u8>) -> Self {
+    fn new(
+        protocol_version: ProtocolVersionType,
+        frame_type: NetFrameType,
+        payload: Vec<u8>,
+    ) -> Self {
         Self {
-            protocol_version: PROTOCOL_VERSION,
+            protocol_version,
             frame_size: NetFrame::compute_frame_size(&payload),
             frame_type,
             payload,
         }
     }
 
-    /// Export a netframe to vector stream
-    fn to_net(&self) -> Vec<u8> {
-        let mut vector = Vec::with_capacity(self.frame_size as usize);
+    /// Create a frame with the message encrypted, authenticating the frame header if the
+    /// protocol version supports it.
+    fn encrypted(
+        protocol_version: ProtocolVersionType,
+        frame_type: NetFrameType,
+        cipher: &Cipher,
+        nonce: &Nonce,
+        message: &[u8],
+    ) -> Result<Self, chacha20poly1305::aead::Error> {
+        let mut frame = Self {
+            protocol_version,
+            frame_size: (HEADER_SIZE + message.len() + TAG_SIZE).try_into().unwrap(),
+            frame_type,
+            payload: Vec::with_capacity(0),
+        };
+        let aad = frame.associated_data();
+        frame.payload = cipher.encrypt(
+            nonce.cipher_nonce(),
+            Payload {
+                msg: message,
+                aad: &aad,
+            },
+        )?;
+        debug_assert_eq!(
+            frame.frame_size,
+            NetFrame::compute_frame_size(&frame.payload)
+        );
+        Ok(frame)
+    }
+
+    /// Decrypt the frame payload, authenticating the frame header if the protocol version
+    /// supports it.
+    fn decrypt(
+        &self,
+        cipher: &Cipher,
+        nonce: &Nonce,
+    ) -> Result<Vec<u8>, chacha20poly1305::aead::Error> {
+        let aad = self.associated_data();
+        cipher.decrypt(
+            nonce.cipher_nonce(),
+            Payload {
+                msg: &self.payload,
+                aad: &aad,
+            },
+        )
+    }
+
+    fn associated_data(&self) -> Vec<u8> {
+        if has_authenticated_header(self.protocol_version) {
+            self.header().to_vec()
+        } else {
+            Vec::with_capacity(0)
+        }
+    }
+
+    fn header(&self) -> [u8; HEADER_SIZE] {
+        let mut header = [0; HEADER_SIZE];
         let frame_type = num_traits::ToPrimitive::to_u32(&self.frame_type)
             .unwrap()
             .to_le_bytes();
-        vector.extend_from_slice(&self.protocol_version.to_le_bytes());
-        vector.extend_from_slice(&self.frame_size.to_le_bytes());
-        vector.extend_from_slice(&frame_type);
+        header[..PROTOCOL_VERSION_SIZE].copy_from_slice(&self.protocol_version.to_le_bytes());
+        header[PROTOCOL_VERSION_SIZE..PROTOCOL_VERSION_SIZE + FRAME_SIZE_SIZE]
+            .copy_from_slice(&self.frame_size.to_le_bytes());
+        header[PROTOCOL_VERSION_SIZE + FRAME_SIZE_SIZE..].copy_from_slice(&frame_type);
+        header
+    }
+
+    /// Export a netframe to vector stream
+    fn to_net(&self) -> Vec<u8> {
+        let mut vector = Vec::with_capacity(self.frame_size as usize);
+        vector.extend_from_slice(&self.header());
         vector.extend_from_slice(&self.payload);
         vector
     }
 
-    /// Read NetFrame from a network stream
+    /// Read NetFrame from a network stream.
+    /// Frames larger than `max_frame_size` are rejected before allocating their payload.
     /// Note: from_net does two read operation per frame (one for header, one for the
     /// payload), this might be inefficient on direct fd since it will trigger
     ///  syscalls on unbuffered readers.
-    fn from_net<T: Read>(reader: &mut T) -> Result<Self, Error> {
-        const HEADER_WIDTH: usize = PROTOCOL_VERSION_SIZE + FRAME_SIZE_SIZE;
-        let mut header_buffer: [u8; HEADER_WIDTH] = [0; HEADER_WIDTH];
+    fn from_net<T: Read>(
+        reader: &mut T,
+        max_frame_size: FrameSizeType,
+    ) -> Result<Self, FrameError> {
+        let mut header_buffer: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
         match reader.read_exact(&mut header_buffer) {
             Ok(_) => (),
             Err(e) => {
                 error!("End of stream while reading header");
-                return Err(e);
+                return Err(e.into());
             }
         }
 
         let protocol_version = read_protocol_version(&header_buffer)?;
-        let frame_size = read_frame_size(&header_buffer)?;
-        let payload_buffer_size = frame_size as usize - HEADER_WIDTH;
+        let frame_size = read_frame_size(&header_buffer, max_frame_size)?;
+        let frame_type = read_frame_type(&header_buffer)?;
+        // frame_size is bounded by max_frame_size and at least HEADER_SIZE
+        let payload_size = usize::try_from(frame_size).map_err(|_| FrameError::Oversized {
+            size: frame_size,
+            max: max_frame_size,
+        })? - HEADER_SIZE;
 
-        trace!("NetFrame payload size: {}", payload_buffer_size);
-        let mut payload_buffer = vec![0; payload_buffer_size];
-        reader.read_exact(&mut payload_buffer)?;
+        trace!("NetFrame payload size: {}", payload_size);
+        let mut payload = vec![0; payload_size];
+        reader
+            .read_exact(&mut payload)
+            .map_err(|e| match e.kind() {
+                ErrorKind::UnexpectedEof => {
+                    error!("End of stream while reading payload");
+                    FrameError::Truncated {
+                        expected: payload_size,
+                    }
+                }
+                _ => FrameError::Io(e),
+            })?;
 
-        let frame_type = read_frame_type(&payload_buffer)?;
-        let payload = payload_buffer.into_iter().skip(FRAME_TYPE_SIZE).collect();
         Ok(NetFrame {
             protocol_version,
             frame_size,
@@ -230,38 +649,119 @@ impl NetFrame {
         })
     }
 
-    /// Close frame should be the last frame sent
-    fn close_frame(payload: Vec<u8>) -> NetFrame {
-        Self {
-            protocol_version: PROTOCOL_VERSION,
-            frame_size: NetFrame::compute_frame_size(&payload),
-            frame_type: NetFrameType::Close,
-            payload,
+    fn open_frame(hello: &handshake::ClientHello) -> NetFrame {
+        Self::new(MIN_PROTOCOL_VERSION, NetFrameType::Open, hello.to_bytes())
+    }
+
+    /// Plaintext error sent before a connection is opened
+    fn handshake_error_frame(status: &Status) -> NetFrame {
+        Self::new(MIN_PROTOCOL_VERSION, NetFrameType::Error, status.to_bytes())
+    }
+
+    fn compute_frame_size(payload: &[u8]) -> FrameSizeType {
+        (HEADER_SIZE + payload.len()).try_into().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const MAX_FRAME_SIZE: FrameSizeType = 64;
+
+    fn header(protocol_version: u32, frame_size: u64, frame_type: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&protocol_version.to_le_bytes());
+        bytes.extend_from_slice(&frame_size.to_le_bytes());
+        bytes.extend_from_slice(&frame_type.to_le_bytes());
+        bytes
+    }
+
+    fn parse(bytes: Vec<u8>) -> Result<NetFrame, FrameError> {
+        NetFrame::from_net(&mut Cursor::new(bytes), MAX_FRAME_SIZE)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let frame = NetFrame::new(
+            PROTOCOL_VERSION,
+            NetFrameType::CopyMessage,
+            b"payload".to_vec(),
+        );
+        let parsed = parse(frame.to_net()).unwrap();
+        assert_eq!(NetFrameType::CopyMessage, parsed.frame_type);
+        assert_eq!(frame.frame_size, parsed.frame_size);
+        assert_eq!(b"payload".to_vec(), parsed.payload);
+    }
+
+    #[test]
+    fn test_empty_payload() {
+        let parsed =
+            parse(NetFrame::new(MIN_PROTOCOL_VERSION, NetFrameType::Open, vec![]).to_net())
+                .unwrap();
+        assert_eq!(NetFrameType::Open, parsed.frame_type);
+        assert!(parsed.payload.is_empty());
+    }
+
+    #[test]
+    fn test_undersized_frame() {
+        for size in [0, 1, HEADER_SIZE as u64 - 1] {
+            let result = parse(header(PROTOCOL_VERSION, size, 0));
+            assert!(matches!(result, Err(FrameError::Undersized(s)) if s == size));
         }
     }
 
-    fn open_frame() -> NetFrame {
-        let payload = Vec::with_capacity(0);
-        Self {
-            protocol_version: PROTOCOL_VERSION,
-            frame_size: NetFrame::compute_frame_size(&payload),
-            frame_type: NetFrameType::Open,
-            payload,
+    #[test]
+    fn test_oversized_frame() {
+        for size in [MAX_FRAME_SIZE + 1, u64::MAX] {
+            let result = parse(header(PROTOCOL_VERSION, size, 0));
+            assert!(matches!(
+                result,
+                Err(FrameError::Oversized { size: s, max: MAX_FRAME_SIZE }) if s == size
+            ));
         }
     }
-    fn nounce_frame(nounce: &Nonce) -> NetFrame {
-        let payload = nounce.value.to_vec();
-        Self {
-            protocol_version: PROTOCOL_VERSION,
-            frame_size: NetFrame::compute_frame_size(&payload),
-            frame_type: NetFrameType::Open,
-            payload,
+
+    #[test]
+    fn test_unknown_frame_type() {
+        let result = parse(header(PROTOCOL_VERSION, HEADER_SIZE as u64, 42));
+        assert!(matches!(result, Err(FrameError::UnknownType(42))));
+    }
+
+    #[test]
+    fn test_supported_versions() {
+        for version in MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION {
+            let parsed = parse(header(version, HEADER_SIZE as u64, 0)).unwrap();
+            assert_eq!(version, parsed.protocol_version);
         }
     }
 
-    fn compute_frame_size(payload: &[u8]) -> FrameSizeType {
-        (PROTOCOL_VERSION_SIZE + FRAME_TYPE_SIZE + FRAME_SIZE_SIZE + payload.len())
-            .try_into()
-            .unwrap()
+    #[test]
+    fn test_unsupported_version() {
+        for version in [MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let result = parse(header(version, HEADER_SIZE as u64, 0));
+            assert!(matches!(result, Err(FrameError::UnsupportedVersion(v)) if v == version));
+        }
+    }
+
+    #[test]
+    fn test_truncated_payload() {
+        let mut bytes = header(PROTOCOL_VERSION, HEADER_SIZE as u64 + 10, 2);
+        bytes.extend_from_slice(b"short");
+        let result = parse(bytes);
+        assert!(matches!(
+            result,
+            Err(FrameError::Truncated { expected: 10 })
+        ));
+    }
+
+    #[test]
+    fn test_truncated_header() {
+        let mut bytes = header(PROTOCOL_VERSION, HEADER_SIZE as u64, 0);
+        bytes.truncate(HEADER_SIZE - 1);
+        let result = parse(bytes);
+        assert!(matches!(result, Err(FrameError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof));
     }
 }
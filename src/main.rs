@@ -1,14 +1,24 @@
 use std::io::Write;
-use std::{io::Read, path::PathBuf, process::exit};
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    process::exit,
+};
 
 use anyhow::anyhow;
 use anyhow::Result;
 use base64::Engine;
-use clipboard::{ClipboardContext, ClipboardProvider};
+use copiepate::backend::{BackendKind, BackendOptions, ClipboardBackend};
+use copiepate::kdf::KdfParams;
+use copiepate::pairing::{PairingCode, PairingServer};
+use copiepate::server::{ClientKey, Keyring};
+use copiepate::ssh::{AuthorizedKeys, KnownHosts, SshIdentity};
+use copiepate::transport::Transport;
 use etcetera::base_strategy::{self, BaseStrategy};
 use serde_derive::{Deserialize, Serialize};
 use simple_logger::SimpleLogger;
 use structopt::StructOpt;
+use zeroize::{Zeroize, Zeroizing};
 
 // TODO(chore): move opts and opts parsing to a proper module
 // TODO(test): add code coverage
@@ -16,11 +26,17 @@ use structopt::StructOpt;
 // TODO(feat): json output
 // TODO(feat): client/server specific configuration
 // TODO(feat): auto-configuration on startup
-// TODO(feat): allow reverse event sending (from server to client)
 
 const DEFAULT_ADDRESS: &str = "127.0.0.1";
 const DEFAULT_PORT: &str = "2323";
 
+/// Exit code used when the server rejected the message.
+const EXIT_REJECTED: i32 = 2;
+/// Exit code used when the server did not answer in time.
+const EXIT_TIMEOUT: i32 = 3;
+/// Exit code used when the server is interrupted again while shutting down.
+const EXIT_INTERRUPTED: i32 = 130;
+
 const DEFAULT_CONFIG_DIR: &str = "copiepate";
 const DEFAULT_CONFIG_FILENAME: &str = "config.toml";
 
@@ -35,8 +51,13 @@ pub const DEFAULT_INSECURE_KEY: &[u8; copiepate::KEY_SIZE] = b"_WARNING_UNSECURE
     version = "0.2.0"
 )]
 struct Opt {
+    #[structopt(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
+
     #[structopt(
         long = "config",
+        global = true,
         help = "Configuration file. Default configuration location depends on OS.
 ~/.config/copiepate/config.toml for XDG-compatible OSes.",
         parse(from_os_str)
@@ -47,25 +68,43 @@ struct Opt {
     #[structopt(
         short = "s",
         long = "server",
+        global = true,
         help = "Start copiepate server that listen for copy events."
     )]
     server_mode: bool,
 
+    #[structopt(
+        long = "stdio",
+        alias = "stdio-server",
+        help = "Start copiepate server for a single session over stdin and stdout, for instance
+when spawned by ssh or inetd."
+    )]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    stdio: bool,
+
     #[structopt(
         short = "a",
         long = "address",
-        help = "Server ip address in client mode, or server bind address in server mode."
+        global = true,
+        help = "Server ip address in client mode, or server bind address in server mode.
+Unix domain sockets are set with unix:/path/to/socket, the port is then ignored."
     )]
     #[serde(skip_serializing_if = "Option::is_none")]
     address: Option<String>,
 
-    #[structopt(short = "p", long = "port", help = "Server listen port.")]
+    #[structopt(
+        short = "p",
+        long = "port",
+        global = true,
+        help = "Server listen port."
+    )]
     #[serde(skip_serializing_if = "Option::is_none")]
     port: Option<String>,
 
     #[structopt(
         short = "v",
         long = "verbosity",
+        global = true,
         help = "Sets the level of verbosity. Copiepate will log service messages on stderr.
 Increase log level to have more information.",
         parse(from_occurrences)
@@ -83,10 +122,132 @@ WARNING: anybody might be able to read the messages."
     #[structopt(
         long = "--secret",
         help = "32 bits base64 encoded secret to use to contact the server.
-Must be the same between client and server. If `--insecure` is set, will be discarded"
+Must be the same between client and server. If `--insecure` is set, will be discarded.
+WARNING: other users can read it in the process list, prefer `--secret-file`, `--secret-env`
+or `--secret-command`."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<Redacted>,
+
+    #[structopt(
+        long = "--secret-file",
+        help = "File containing the base64 secret, instead of `--secret`. Files readable by every user
+are refused.",
+        parse(from_os_str)
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "--secret-env",
+        help = "Environment variable containing the base64 secret, instead of `--secret`."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret_env: Option<String>,
+
+    #[structopt(
+        long = "--secret-command",
+        help = "Shell command printing the base64 secret, instead of `--secret`, for instance
+\"pass show copiepate\"."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret_command: Option<String>,
+
+    #[structopt(
+        long = "--passphrase",
+        help = "Passphrase the secret is derived from with Argon2id, instead of `--secret`. Requires
+`--kdf-salt`, the passphrase and the KDF settings must be the same between client and server.
+WARNING: other users can read it in the process list, prefer setting it in the configuration
+file."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    passphrase: Option<Redacted>,
+
+    #[structopt(
+        long = "--kdf-salt",
+        help = "Base64 salt the secret is derived from the passphrase with, 8 to 255 bytes long. Generate
+one with `openssl rand -base64 16`."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kdf_salt: Option<String>,
+
+    #[structopt(
+        long = "--kdf-memory-cost",
+        help = "Memory in KiB used to derive the secret from the passphrase. Default: 19456"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kdf_memory_cost: Option<u32>,
+
+    #[structopt(
+        long = "--kdf-time-cost",
+        help = "Number of passes used to derive the secret from the passphrase. Default: 2"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kdf_time_cost: Option<u32>,
+
+    #[structopt(
+        long = "--kdf-parallelism",
+        help = "Number of lanes used to derive the secret from the passphrase. Default: 1"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kdf_parallelism: Option<u32>,
+
+    #[structopt(
+        long = "--key-id",
+        help = "[Client only] ID of the secret in the server keyring. Without a key ID the server uses
+its own secret."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+
+    #[structopt(
+        long = "--ssh-key",
+        help = "[Client only] Authenticate with an SSH ed25519 key instead of a secret: path of an OpenSSH
+private key file, or `agent` for the first ed25519 key of ssh-agent. Encrypted keys are used
+through ssh-agent. Requires `--known-hosts`."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssh_key: Option<String>,
+
+    #[structopt(
+        long = "--known-hosts",
+        help = "[Client only] known_hosts file of the SSH ed25519 host keys servers may sign the handshake
+with, such as `127.0.0.1:2323 ssh-ed25519 AAAA...`. Used with `--ssh-key`.",
+        parse(from_os_str)
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    known_hosts: Option<PathBuf>,
+
+    #[structopt(
+        long = "--authorized-keys",
+        help = "[Server only] authorized_keys file of the SSH ed25519 keys clients may authenticate with.
+Clients authenticating with a secret are then refused, unless `--allow-secret` is set. Requires
+`--host-key`.",
+        parse(from_os_str)
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    authorized_keys: Option<PathBuf>,
+
+    #[structopt(
+        long = "--host-key",
+        help = "[Server only] SSH ed25519 host key the server signs the handshake with for clients
+authenticating with an SSH key: path of an OpenSSH private key file, or `agent`."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host_key: Option<String>,
+
+    #[structopt(
+        long = "--allow-secret",
+        help = "[Server only] Also accept clients authenticating with a secret when `--authorized-keys`
+is set."
     )]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    allow_secret: bool,
+
+    /// [Server only] Keyring of per-client secrets, only set in the configuration file
+    #[structopt(skip)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    secret: Option<String>,
+    keys: Option<Vec<KeyConfig>>,
 
     #[structopt(
         long = "--tee",
@@ -94,6 +255,188 @@ Must be the same between client and server. If `--insecure` is set, will be disc
     )]
     tee: bool,
 
+    #[structopt(
+        long = "--paste",
+        help = "[Client only] Print the content of the server clipboard to stdout instead of sending stdin."
+    )]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    paste: bool,
+
+    #[structopt(
+        long = "--allow-paste",
+        help = "[Server only] Allow clients to read the server clipboard using `--paste`."
+    )]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    allow_paste: bool,
+
+    #[structopt(
+        long = "--max-size",
+        help = "Maximum size in bytes of a message. Larger messages are refused."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_size: Option<usize>,
+
+    #[structopt(
+        long = "--min-protocol-version",
+        help = "Oldest protocol version accepted from the other peer. Older peers are refused.
+Default: 1. Set it to 5 to prevent an attacker in the middle from downgrading connections,
+at the cost of refusing copiepate 0.2 and older."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_protocol_version: Option<u32>,
+
+    #[structopt(
+        long = "--max-connections",
+        help = "[Server only] Maximum number of connections handled at the same time. Other connections are refused."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_connections: Option<usize>,
+
+    #[structopt(
+        long = "--connect-timeout",
+        help = "[Client only] Seconds to wait for the connection to the server. 0 waits for ever."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connect_timeout: Option<u64>,
+
+    #[structopt(
+        long = "--handshake-timeout",
+        help = "Seconds to wait for the connection to be opened and authenticated. 0 waits for ever."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    handshake_timeout: Option<u64>,
+
+    #[structopt(
+        long = "--read-timeout",
+        help = "Seconds to wait for each read and write on an opened connection. 0 waits for ever."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_timeout: Option<u64>,
+
+    #[structopt(
+        long = "--idle-timeout",
+        help = "[Server only] Seconds to wait for the next message of a client. 0 waits for ever."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    idle_timeout: Option<u64>,
+
+    #[structopt(
+        long = "--type",
+        help = "[Client only] MIME content type of the message read from stdin, such as `image/png`.
+Default: text/plain"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+
+    #[structopt(
+        long = "--charset",
+        help = "[Client only] Charset of the message read from stdin, such as `utf-8` or `iso-8859-1`.
+By default the message is sent as raw bytes, and decoded as UTF-8 by the server."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    charset: Option<String>,
+
+    #[structopt(
+        long = "--backend",
+        help = "[Server only] Clipboard backend: auto, clipboard, wayland, xclip, xsel, tmux, command, osc52,
+file, directory or stdout.
+Default: auto, detected from the WAYLAND_DISPLAY, DISPLAY and TMUX environment variables."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backend: Option<String>,
+
+    #[structopt(
+        long = "--copy-command",
+        help = "[Server only] Shell command writing its stdin to the clipboard, used by the command backend."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    copy_command: Option<String>,
+
+    #[structopt(
+        long = "--paste-command",
+        help = "[Server only] Shell command printing the clipboard, used by the command backend."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    paste_command: Option<String>,
+
+    #[structopt(
+        long = "--primary-command",
+        help = "[Server only] Shell command writing its stdin to the primary selection, used by the command
+backend. Without it the command backend has no primary selection."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    primary_command: Option<String>,
+
+    #[structopt(
+        long = "--osc52-tty",
+        help = "[Server only] Terminal the osc52 backend writes to. Default: stdout",
+        parse(from_os_str)
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    osc52_tty: Option<PathBuf>,
+
+    #[structopt(
+        long = "--osc52-passthrough",
+        help = "[Server only] Wrap OSC 52 sequences for a terminal multiplexer: auto, none, tmux or screen.
+Default: auto, detected from the TMUX and TERM environment variables."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    osc52_passthrough: Option<String>,
+
+    #[structopt(
+        long = "--osc52-max-size",
+        help = "[Server only] Maximum size in bytes of an OSC 52 sequence. Larger messages are refused."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    osc52_max_size: Option<usize>,
+
+    #[structopt(
+        long = "--output-path",
+        help = "[Server only] File or directory the file and directory backends write messages to.",
+        parse(from_os_str)
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_path: Option<PathBuf>,
+
+    #[structopt(
+        long = "--once",
+        help = "[Server only] Exit after the first message saved to the clipboard."
+    )]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    once: bool,
+
+    #[structopt(
+        long = "--transport",
+        help = "[Client only] Speak to the server through the stdin and stdout of a command instead
+of connecting to its address, for instance \"command:ssh laptop copiepate --stdio\"."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transport: Option<String>,
+
+    #[structopt(
+        long = "--selection",
+        help = "[Client only] Selection the message is written to: clipboard, primary or both.
+Default: the server default selection."
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    selection: Option<String>,
+
+    #[structopt(
+        long = "--default-selection",
+        help = "[Server only] Selection of messages that don't name one: clipboard, primary or both.
+Default: clipboard"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_selection: Option<String>,
+
+    #[structopt(
+        long = "--allowed-selection",
+        help = "[Server only] Selection clients may write to, can be repeated.
+Default: clipboard and primary"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed_selections: Option<Vec<String>>,
+
     #[structopt(
         long = "--exec",
         help = "[Server only] Shell to command to execute when receiving a new message.
@@ -103,36 +446,508 @@ Received message will be passed as stdin to the invoked command."
     exec: Option<String>,
 }
 
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Pair with another machine using a one-time code, and save the new secret in the
+    /// configuration file. Run `copiepate pair --server` on the server, then `copiepate
+    /// pair` on the client with the code displayed by the server.
+    Pair {
+        #[structopt(
+            help = "[Client only] Code displayed by the server. Asked on the terminal if missing."
+        )]
+        code: Option<String>,
+    },
+}
+
+/// Entry of the server keyring.
+#[derive(Debug, Deserialize, Serialize)]
+struct KeyConfig {
+    id: String,
+    name: String,
+    secret: Redacted,
+    /// Date the key is valid from, as `2024-01-31` or `2024-01-31T12:00:00Z`
+    not_before: Option<String>,
+    /// Date the key is valid until
+    not_after: Option<String>,
+}
+
+/// Secret or passphrase, left out of debug output such as the logged configuration, and
+/// wiped from memory once dropped.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(transparent)]
+struct Redacted(String);
+
+impl Redacted {
+    fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::str::FromStr for Redacted {
+    type Err = std::convert::Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(Self(String::from(value)))
+    }
+}
+
+impl std::fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl Drop for Redacted {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// Server address and port, or Unix domain socket address (`unix:/path`) as is.
 fn get_address(opt: &Opt) -> Result<String> {
+    let address = opt
+        .address
+        .as_ref()
+        .ok_or_else(|| anyhow!("Missing address"))?;
+    if copiepate::transport::unix_socket_path(address).is_some() {
+        return Ok(address.clone());
+    }
     Ok(format!(
         "{}:{}",
-        opt.address
-            .as_ref()
-            .ok_or_else(|| anyhow!("Missing address"))?,
+        address,
         opt.port.as_ref().ok_or_else(|| anyhow!("Missing port"))?,
     ))
 }
 
-fn get_key(opt: &Opt) -> Result<Vec<u8>> {
-    let secret = if opt.insecure {
-        Ok(DEFAULT_INSECURE_KEY.to_vec())
-    } else {
-        let decoder = base64::engine::general_purpose::STANDARD;
-        match &opt.secret {
-            None => Err(anyhow!("No secret provided.")),
-            Some(k) => Ok(decoder.decode(k.clone())?),
+fn get_transport(opt: &Opt) -> Result<Transport> {
+    match &opt.transport {
+        None => Ok(Transport::Socket),
+        Some(transport) => transport.parse().map_err(|e: String| anyhow!(e)),
+    }
+}
+
+/// Whether the clipboard backend writes messages to stdout.
+fn writes_to_stdout(opt: &Opt) -> bool {
+    match opt.backend.as_deref() {
+        Some("stdout") => true,
+        Some("osc52") => opt.osc52_tty.is_none(),
+        _ => false,
+    }
+}
+
+fn parse_selection(name: &str) -> Result<copiepate::Selection> {
+    name.parse().map_err(|e: String| anyhow!(e))
+}
+
+/// Selection of the client message, default and allowed selections of the server.
+fn get_selections(
+    opt: &Opt,
+) -> Result<(
+    Option<copiepate::Selection>,
+    copiepate::Selection,
+    Vec<copiepate::Selection>,
+)> {
+    let selection = opt.selection.as_deref().map(parse_selection).transpose()?;
+    let default_selection = opt
+        .default_selection
+        .as_deref()
+        .map(parse_selection)
+        .transpose()?
+        .unwrap_or(copiepate::Selection::Clipboard);
+    let allowed_selections = match &opt.allowed_selections {
+        Some(names) => names
+            .iter()
+            .map(|name| parse_selection(name))
+            .collect::<Result<_>>()?,
+        None => vec![
+            copiepate::Selection::Clipboard,
+            copiepate::Selection::Primary,
+        ],
+    };
+    Ok((selection, default_selection, allowed_selections))
+}
+
+/// Timeouts in seconds, a zero timeout waits for ever.
+fn get_timeouts(opt: &Opt) -> copiepate::Timeouts {
+    let timeout = |seconds: Option<u64>, default| match seconds {
+        Some(0) => None,
+        Some(seconds) => Some(std::time::Duration::from_secs(seconds)),
+        None => default,
+    };
+    let defaults = copiepate::Timeouts::default();
+    copiepate::Timeouts {
+        connect: timeout(opt.connect_timeout, defaults.connect),
+        handshake: timeout(opt.handshake_timeout, defaults.handshake),
+        read: timeout(opt.read_timeout, defaults.read),
+        idle: timeout(opt.idle_timeout, defaults.idle),
+    }
+}
+
+/// Secret, or the key derived from the passphrase, wiped from memory once dropped.
+fn get_key(opt: &Opt) -> Result<Zeroizing<Vec<u8>>> {
+    if opt.insecure {
+        return Ok(Zeroizing::new(DEFAULT_INSECURE_KEY.to_vec()));
+    }
+    match (&opt.passphrase, get_kdf(opt)?) {
+        (Some(_), _) if has_secret(opt) => Err(anyhow!(
+            "Only one of passphrase, secret, secret_file, secret_env and secret_command can be set."
+        )),
+        (Some(passphrase), Some(kdf)) => {
+            let key = kdf.derive_key(passphrase.expose().as_bytes())?;
+            Ok(Zeroizing::new(key.to_vec()))
         }
+        _ => decode_secret(&read_secret(opt)?),
+    }
+}
+
+/// Server secret, optional when the server has a keyring or authorized keys.
+fn get_server_key(opt: &Opt) -> Result<Option<Zeroizing<Vec<u8>>>> {
+    let has_keyring = opt.keys.as_ref().is_some_and(|keys| !keys.is_empty());
+    let has_authorized_keys = opt.authorized_keys.is_some();
+    if (has_keyring || has_authorized_keys)
+        && !opt.insecure
+        && !has_secret(opt)
+        && opt.passphrase.is_none()
+    {
+        return Ok(None);
+    }
+    get_key(opt).map(Some)
+}
+
+/// Argon2id parameters the key is derived from the passphrase with, `None` without
+/// passphrase.
+fn get_kdf(opt: &Opt) -> Result<Option<KdfParams>> {
+    if opt.passphrase.is_none() || opt.insecure {
+        return Ok(None);
+    }
+    let salt = opt.kdf_salt.as_ref().ok_or_else(|| {
+        anyhow!(
+            "A kdf_salt must be set with the passphrase, the same on the client and the server.
+Generate one with: openssl rand -base64 16"
+        )
+    })?;
+    let salt = base64::engine::general_purpose::STANDARD
+        .decode(salt.trim())
+        .map_err(|e| anyhow!("Invalid kdf_salt: {}", e))?;
+    let defaults = KdfParams::new(&salt);
+    let kdf = KdfParams {
+        memory_cost: opt.kdf_memory_cost.unwrap_or(defaults.memory_cost),
+        time_cost: opt.kdf_time_cost.unwrap_or(defaults.time_cost),
+        parallelism: opt.kdf_parallelism.unwrap_or(defaults.parallelism),
+        ..defaults
     };
+    kdf.validate()?;
+    Ok(Some(kdf))
+}
+
+/// Whether the secret is set, or read from a file, an environment variable or a command.
+fn has_secret(opt: &Opt) -> bool {
+    opt.secret.is_some()
+        || opt.secret_file.is_some()
+        || opt.secret_env.is_some()
+        || opt.secret_command.is_some()
+}
+
+/// Base64 secret, from the only secret source set.
+fn read_secret(opt: &Opt) -> Result<Zeroizing<String>> {
+    let sources = [
+        opt.secret.is_some(),
+        opt.secret_file.is_some(),
+        opt.secret_env.is_some(),
+        opt.secret_command.is_some(),
+    ];
+    if sources.iter().filter(|set| **set).count() > 1 {
+        return Err(anyhow!(
+            "Only one of secret, secret_file, secret_env and secret_command can be set."
+        ));
+    }
+
+    if let Some(secret) = &opt.secret {
+        Ok(Zeroizing::new(String::from(secret.expose())))
+    } else if let Some(path) = &opt.secret_file {
+        read_secret_file(path)
+    } else if let Some(name) = &opt.secret_env {
+        std::env::var(name)
+            .map(Zeroizing::new)
+            .map_err(|e| anyhow!("Failed to read environment variable {}: {}", name, e))
+    } else if let Some(command) = &opt.secret_command {
+        run_secret_command(command)
+    } else {
+        Err(anyhow!("No secret provided."))
+    }
+}
 
-    secret.and_then(|s| match s.len() {
-        copiepate::KEY_SIZE => Ok(s),
+/// Read a secret file, refusing files readable by every user.
+fn read_secret_file(path: &Path) -> Result<Zeroizing<String>> {
+    let error = |e: std::io::Error| anyhow!("Failed to read secret file {:?}: {}", path, e);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path).map_err(error)?.permissions().mode();
+        if mode & 0o004 != 0 {
+            return Err(anyhow!(
+                "Secret file {:?} is readable by every user, restrict it with `chmod 600`.",
+                path
+            ));
+        }
+    }
+    std::fs::read_to_string(path)
+        .map(Zeroizing::new)
+        .map_err(error)
+}
+
+/// Run a shell command printing the secret on its stdout, such as `pass show copiepate`.
+/// Its stdin is closed, it may carry a `--stdio` session.
+fn run_secret_command(command: &str) -> Result<Zeroizing<String>> {
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .map_err(|e| anyhow!("Failed to run secret command: {}", e))?;
+    let mut stdout = Zeroizing::new(output.stdout);
+    if !output.status.success() {
+        return Err(anyhow!("Secret command failed: {}", output.status));
+    }
+    String::from_utf8(std::mem::take(&mut *stdout))
+        .map(Zeroizing::new)
+        .map_err(|e| {
+            e.into_bytes().zeroize();
+            anyhow!("Secret command printed an invalid secret")
+        })
+}
+
+/// Decode a base64 secret, surrounding whitespace is ignored.
+fn decode_secret(secret: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let decoder = base64::engine::general_purpose::STANDARD;
+    let secret = Zeroizing::new(decoder.decode(secret.trim())?);
+    match secret.len() {
+        copiepate::KEY_SIZE => Ok(secret),
         _ => Err(anyhow!(
             "Decoded secret must have a length of {} bytes.",
             copiepate::KEY_SIZE
         )),
+    }
+}
+
+/// Parse a keyring date. A date without time starts at midnight, or lasts until the end of
+/// the day with `end_of_day`, so that a key expiring on a date is valid that whole day.
+fn parse_date(date: &str, end_of_day: bool) -> Result<std::time::SystemTime> {
+    let error = |e| anyhow!("Invalid date: {}", e);
+    if date.len() != "2024-01-31".len() {
+        return humantime::parse_rfc3339_weak(date).map_err(error);
+    }
+    let midnight = humantime::parse_rfc3339_weak(&format!("{date}T00:00:00")).map_err(error)?;
+    Ok(if end_of_day {
+        midnight + std::time::Duration::from_secs(24 * 60 * 60) - std::time::Duration::from_nanos(1)
+    } else {
+        midnight
+    })
+}
+
+fn get_keyring(opt: &Opt) -> Result<Keyring> {
+    let mut keyring = Keyring::default();
+    for entry in opt.keys.iter().flatten() {
+        let error = |e: anyhow::Error| anyhow!("Key '{}': {}", entry.id, e);
+        let mut key = ClientKey::new(
+            &entry.id,
+            &entry.name,
+            &decode_secret(entry.secret.expose()).map_err(error)?,
+        );
+        key.not_before = entry
+            .not_before
+            .as_deref()
+            .map(|date| parse_date(date, false))
+            .transpose()
+            .map_err(error)?;
+        key.not_after = entry
+            .not_after
+            .as_deref()
+            .map(|date| parse_date(date, true))
+            .transpose()
+            .map_err(error)?;
+        keyring.add(key)?;
+    }
+    Ok(keyring)
+}
+
+fn get_ssh_identity(ssh_key: &str) -> Result<SshIdentity> {
+    Ok(match ssh_key {
+        "agent" => SshIdentity::from_agent(None)?,
+        path => SshIdentity::from_file(std::path::Path::new(path))?,
+    })
+}
+
+/// Host keys the client trusts when authenticating with an SSH key.
+fn get_known_hosts(opt: &Opt) -> Result<KnownHosts> {
+    let path = opt.known_hosts.as_ref().ok_or_else(|| {
+        anyhow!("A known_hosts file with the host key of the server must be set with ssh_key.")
+    })?;
+    KnownHosts::read_file(path).map_err(|e| anyhow!("Failed to load {:?}: {}", path, e))
+}
+
+/// Client authenticating with its SSH key, or with the secret.
+fn create_client<'a>(opt: &Opt, address: &'a str) -> copiepate::client::Client<'a> {
+    let mut client = match &opt.ssh_key {
+        Some(ssh_key) => {
+            let known_hosts = get_known_hosts(opt).unwrap_or_else(|e| {
+                log::error!("{}", e);
+                exit(1);
+            });
+            match get_ssh_identity(ssh_key) {
+                Ok(identity) => {
+                    copiepate::client::Client::with_ssh_identity(address, identity, known_hosts)
+                }
+                Err(e) => {
+                    log::error!("Failed to load SSH key {}: {}", ssh_key, e);
+                    exit(1);
+                }
+            }
+        }
+        None => {
+            let key = get_key(opt).unwrap_or_else(|e| exit_on_key_error(e));
+            let mut client = copiepate::client::Client::new(address, &key);
+            client.kdf = get_kdf(opt).unwrap_or_else(|e| exit_on_key_error(e));
+            client
+        }
+    };
+    client.key_id = opt.key_id.clone();
+    client
+}
+
+/// Wait for a client to pair with a new one-time code, returns the shared secret.
+fn pair_server(
+    address: &str,
+    timeouts: copiepate::Timeouts,
+) -> Result<Zeroizing<[u8; copiepate::KEY_SIZE]>> {
+    let mut server = PairingServer::new(address, PairingCode::generate());
+    server.timeout = timeouts.handshake;
+    server.bind()?;
+    println!("Pairing code: {}", server.code());
+    Ok(Zeroizing::new(server.accept()?))
+}
+
+/// Pair with the server with the code passed as argument, or typed on the terminal.
+fn pair_client(
+    client: &copiepate::client::Client,
+    code: Option<&str>,
+) -> Result<Zeroizing<[u8; copiepate::KEY_SIZE]>> {
+    let code = match code {
+        Some(code) => String::from(code),
+        None => {
+            eprint!("Pairing code: ");
+            std::io::stderr().flush()?;
+            let mut code = String::new();
+            std::io::stdin().read_line(&mut code)?;
+            code
+        }
+    };
+    Ok(Zeroizing::new(client.pair(&code.parse()?)?))
+}
+
+/// File the paired secret is saved in: the secret file if one is set, or the configuration
+/// file. Secrets read from an environment variable or a command, or derived from a
+/// passphrase, can't be replaced.
+fn get_pairing_file(opt: &Opt) -> Result<PathBuf> {
+    if opt.secret_env.is_some() || opt.secret_command.is_some() || opt.passphrase.is_some() {
+        return Err(anyhow!(
+            "Can't save the paired secret: the secret is read from secret_env or secret_command,
+or derived from a passphrase. Remove them from the configuration, or use secret_file instead."
+        ));
+    }
+    Ok(match &opt.secret_file {
+        Some(path) => path.clone(),
+        None => opt
+            .config_file
+            .clone()
+            .expect("Configuration file has a default value"),
     })
 }
 
+/// Save the secret in the secret file `path`, or in the configuration file `path`,
+/// replacing its previous secret. The file is then only readable by its owner.
+fn save_secret(path: &Path, secret: &[u8], is_secret_file: bool) -> Result<()> {
+    let secret = Zeroizing::new(base64::engine::general_purpose::STANDARD.encode(secret));
+    let content = if is_secret_file {
+        Zeroizing::new(format!("{}\n", *secret))
+    } else {
+        let config = match std::fs::read_to_string(path) {
+            Ok(config) => config,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Zeroizing::new(set_secret(&config, &secret))
+    };
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // The mode only applies to new files, existing files keep theirs otherwise
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+/// Replace the top-level `secret` entry of a TOML configuration, or add it before the
+/// first table.
+fn set_secret(config: &str, secret: &str) -> String {
+    let entry = format!("secret = \"{secret}\"");
+    let mut lines = Vec::new();
+    let mut in_table = false;
+    let mut replaced = false;
+    for line in config.lines() {
+        let trimmed = line.trim_start();
+        in_table |= trimmed.starts_with('[');
+        let is_secret = trimmed
+            .strip_prefix("secret")
+            .is_some_and(|rest| rest.trim_start().starts_with('='));
+        if is_secret && !in_table && !replaced {
+            lines.push(entry.as_str());
+            replaced = true;
+        } else {
+            lines.push(line);
+        }
+    }
+    if !replaced {
+        lines.insert(0, &entry);
+    }
+    lines.join("\n") + "\n"
+}
+
+fn exit_on_key_error(error: anyhow::Error) -> ! {
+    log::error!(
+        "Failed to load secret.
+
+You must specify a valid Base64 secret, either in the configuration file or by passing the --secret option,
+or read it from a file, an environment variable or a command with the --secret-file, --secret-env or
+--secret-command options, or derive it from a passphrase with the --passphrase and --kdf-salt options.
+More information: https://github.com/dimtion/copiepate#setup-and-installation
+
+Error: {} ", error);
+    exit(1);
+}
+
+fn exit_on_kdf_mismatch(error: copiepate::client::ClientError) -> ! {
+    log::error!(
+        "{}
+
+The client and the server derive their secret from a passphrase with different settings.
+The passphrase, kdf_salt, kdf_memory_cost, kdf_time_cost and kdf_parallelism must be the same
+on both ends.",
+        error
+    );
+    exit(EXIT_REJECTED);
+}
+
 fn get_log_level(verbosity: u64) -> log::LevelFilter {
     match verbosity {
         0 => log::LevelFilter::Info,
@@ -143,12 +958,10 @@ fn get_log_level(verbosity: u64) -> log::LevelFilter {
 
 fn load_config(opt: &Opt) -> Result<Opt> {
     match &opt.config_file {
-        None => (),
-        Some(filename) => {
-            if !filename.exists() {
-                return Err(anyhow!("Configuration file {:?} does not exist.", filename));
-            }
+        Some(filename) if !filename.exists() => {
+            return Err(anyhow!("Configuration file {:?} does not exist.", filename));
         }
+        _ => (),
     }
 
     let config_filename = opt.config_file.clone().unwrap_or({
@@ -193,6 +1006,27 @@ fn create_logger(opt: &Opt) {
     logger.init().unwrap();
 }
 
+fn load_backend(opt: &Opt) -> Result<Box<dyn ClipboardBackend>> {
+    let kind = match &opt.backend {
+        Some(name) => name.parse()?,
+        None => BackendKind::Auto,
+    };
+    let options = BackendOptions {
+        copy_command: opt.copy_command.clone(),
+        paste_command: opt.paste_command.clone(),
+        primary_command: opt.primary_command.clone(),
+        osc52_tty: opt.osc52_tty.clone(),
+        osc52_passthrough: opt
+            .osc52_passthrough
+            .as_deref()
+            .map(str::parse)
+            .transpose()?,
+        osc52_max_size: opt.osc52_max_size,
+        output_path: opt.output_path.clone(),
+    };
+    Ok(kind.build(&options)?)
+}
+
 fn tee(message: &[u8]) -> Result<()> {
     let mut stdout = std::io::stdout();
     stdout.write_all(message)?;
@@ -218,43 +1052,191 @@ Error: {}",
     log::trace!("Configuration: {:#?}", &config);
 
     let address = get_address(&config).expect("Failed to load server address");
-    let key = match get_key(&config) {
-        Ok(k) => k,
-        Err(e) => {
-            log::error!(
-                "Failed to load secret.
 
-You must specify a valid Base64 secret, either in the configuration file or by passing the --secret option.
-More information: https://github.com/dimtion/copiepate#setup-and-installation
+    let max_payload_size = config
+        .max_size
+        .unwrap_or(copiepate::DEFAULT_MAX_PAYLOAD_SIZE);
+    let max_frame_size = (max_payload_size as u64).saturating_add(copiepate::FRAME_OVERHEAD);
+    let min_protocol_version = config
+        .min_protocol_version
+        .unwrap_or(copiepate::MIN_PROTOCOL_VERSION);
+    let timeouts = get_timeouts(&config);
+    let (selection, default_selection, allowed_selections) = match get_selections(&config) {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("Invalid selection: {}", e);
+            exit(1);
+        }
+    };
 
-Error: {} ", e);
+    let transport = match get_transport(&config) {
+        Ok(t) => t,
+        Err(e) => {
+            log::error!("Invalid transport: {}", e);
             exit(1);
         }
     };
 
-    if config.server_mode {
-        let mut clipboard_ctx =
-            ClipboardProvider::new().expect("Failed to load clipboard provider");
-        let mut server = copiepate::server::ServerBuilder::<ClipboardContext>::default()
+    if let Some(Command::Pair { code }) = &opt.command {
+        let secret_file = match get_pairing_file(&config) {
+            Ok(path) => path,
+            Err(e) => {
+                log::error!("Failed to pair: {}", e);
+                exit(1);
+            }
+        };
+        let secret = if config.server_mode {
+            pair_server(&address, timeouts)
+        } else {
+            let mut client = copiepate::client::Client::new(&address, &[0; copiepate::KEY_SIZE]);
+            client.timeouts = timeouts;
+            client.transport = transport;
+            pair_client(&client, code.as_deref())
+        };
+        match secret
+            .and_then(|secret| save_secret(&secret_file, &secret[..], config.secret_file.is_some()))
+        {
+            Ok(()) => log::info!("Paired, new secret saved in {:?}", secret_file),
+            Err(e) => {
+                log::error!("Failed to pair: {}", e);
+                exit(1);
+            }
+        }
+    } else if config.server_mode || config.stdio {
+        if config.stdio && writes_to_stdout(&config) {
+            log::error!(
+                "The clipboard backend can't write to stdout, it carries the --stdio session"
+            );
+            exit(1);
+        }
+        let key = get_server_key(&config).unwrap_or_else(|e| exit_on_key_error(e));
+        let kdf = get_kdf(&config).unwrap_or_else(|e| exit_on_key_error(e));
+        let keyring = match get_keyring(&config) {
+            Ok(keyring) => keyring,
+            Err(e) => {
+                log::error!("Invalid keyring: {}", e);
+                exit(1);
+            }
+        };
+        let authorized_keys = match &config.authorized_keys {
+            None => AuthorizedKeys::default(),
+            Some(path) => match AuthorizedKeys::read_file(path) {
+                Ok(keys) => keys,
+                Err(e) => {
+                    log::error!("Failed to load authorized keys {:?}: {}", path, e);
+                    exit(1);
+                }
+            },
+        };
+        let host_key = match config.host_key.as_deref().map(get_ssh_identity).transpose() {
+            Ok(host_key) => host_key,
+            Err(e) => {
+                log::error!("Failed to load host key: {}", e);
+                exit(1);
+            }
+        };
+        let clipboard_ctx = match load_backend(&config) {
+            Ok(backend) => backend,
+            Err(e) => {
+                log::error!("Failed to load clipboard backend: {}", e);
+                exit(1);
+            }
+        };
+        let mut builder = copiepate::server::ServerBuilder::<Box<dyn ClipboardBackend>>::default();
+        if let Some(key) = key {
+            builder = builder.key(&key);
+        }
+        let mut server = builder
             .address(&address)
-            .clipboard_ctx(&mut clipboard_ctx)
-            .key(&key)
+            .clipboard_ctx(clipboard_ctx)
+            .kdf(kdf)
+            .keyring(keyring)
+            .authorized_keys(authorized_keys)
+            .host_key(host_key)
+            .allow_secret(config.allow_secret)
             .exec_command(config.exec)
+            .allow_fetch(config.allow_paste)
+            .max_payload_size(max_payload_size)
+            .max_frame_size(max_frame_size)
+            .min_protocol_version(min_protocol_version)
+            .max_connections(
+                config
+                    .max_connections
+                    .unwrap_or(copiepate::DEFAULT_MAX_CONNECTIONS),
+            )
+            .timeouts(timeouts)
+            .once(config.once)
+            .default_selection(default_selection)
+            .allowed_selections(allowed_selections)
             .build()
             .expect("Failed setting up copiepate server");
-        match server.start() {
-            Ok(_) => (),
+
+        let result = if config.stdio {
+            server.serve_stdio()
+        } else {
+            // Stop accepting connections on SIGINT or SIGTERM, a second signal exits right
+            // away
+            let handle = server.handle();
+            if let Err(e) = ctrlc::set_handler(move || {
+                if handle.is_shutdown() {
+                    exit(EXIT_INTERRUPTED);
+                }
+                handle.shutdown();
+            }) {
+                log::warn!("Failed to set signal handler: {}", e);
+            }
+            server.start()
+        };
+        match result {
+            Ok(Some(_)) => log::info!("Message received, stopping server"),
+            Ok(None) => (),
             Err(e) => {
                 log::error!("Failed to start server: {}", e);
                 exit(1);
             }
         }
+    } else if config.paste {
+        let mut client = create_client(&config, &address);
+        client.max_payload_size = max_payload_size;
+        client.max_frame_size = max_frame_size;
+        client.min_protocol_version = min_protocol_version;
+        client.timeouts = timeouts;
+        client.transport = transport.clone();
+        match client.fetch() {
+            Ok(message) => tee(&message.content).expect("Failed to write to stdout"),
+            Err(
+                e @ copiepate::client::ClientError::Rejected {
+                    code: copiepate::StatusCode::KdfMismatch,
+                    ..
+                },
+            ) => exit_on_kdf_mismatch(e),
+            Err(
+                e @ (copiepate::client::ClientError::Rejected { .. }
+                | copiepate::client::ClientError::Authentication(_)),
+            ) => {
+                log::error!("{}", e);
+                exit(EXIT_REJECTED);
+            }
+            Err(e @ copiepate::client::ClientError::Timeout(_)) => {
+                log::error!("{}", e);
+                exit(EXIT_TIMEOUT);
+            }
+            Err(e) => {
+                log::error!("Failed to fetch clipboard: {}", e);
+                exit(1);
+            }
+        }
     } else {
         let mut message = Vec::new();
         let mut stdin = std::io::stdin();
         stdin.read_to_end(&mut message).unwrap();
 
-        let mut client = copiepate::client::Client::new(&address, &key);
+        let mut client = create_client(&config, &address);
+        client.max_payload_size = max_payload_size;
+        client.max_frame_size = max_frame_size;
+        client.min_protocol_version = min_protocol_version;
+        client.timeouts = timeouts;
+        client.transport = transport.clone();
 
         if config.tee {
             tee(&message).expect("Failed to write to stdout");
@@ -262,10 +1244,33 @@ Error: {} ", e);
             eprintln!();
         }
 
-        match client.send(&message) {
+        let message = copiepate::Message {
+            selection,
+            content_type: config.content_type.clone(),
+            charset: config.charset.clone(),
+            content: message,
+        };
+        match client.send_message(&message) {
             Ok(_) => {
                 log::info!("Message sent successfully");
             }
+            Err(
+                e @ copiepate::client::ClientError::Rejected {
+                    code: copiepate::StatusCode::KdfMismatch,
+                    ..
+                },
+            ) => exit_on_kdf_mismatch(e),
+            Err(
+                e @ (copiepate::client::ClientError::Rejected { .. }
+                | copiepate::client::ClientError::Authentication(_)),
+            ) => {
+                log::error!("{}", e);
+                exit(EXIT_REJECTED);
+            }
+            Err(e @ copiepate::client::ClientError::Timeout(_)) => {
+                log::error!("{}", e);
+                exit(EXIT_TIMEOUT);
+            }
             Err(e) => {
                 log::error!("Failed to send message: {}", e);
                 exit(1);
@@ -273,3 +1278,93 @@ Error: {} ", e);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_date() {
+        let midnight = humantime::parse_rfc3339("2025-01-31T00:00:00Z").unwrap();
+        let next_day = midnight + Duration::from_secs(24 * 60 * 60);
+        assert_eq!(midnight, parse_date("2025-01-31", false).unwrap());
+
+        // A key expiring on a date is valid that whole day
+        let mut key = ClientKey::new("laptop", "Laptop", &[0; copiepate::KEY_SIZE]);
+        key.not_before = Some(parse_date("2025-01-31", false).unwrap());
+        key.not_after = Some(parse_date("2025-01-31", true).unwrap());
+        assert!(!key.is_valid_at(midnight - Duration::from_secs(1)));
+        assert!(key.is_valid_at(midnight));
+        assert!(key.is_valid_at(next_day - Duration::from_secs(1)));
+        assert!(!key.is_valid_at(next_day));
+
+        // Dates with a time are used as is
+        assert_eq!(
+            midnight + Duration::from_secs(12 * 60 * 60),
+            parse_date("2025-01-31T12:00:00Z", true).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_pairing_file() {
+        let opt = |args: &[&str]| {
+            let mut opt = Opt::from_iter(["copiepate"].iter().chain(args));
+            opt.config_file = Some(PathBuf::from("/config.toml"));
+            opt
+        };
+        assert_eq!(
+            PathBuf::from("/config.toml"),
+            get_pairing_file(&opt(&[])).unwrap()
+        );
+        assert_eq!(
+            PathBuf::from("/secret"),
+            get_pairing_file(&opt(&["--secret-file", "/secret"])).unwrap()
+        );
+
+        // Other secret sources can't be replaced
+        for args in [
+            ["--secret-env", "COPIEPATE_SECRET"],
+            ["--secret-command", "pass show copiepate"],
+            ["--passphrase", "correct horse battery staple"],
+        ] {
+            assert!(get_pairing_file(&opt(&args)).is_err());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_save_secret_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir();
+        let config = dir.join(format!("copiepate-pair-config-{}.toml", std::process::id()));
+        let secret_file = dir.join(format!("copiepate-pair-secret-{}", std::process::id()));
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        // Pairing into existing files readable by every user restricts them to their owner
+        for (path, is_secret_file) in [(&config, false), (&secret_file, true)] {
+            std::fs::write(path, "address = \"127.0.0.1\"\n").unwrap();
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            save_secret(path, b"secret", is_secret_file).unwrap();
+            assert_eq!(0o600, mode(path));
+        }
+        assert!(std::fs::read_to_string(&config)
+            .unwrap()
+            .contains("address = \"127.0.0.1\""));
+        assert_eq!("c2VjcmV0\n", *read_secret_file(&secret_file).unwrap());
+
+        std::fs::remove_file(&config).unwrap();
+        std::fs::remove_file(&secret_file).unwrap();
+    }
+
+    #[test]
+    fn test_secrets_redacted() {
+        let opt = Opt::from_iter(["copiepate", "--secret", "c2VjcmV0", "--passphrase", "horse"]);
+        let debug = format!("{opt:?}");
+        assert!(!debug.contains("c2VjcmV0"));
+        assert!(!debug.contains("horse"));
+        assert_eq!("c2VjcmV0", opt.secret.unwrap().expose());
+    }
+}
@@ -0,0 +1,231 @@
+use std::{
+    io::{Error, ErrorKind},
+    str::FromStr,
+};
+
+use log::error;
+use num_derive::{FromPrimitive, ToPrimitive};
+
+use crate::ProtocolVersionType;
+
+const FIELD_SIZE_SIZE: usize = std::mem::size_of::<u8>();
+
+/// Content type of messages that don't declare one.
+pub const DEFAULT_CONTENT_TYPE: &str = "text/plain";
+
+/// Selections a message is written to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromPrimitive, ToPrimitive)]
+pub enum Selection {
+    /// Clipboard, pasted with Ctrl+V
+    Clipboard = 1,
+    /// X11 and Wayland primary selection, pasted with a middle click
+    Primary = 2,
+    /// Both the clipboard and the primary selection
+    Both = 3,
+}
+
+impl Selection {
+    /// Clipboard and primary selection targeted by this selection.
+    pub fn targets(&self) -> &'static [Selection] {
+        match self {
+            Selection::Clipboard => &[Selection::Clipboard],
+            Selection::Primary => &[Selection::Primary],
+            Selection::Both => &[Selection::Clipboard, Selection::Primary],
+        }
+    }
+}
+
+impl FromStr for Selection {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "clipboard" => Ok(Selection::Clipboard),
+            "primary" => Ok(Selection::Primary),
+            "both" => Ok(Selection::Both),
+            _ => Err(format!(
+                "unknown selection '{name}', expected one of: clipboard, primary, both"
+            )),
+        }
+    }
+}
+
+/// Content of CopyMessage, ExecMessage and Clipboard frames.
+///
+/// Since protocol version 8 the content is preceded by its selection, content type and
+/// charset:
+/// | selection (u8) | content_type_size (u8) | content_type (ascii) | charset_size (u8) | charset (ascii) | content |
+///
+/// Protocol version 7 does not declare the selection, protocol version 6 only declares
+/// the charset, and older protocol versions only carry the content. Empty fields and a
+/// zero selection are not declared. Clients refuse to send a selection or a non text
+/// content type to servers that can't receive them.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Message {
+    /// Selection the message is written to, the server default if not declared
+    pub selection: Option<Selection>,
+    /// MIME type of the content, such as `image/png`
+    pub content_type: Option<String>,
+    /// Charset of the content if it is text, such as `utf-8`
+    pub charset: Option<String>,
+    /// Raw content of the message
+    pub content: Vec<u8>,
+}
+
+impl Message {
+    /// Message without any declared content type or charset.
+    pub fn new(content: Vec<u8>) -> Self {
+        Self {
+            selection: None,
+            content_type: None,
+            charset: None,
+            content,
+        }
+    }
+
+    /// UTF-8 plain text message.
+    pub fn text(content: String) -> Self {
+        Self {
+            selection: None,
+            content_type: Some(String::from(DEFAULT_CONTENT_TYPE)),
+            charset: Some(String::from("utf-8")),
+            content: content.into_bytes(),
+        }
+    }
+
+    pub fn with_selection(mut self, selection: Selection) -> Self {
+        self.selection = Some(selection);
+        self
+    }
+
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    pub fn with_charset(mut self, charset: impl Into<String>) -> Self {
+        self.charset = Some(charset.into());
+        self
+    }
+
+    /// MIME type of the content without its parameters, `text/plain` if not declared.
+    pub fn mime_type(&self) -> String {
+        self.content_type
+            .as_deref()
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|mime_type| mime_type.trim().to_ascii_lowercase())
+            .filter(|mime_type| !mime_type.is_empty())
+            .unwrap_or_else(|| String::from(DEFAULT_CONTENT_TYPE))
+    }
+
+    /// Whether the content is text, and can be written to a text clipboard.
+    pub fn is_text(&self) -> bool {
+        self.mime_type().starts_with("text/")
+    }
+
+    /// Decode the content as text, returns `None` if its charset is not supported.
+    ///
+    /// Content without a declared charset is decoded as UTF-8. Invalid sequences are
+    /// replaced with U+FFFD.
+    pub fn to_text(&self) -> Option<String> {
+        let charset = match &self.charset {
+            None => return Some(String::from_utf8_lossy(&self.content).into_owned()),
+            Some(charset) => charset.to_ascii_lowercase(),
+        };
+        match charset.as_str() {
+            "utf-8" | "utf8" | "us-ascii" | "ascii" => {
+                Some(String::from_utf8_lossy(&self.content).into_owned())
+            }
+            "iso-8859-1" | "latin1" => Some(self.content.iter().map(|&b| b as char).collect()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn to_bytes(&self, version: ProtocolVersionType) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        if crate::has_selection(version) {
+            let selection = self
+                .selection
+                .map_or(0, |s| num_traits::ToPrimitive::to_u8(&s).unwrap());
+            bytes.push(selection);
+        }
+        if crate::has_content_type(version) {
+            write_field(&mut bytes, self.content_type.as_deref())?;
+        }
+        if crate::has_charset(version) {
+            write_field(&mut bytes, self.charset.as_deref())?;
+        }
+        bytes.extend_from_slice(&self.content);
+        Ok(bytes)
+    }
+
+    pub(crate) fn from_bytes(version: ProtocolVersionType, bytes: &[u8]) -> Result<Self, Error> {
+        let mut bytes = bytes;
+        let selection = if crate::has_selection(version) {
+            let (selection, rest) = bytes.split_first().ok_or_else(|| {
+                error!("Message payload too short");
+                Error::from(ErrorKind::InvalidData)
+            })?;
+            bytes = rest;
+            match selection {
+                0 => None,
+                s => Some(num_traits::FromPrimitive::from_u8(*s).ok_or_else(|| {
+                    error!("Unknown message selection {s}");
+                    Error::from(ErrorKind::InvalidData)
+                })?),
+            }
+        } else {
+            None
+        };
+        let content_type = if crate::has_content_type(version) {
+            read_field(&mut bytes)?
+        } else {
+            None
+        };
+        let charset = if crate::has_charset(version) {
+            read_field(&mut bytes)?
+        } else {
+            None
+        };
+        Ok(Self {
+            selection,
+            content_type,
+            charset,
+            content: bytes.to_vec(),
+        })
+    }
+}
+
+/// Write a size prefixed ascii field, an empty field is not declared.
+fn write_field(bytes: &mut Vec<u8>, field: Option<&str>) -> Result<(), Error> {
+    let field = field.unwrap_or_default();
+    if field.len() > u8::MAX as usize || !field.is_ascii() {
+        error!("Invalid message field: '{field}'");
+        return Err(Error::from(ErrorKind::InvalidInput));
+    }
+    bytes.reserve(FIELD_SIZE_SIZE + field.len());
+    bytes.push(field.len() as u8);
+    bytes.extend_from_slice(field.as_bytes());
+    Ok(())
+}
+
+fn read_field(bytes: &mut &[u8]) -> Result<Option<String>, Error> {
+    let (size, rest) = bytes.split_first().ok_or_else(|| {
+        error!("Message payload too short");
+        Error::from(ErrorKind::InvalidData)
+    })?;
+    if rest.len() < *size as usize {
+        error!("Message field truncated");
+        return Err(Error::from(ErrorKind::InvalidData));
+    }
+    let (field, rest) = rest.split_at(*size as usize);
+    *bytes = rest;
+    match std::str::from_utf8(field) {
+        Ok("") => Ok(None),
+        Ok(field) if field.is_ascii() => Ok(Some(field.to_owned())),
+        _ => {
+            error!("Message field is not ascii");
+            Err(Error::from(ErrorKind::InvalidData))
+        }
+    }
+}
@@ -0,0 +1,425 @@
+//! Pair a client with a server: both peers derive a new secret from a short one-time
+//! code with SPAKE2, so that no secret has to be copied from one machine to the other.
+//!
+//! An eavesdropper learns nothing about the code or the secret, and an active attacker
+//! gets a single guess of the code: the server stops pairing after a wrong code.
+
+use std::{
+    fmt,
+    io::{Read, Write},
+    str::FromStr,
+    time::Duration,
+};
+
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use thiserror::Error;
+use zeroize::Zeroize;
+
+use crate::{
+    handshake::{Role, PROOF_SIZE},
+    transport::{Listener, LocalAddress},
+    FrameError, FrameSizeType, NetFrame, NetFrameType, Status, StatusCode, Timeout,
+    DEFAULT_HANDSHAKE_TIMEOUT, KEY_SIZE, PROTOCOL_VERSION,
+};
+
+/// Number of digits of a pairing code.
+pub const PAIRING_CODE_DIGITS: usize = 8;
+const CLIENT_IDENTITY: &[u8] = b"copiepate client";
+const SERVER_IDENTITY: &[u8] = b"copiepate server";
+const SECRET_INFO: &[u8] = b"copiepate paired secret";
+const CONFIRMATION_KEY_INFO: &[u8] = b"copiepate pairing confirmation key";
+/// Pairing frames only carry SPAKE2 messages, proofs and errors.
+const MAX_PAIRING_FRAME_SIZE: FrameSizeType = 1024;
+
+#[derive(Error, Debug)]
+pub enum PairingError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("Malformed frame: {0}")]
+    Frame(FrameError),
+
+    #[error("Invalid pairing code: {0}")]
+    InvalidCode(String),
+
+    #[error("Key exchange failed: {0}")]
+    KeyExchange(#[from] spake2::Error),
+
+    #[error("Pairing codes of the client and the server differ")]
+    WrongCode,
+
+    #[error("Pairing rejected by server with code {code}: {reason}")]
+    Rejected { code: StatusCode, reason: String },
+
+    #[error("Invalid state {0}")]
+    InvalidState(String),
+
+    #[error("Timed out {0}")]
+    Timeout(String),
+}
+
+impl From<FrameError> for PairingError {
+    fn from(error: FrameError) -> Self {
+        match error {
+            FrameError::Io(e) => PairingError::Io(e),
+            e => PairingError::Frame(e),
+        }
+    }
+}
+
+impl PairingError {
+    /// Report IO timeouts as `Timeout`, while doing `context`.
+    fn or_timeout(self, context: &str) -> Self {
+        match self {
+            PairingError::Io(e) if crate::is_timeout(&e) => PairingError::Timeout(context.into()),
+            e => e,
+        }
+    }
+}
+
+/// One-time code displayed by the server and typed on the client, such as `1234-5678`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PairingCode(String);
+
+impl PairingCode {
+    /// Random code.
+    pub fn generate() -> Self {
+        let mut rng = rand::rngs::OsRng;
+        Self(
+            (0..PAIRING_CODE_DIGITS)
+                .map(|_| char::from(b'0' + rng.gen_range(0..10)))
+                .collect(),
+        )
+    }
+
+    fn password(&self) -> Password {
+        Password::new(self.0.as_bytes())
+    }
+}
+
+impl fmt::Display for PairingCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (first, second) = self.0.split_at(PAIRING_CODE_DIGITS / 2);
+        write!(f, "{first}-{second}")
+    }
+}
+
+impl fmt::Debug for PairingCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PairingCode(..)")
+    }
+}
+
+impl FromStr for PairingCode {
+    type Err = PairingError;
+
+    /// Parse a code typed by the user, dashes and spaces are ignored.
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        let digits: String = code
+            .chars()
+            .filter(|c| *c != '-' && !c.is_whitespace())
+            .collect();
+        if digits.len() != PAIRING_CODE_DIGITS || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(PairingError::InvalidCode(format!(
+                "expected {PAIRING_CODE_DIGITS} digits"
+            )));
+        }
+        Ok(Self(digits))
+    }
+}
+
+/// Server waiting for a client to pair with its one-time code. The code can be tried
+/// only once: pairing stops as soon as a client sends a wrong code.
+pub struct PairingServer<'a> {
+    address: &'a str,
+    code: PairingCode,
+    /// Time a client has to complete the exchange once connected, `None` waits for ever
+    pub timeout: Option<Duration>,
+    /// Listener bound with `PairingServer::bind`, used by the next `PairingServer::accept`
+    listener: Option<Listener>,
+}
+
+impl<'a> PairingServer<'a> {
+    pub fn new(address: &'a str, code: PairingCode) -> Self {
+        Self {
+            address,
+            code,
+            timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+            listener: None,
+        }
+    }
+
+    /// Code clients must send to pair.
+    pub fn code(&self) -> &PairingCode {
+        &self.code
+    }
+
+    /// Bind the server address, returns the address actually bound.
+    pub fn bind(&mut self) -> Result<LocalAddress, PairingError> {
+        if self.listener.is_none() {
+            self.listener = Some(Listener::bind(self.address)?);
+        }
+        let listener = self.listener.as_ref().expect("Listener is bound");
+        Ok(listener.local_address()?)
+    }
+
+    /// Wait for a client to pair, returns the secret shared with the client. Clients that
+    /// don't pair are turned away, a wrong code stops pairing.
+    pub fn accept(&mut self) -> Result<[u8; KEY_SIZE], PairingError> {
+        self.bind()?;
+        let listener = self.listener.take().expect("Listener is bound");
+        log::info!(
+            "Waiting for a client to pair on {}",
+            listener.local_address()?
+        );
+        loop {
+            let mut stream = listener.accept()?;
+            stream.set_read_timeout(self.timeout)?;
+            stream.set_write_timeout(self.timeout)?;
+            match pair_server(&mut stream, &self.code).map_err(|e| e.or_timeout("pairing")) {
+                Ok(secret) => return Ok(secret),
+                Err(PairingError::WrongCode) => return Err(PairingError::WrongCode),
+                Err(e) => log::error!("Failed to pair with client: {e}"),
+            }
+        }
+    }
+}
+
+/// Pair with a server waiting with `code` on `stream`, returns the shared secret.
+pub(crate) fn pair_client<Stream: Read + Write>(
+    stream: &mut Stream,
+    code: &PairingCode,
+) -> Result<[u8; KEY_SIZE], PairingError> {
+    let (spake, client_message) = Spake2::<Ed25519Group>::start_a(
+        &code.password(),
+        &Identity::new(CLIENT_IDENTITY),
+        &Identity::new(SERVER_IDENTITY),
+    );
+    write_frame(stream, NetFrameType::Pair, client_message.clone())?;
+    let server_message = read_frame(stream, NetFrameType::Pair)?.payload;
+    let confirmation = Confirmation::new(
+        &spake.finish(&server_message)?,
+        &client_message,
+        &server_message,
+    );
+
+    write_frame(
+        stream,
+        NetFrameType::Auth,
+        confirmation.proof(Role::Client).to_vec(),
+    )?;
+    let proof = read_frame(stream, NetFrameType::Auth)?.payload;
+    if !confirmation.verify(Role::Server, &proof) {
+        return Err(PairingError::WrongCode);
+    }
+    log::debug!("Paired with server");
+    Ok(confirmation.secret())
+}
+
+/// Pair with the client connected on `stream`, returns the shared secret.
+fn pair_server<Stream: Read + Write>(
+    stream: &mut Stream,
+    code: &PairingCode,
+) -> Result<[u8; KEY_SIZE], PairingError> {
+    let client_message = match read_frame(stream, NetFrameType::Pair) {
+        Ok(frame) => frame.payload,
+        Err(e @ PairingError::InvalidState(_)) => {
+            reject(
+                stream,
+                StatusCode::Forbidden,
+                "server is pairing, retry later",
+            )?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    let (spake, server_message) = Spake2::<Ed25519Group>::start_b(
+        &code.password(),
+        &Identity::new(CLIENT_IDENTITY),
+        &Identity::new(SERVER_IDENTITY),
+    );
+    let shared_secret = match spake.finish(&client_message) {
+        Ok(shared_secret) => shared_secret,
+        Err(e) => {
+            reject(stream, StatusCode::HandshakeFailed, &e.to_string())?;
+            return Err(e.into());
+        }
+    };
+    write_frame(stream, NetFrameType::Pair, server_message.clone())?;
+    let confirmation = Confirmation::new(&shared_secret, &client_message, &server_message);
+
+    let proof = read_frame(stream, NetFrameType::Auth)?.payload;
+    if !confirmation.verify(Role::Client, &proof) {
+        reject(
+            stream,
+            StatusCode::AuthenticationFailed,
+            "wrong pairing code",
+        )?;
+        return Err(PairingError::WrongCode);
+    }
+    write_frame(
+        stream,
+        NetFrameType::Auth,
+        confirmation.proof(Role::Server).to_vec(),
+    )?;
+    log::debug!("Paired with client");
+    Ok(confirmation.secret())
+}
+
+fn write_frame<Stream: Write>(
+    stream: &mut Stream,
+    frame_type: NetFrameType,
+    payload: Vec<u8>,
+) -> Result<(), PairingError> {
+    stream.write_all(&NetFrame::new(PROTOCOL_VERSION, frame_type, payload).to_net())?;
+    Ok(())
+}
+
+/// Plaintext error, the peers don't share any key yet.
+fn reject<Stream: Write>(
+    stream: &mut Stream,
+    code: StatusCode,
+    message: &str,
+) -> Result<(), PairingError> {
+    let status = Status {
+        code,
+        message: String::from(message),
+    };
+    stream.write_all(&NetFrame::handshake_error_frame(&status).to_net())?;
+    Ok(())
+}
+
+/// Read the next frame, which must be of type `expected`.
+fn read_frame<Stream: Read>(
+    stream: &mut Stream,
+    expected: NetFrameType,
+) -> Result<NetFrame, PairingError> {
+    let frame = NetFrame::from_net(stream, MAX_PAIRING_FRAME_SIZE)?;
+    match frame.frame_type {
+        frame_type if frame_type == expected => Ok(frame),
+        NetFrameType::Error => {
+            let status = Status::from_bytes(&frame.payload)?;
+            Err(PairingError::Rejected {
+                code: status.code,
+                reason: status.message,
+            })
+        }
+        frame_type => Err(PairingError::InvalidState(format!(
+            "Unexpected {frame_type:?} frame while pairing"
+        ))),
+    }
+}
+
+/// Key confirmation of the SPAKE2 exchange: each peer proves it derived the same key,
+/// which only happens if both used the same code.
+struct Confirmation {
+    shared_secret: Vec<u8>,
+    key: [u8; KEY_SIZE],
+}
+
+impl Confirmation {
+    fn new(shared_secret: &[u8], client_message: &[u8], server_message: &[u8]) -> Self {
+        let mut info = CONFIRMATION_KEY_INFO.to_vec();
+        info.extend_from_slice(client_message);
+        info.extend_from_slice(server_message);
+        let mut key = [0; KEY_SIZE];
+        Hkdf::<Sha256>::new(None, shared_secret)
+            .expand(&info, &mut key)
+            .expect("Confirmation key length is valid for HKDF");
+        Self {
+            shared_secret: shared_secret.to_vec(),
+            key,
+        }
+    }
+
+    fn proof(&self, role: Role) -> [u8; PROOF_SIZE] {
+        self.mac(role).finalize().into_bytes().into()
+    }
+
+    /// Check a proof in constant time.
+    fn verify(&self, role: Role, proof: &[u8]) -> bool {
+        self.mac(role).verify_slice(proof).is_ok()
+    }
+
+    fn mac(&self, role: Role) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key)
+            .expect("HMAC accepts keys of any size");
+        mac.update(role.label());
+        mac
+    }
+
+    /// Secret shared by the paired peers, independent of the confirmation key.
+    fn secret(&self) -> [u8; KEY_SIZE] {
+        let mut secret = [0; KEY_SIZE];
+        Hkdf::<Sha256>::new(None, &self.shared_secret)
+            .expand(SECRET_INFO, &mut secret)
+            .expect("Secret length is valid for HKDF");
+        secret
+    }
+}
+
+impl Drop for Confirmation {
+    /// Wipe the keys derived from the exchange from memory.
+    fn drop(&mut self) {
+        self.shared_secret.zeroize();
+        self.key.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_code() {
+        let code = PairingCode::generate();
+        assert_eq!(code, code.to_string().parse().unwrap());
+        assert_eq!(
+            PairingCode(String::from("12345678")),
+            " 1234 5678\n".parse().unwrap()
+        );
+        assert!("1234-567".parse::<PairingCode>().is_err());
+        assert!("1234-567a".parse::<PairingCode>().is_err());
+    }
+
+    fn pair(
+        server_code: &str,
+        client_code: &str,
+    ) -> (
+        Result<[u8; KEY_SIZE], PairingError>,
+        Result<[u8; KEY_SIZE], PairingError>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let server_code = server_code.parse().unwrap();
+        let server = std::thread::spawn(move || pair_server(&mut server, &server_code));
+        let client = pair_client(&mut client, &client_code.parse().unwrap());
+        (server.join().unwrap(), client)
+    }
+
+    #[test]
+    fn test_same_code() {
+        let (server, client) = pair("1234-5678", "1234-5678");
+        assert_eq!(server.unwrap(), client.unwrap());
+    }
+
+    #[test]
+    fn test_wrong_code() {
+        let (server, client) = pair("1234-5678", "1234-5679");
+        assert!(matches!(server, Err(PairingError::WrongCode)));
+        assert!(matches!(
+            client,
+            Err(PairingError::Rejected {
+                code: StatusCode::AuthenticationFailed,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,416 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, oneshot, Semaphore},
+};
+use tokio_util::codec::Framed;
+
+use super::{
+    connection::{self, ConnectionSettings, Event, FrameEvent, Protocol, Response},
+    error::ServerError,
+    handle::{ServerHandle, SHUTDOWN_POLL_INTERVAL},
+    keyring::ServerKeys,
+    worker::WorkerSettings,
+    Server,
+};
+use crate::{
+    backend::ClipboardBackend,
+    codec::NetFrameCodec,
+    transport::{AsyncListener, LocalAddress},
+    Message, NetFrame,
+};
+
+/// Request of a connection task to the owner of the clipboard.
+enum Request {
+    /// Handle an event, its result is sent back to the connection
+    Event(Event, oneshot::Sender<Result<Response, ServerError>>),
+    /// A connection that saved a message in `once` mode was closed
+    Done,
+}
+
+/// Async counterpart of [`Server`], running on the tokio runtime.
+///
+/// Each connection is handled by its own task, their events are handled one at a time
+/// on the blocking thread pool, since clipboard backends and exec commands are blocking.
+/// Connections keep being accepted and opened while an event is handled.
+pub struct AsyncServer<'a, P>
+where
+    P: ClipboardBackend,
+{
+    server: Server<'a, P>,
+}
+
+impl<'a, P> From<Server<'a, P>> for AsyncServer<'a, P>
+where
+    P: ClipboardBackend,
+{
+    fn from(server: Server<'a, P>) -> Self {
+        Self { server }
+    }
+}
+
+impl<'a, P> AsyncServer<'a, P>
+where
+    P: ClipboardBackend,
+{
+    /// Bind the server to its address, see [`Server::bind`].
+    pub fn bind(&mut self) -> Result<LocalAddress, ServerError> {
+        self.server.bind()
+    }
+
+    /// Handle to shut down the server, see [`Server::handle`].
+    pub fn handle(&self) -> ServerHandle {
+        self.server.handle()
+    }
+
+    /// Handle a connection with the settings of the server, for applications handling
+    /// events themselves.
+    pub fn connection<S>(&self, stream: S) -> AsyncConnection<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let settings = self.server.worker_settings();
+        AsyncConnection::new(stream, settings.keys, settings.connection)
+    }
+}
+
+impl<'a, P> AsyncServer<'a, P>
+where
+    P: ClipboardBackend + Send + 'static,
+{
+    /// Start Copiepate server. Listen until the server is shut down, or until the first
+    /// message saved to the clipboard in `once` mode, which is then returned.
+    ///
+    /// The clipboard is lost if this future is dropped while an event is handled, the
+    /// server can't be started again then.
+    pub async fn start(&mut self) -> Result<Option<Message>, ServerError> {
+        let listener = AsyncListener::from_std(self.server.listener()?)?;
+        let mut handler = self.server.event_handler()?;
+
+        let (requests, mut receiver) = mpsc::channel(self.server.max_connections.max(1));
+        let accept = tokio::spawn(accept(
+            listener,
+            self.server.max_connections.max(1),
+            self.server.worker_settings(),
+            requests,
+        ));
+
+        let mut pasted = None;
+        let result = loop {
+            match receiver.recv().await {
+                Some(Request::Event(event, reply)) => {
+                    let task = tokio::task::spawn_blocking(move || {
+                        let result = handler.handle_event(event, &mut pasted);
+                        (handler, pasted, result)
+                    });
+                    let result;
+                    (handler, pasted, result) = match task.await {
+                        Ok(handled) => handled,
+                        Err(e) => {
+                            accept.abort();
+                            return Err(ServerError::Io(std::io::Error::other(e)));
+                        }
+                    };
+                    if reply.send(result).is_err() {
+                        log::error!("Connection closed before its event was handled");
+                    }
+                }
+                Some(Request::Done) => break Ok(pasted),
+                None => {
+                    log::info!("Server stopped");
+                    break Ok(pasted);
+                }
+            }
+        };
+        accept.abort();
+        // Wait for the listener to be closed, so that its address can be bound again
+        let _ = accept.await;
+        self.server.restore_handler(handler);
+        result
+    }
+}
+
+async fn accept(
+    listener: AsyncListener,
+    max_connections: usize,
+    settings: WorkerSettings,
+    requests: mpsc::Sender<Request>,
+) {
+    let permits = Arc::new(Semaphore::new(max_connections));
+    loop {
+        let stream = listener.accept().await;
+        // The handle connects to the server to wake up this loop
+        if settings.connection.shutdown.is_shutdown() {
+            return;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::error!("Connection failed: {}", e);
+                continue;
+            }
+        };
+
+        let permit = match permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let error = ServerError::TooManyConnections(max_connections);
+                log::warn!("Refusing connection: {error}");
+                let mut transport = Framed::new(
+                    stream,
+                    NetFrameCodec::new(settings.connection.max_frame_size),
+                );
+                if let Err(e) = transport
+                    .send(connection::handshake_error_frame(&error))
+                    .await
+                {
+                    log::error!("Failed to send error to client: {e}");
+                }
+                continue;
+            }
+        };
+
+        let connection =
+            AsyncConnection::new(stream, settings.keys.clone(), settings.connection.clone());
+        let requests = requests.clone();
+        let once = settings.once;
+        tokio::spawn(async move {
+            handle_connection(connection, once, requests).await;
+            drop(permit);
+        });
+    }
+}
+
+/// Handle the events of a connection until it is closed.
+async fn handle_connection<S>(
+    mut connection: AsyncConnection<S>,
+    once: bool,
+    requests: mpsc::Sender<Request>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut pasted = false;
+
+    while let Some(event) = connection.next_event().await {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                log::error!("Error handling connection: {e}");
+                // Handshake errors are already reported to the client
+                if connection.is_opened() && e.is_reportable() {
+                    if let Err(e) = connection.reject(&e).await {
+                        log::error!("Failed to send error to client: {e}");
+                    }
+                }
+                break;
+            }
+        };
+
+        let is_paste = matches!(event, Event::PasteEvent(_));
+        let (reply, result) = oneshot::channel();
+        let result = match requests.send(Request::Event(event, reply)).await {
+            Ok(()) => result.await.ok(),
+            Err(_) => None,
+        }
+        .unwrap_or_else(|| {
+            Err(ServerError::Io(std::io::Error::other(
+                "server is shutting down",
+            )))
+        });
+        pasted |= is_paste && result.is_ok();
+
+        let response = match result {
+            Ok(response) => connection.respond(response).await,
+            Err(e) => {
+                log::error!("{e}");
+                connection.reject(&e).await
+            }
+        };
+        if let Err(e) = response {
+            log::error!("Failed to respond to client: {e}");
+            break;
+        }
+    }
+
+    if pasted && once {
+        // The owner may already be gone
+        let _ = requests.send(Request::Done).await;
+    }
+}
+
+/// Connection of a client over an async stream, the async counterpart of the blocking
+/// connections of [`Server`].
+///
+/// Events received from the client are read as a [`Stream`], each of them is answered
+/// with [`AsyncConnection::respond`] or [`AsyncConnection::reject`].
+pub struct AsyncConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    transport: Framed<S, NetFrameCodec>,
+    protocol: Protocol,
+    settings: ConnectionSettings,
+    /// Handshake frames not sent yet
+    outgoing: VecDeque<NetFrame>,
+    /// Error returned once the handshake frames are sent
+    error: Option<ServerError>,
+    closed: bool,
+}
+
+impl<S> AsyncConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(stream: S, keys: Arc<ServerKeys>, settings: ConnectionSettings) -> Self {
+        Self {
+            transport: Framed::new(stream, NetFrameCodec::new(settings.max_frame_size)),
+            protocol: Protocol::new(keys, settings.clone()),
+            settings,
+            outgoing: VecDeque::new(),
+            error: None,
+            closed: false,
+        }
+    }
+
+    /// Next event, fails with `ServerError::Timeout` if the client does not open the
+    /// connection or send a message in time. `None` once the connection is closed, or
+    /// when the server shuts down while waiting for a message.
+    pub async fn next_event(&mut self) -> Option<Result<Event, ServerError>> {
+        let (timeout, context) = match self.protocol.next_timeout() {
+            Ok(timeout) => timeout,
+            Err(e) => return Some(Err(e)),
+        };
+        let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+
+        loop {
+            // Frames being received are read before closing the connection
+            if self.settings.shutdown.is_shutdown() && self.transport.read_buffer().is_empty() {
+                log::debug!("Closing connection, the server is shutting down");
+                return None;
+            }
+
+            let wait = tokio::time::Instant::now() + SHUTDOWN_POLL_INTERVAL;
+            let wait = deadline.map_or(wait, |deadline| deadline.min(wait));
+            match tokio::time::timeout_at(wait, self.next()).await {
+                Ok(event) => return event,
+                Err(_) if deadline == Some(wait) => {
+                    return Some(Err(ServerError::Timeout(String::from(context))))
+                }
+                Err(_) => (),
+            }
+        }
+    }
+
+    /// Whether the connection is opened, errors can then be reported with `reject`.
+    pub fn is_opened(&self) -> bool {
+        self.protocol.is_opened()
+    }
+
+    /// Respond to the last message received.
+    pub async fn respond(&mut self, response: Response) -> Result<(), ServerError> {
+        let frame = self.protocol.response_frame(response)?;
+        self.send(frame).await
+    }
+
+    /// Reject the last message received.
+    pub async fn reject(&mut self, error: &ServerError) -> Result<(), ServerError> {
+        let frame = self.protocol.rejection_frame(error)?;
+        self.send(frame).await
+    }
+
+    async fn send(&mut self, frame: Option<NetFrame>) -> Result<(), ServerError> {
+        if let Some(frame) = frame {
+            with_timeout(
+                self.settings.timeouts.read,
+                "sending a response",
+                self.transport.send(frame),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Send the queued handshake frames.
+    fn poll_outgoing(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ServerError>> {
+        while !self.outgoing.is_empty() {
+            ready!(Pin::new(&mut self.transport).poll_ready(cx))?;
+            if let Some(frame) = self.outgoing.pop_front() {
+                Pin::new(&mut self.transport).start_send(frame)?;
+            }
+        }
+        ready!(Pin::new(&mut self.transport).poll_flush(cx))?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> Stream for AsyncConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = Result<Event, ServerError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Err(e) = ready!(this.poll_outgoing(cx)) {
+                this.closed = true;
+                return Poll::Ready(Some(Err(e)));
+            }
+            if let Some(e) = this.error.take() {
+                this.closed = true;
+                return Poll::Ready(Some(Err(e)));
+            }
+            if this.closed {
+                return Poll::Ready(None);
+            }
+
+            let frame = match ready!(this.transport.poll_next_unpin(cx)) {
+                Some(frame) => frame,
+                None => {
+                    this.closed = true;
+                    return Poll::Ready(Some(Err(ServerError::Io(std::io::Error::from(
+                        std::io::ErrorKind::UnexpectedEof,
+                    )))));
+                }
+            };
+
+            let frame_event = this.protocol.handle_frame(frame);
+            this.outgoing.extend(this.protocol.take_outgoing());
+            match frame_event {
+                Ok(FrameEvent::Closed) => this.closed = true,
+                Ok(FrameEvent::Open) => (), // Wait for next frame on Open
+                Ok(FrameEvent::Message(m)) => return Poll::Ready(Some(Ok(Event::PasteEvent(m)))),
+                Ok(FrameEvent::Exec(m)) => return Poll::Ready(Some(Ok(Event::ExecEvent(m)))),
+                Ok(FrameEvent::Fetch(m)) => return Poll::Ready(Some(Ok(Event::FetchEvent(m)))),
+                // Send the handshake error before reporting the error
+                Err(e) => this.error = Some(e),
+            }
+        }
+    }
+}
+
+/// Fail with `ServerError::Timeout` if `future` does not complete in time.
+async fn with_timeout<T, E>(
+    timeout: Option<Duration>,
+    context: &str,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, ServerError>
+where
+    ServerError: From<E>,
+{
+    match timeout {
+        None => Ok(future.await?),
+        Some(timeout) => Ok(tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| ServerError::Timeout(context.into()))??),
+    }
+}
@@ -1,66 +1,325 @@
-use std::io::{Read, Write};
+use std::{
+    io::{Read, Write},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{Key, KeyInit};
+use rand::rngs::OsRng;
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
 
-use crate::{Cipher, NetFrame, Nonce, CLOSE_PAYLOAD};
+use crate::{
+    handshake::{self, AuthMethod, Authenticator, ClientHello, Role, ServerHello},
+    kdf::KdfParams,
+    Capabilities, Cipher, FrameError, FrameSizeType, Message, NetFrame, NetFrameType, Nonce,
+    ProtocolVersionType, Status, StatusCode, Timeout, Timeouts, CLOSE_PAYLOAD,
+    MIN_PROTOCOL_VERSION, TAG_SIZE,
+};
 
-use super::error::ServerError;
+use super::{
+    error::ServerError,
+    handle::{ServerHandle, SHUTDOWN_POLL_INTERVAL},
+    keyring::{ClientIdentity, ServerKeys},
+};
 
-enum FrameEvent {
+/// Outcome of a frame received from the client.
+pub(super) enum FrameEvent {
     Open,
     Message(PasteEvent),
     Exec(ExecEvent),
+    Fetch(FetchEvent),
     Closed,
 }
 
 #[derive(Debug, Clone)]
 pub struct PasteEvent {
-    pub payload: String,
+    pub message: Message,
+    /// Client that sent the event, `None` for clients using the server default key
+    pub client: Option<ClientIdentity>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ExecEvent {
-    pub payload: String,
+    pub message: Message,
+    /// Client that sent the event, `None` for clients using the server default key
+    pub client: Option<ClientIdentity>,
 }
 
 #[derive(Debug, Clone)]
+pub struct FetchEvent {
+    /// Client that sent the event, `None` for clients using the server default key
+    pub client: Option<ClientIdentity>,
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Event {
     PasteEvent(PasteEvent),
     ExecEvent(ExecEvent),
+    FetchEvent(FetchEvent),
 }
 
-pub struct Connection<Stream>
+impl Event {
+    /// Client that sent the event, `None` for clients using the server default key.
+    pub fn client(&self) -> Option<&ClientIdentity> {
+        match self {
+            Event::PasteEvent(e) => e.client.as_ref(),
+            Event::ExecEvent(e) => e.client.as_ref(),
+            Event::FetchEvent(e) => e.client.as_ref(),
+        }
+    }
+}
+
+/// Response sent back to the client once an event has been handled.
+#[derive(Debug, Clone)]
+pub enum Response {
+    /// Acknowledge the event with a message
+    Ack(String),
+    /// Send the content of the clipboard
+    Clipboard(Message),
+}
+
+/// Settings shared by every connection of a server.
+#[derive(Debug, Clone)]
+pub struct ConnectionSettings {
+    pub max_frame_size: FrameSizeType,
+    pub max_payload_size: usize,
+    /// Oldest protocol version accepted from clients
+    pub min_protocol_version: ProtocolVersionType,
+    /// Handshake, read and idle timeouts
+    pub timeouts: Timeouts,
+    /// Connections are closed between messages once the server shuts down
+    pub shutdown: ServerHandle,
+}
+
+/// Reject a connection that is not opened yet with a plaintext error.
+pub fn reject_connection<Stream>(mut stream: Stream, error: &ServerError) -> Result<(), ServerError>
 where
-    Stream: Sized + Read + Write,
+    Stream: Write,
 {
-    stream: Stream,
+    stream.write_all(&handshake_error_frame(error).to_net())?;
+    Ok(())
+}
+
+/// Plaintext error sent before the connection is opened.
+pub(super) fn handshake_error_frame(error: &ServerError) -> NetFrame {
+    let status = Status {
+        code: error.status_code(),
+        message: error.to_string(),
+    };
+    NetFrame::handshake_error_frame(&status)
+}
+
+/// Protocol state of a connection, independent of its transport: frames received from
+/// the client are handled by [`Protocol::handle_frame`], frames to send back are queued
+/// in `outgoing`.
+pub(super) struct Protocol {
+    /// Start of the handshake timeout
+    accepted_at: Instant,
+    /// Keys accepted by the server
+    keys: Arc<ServerKeys>,
+    /// Client owning the key, `None` for the server default key
+    client: Option<ClientIdentity>,
+    /// How the client proves its identity
+    auth_method: AuthMethod,
+    /// Cipher of the session, derived from the pre-shared key once the connection is opened
     cipher: Cipher,
+    settings: ConnectionSettings,
     state: crate::ConnectionState,
+    /// Protocol version negotiated when opening the connection
+    version: ProtocolVersionType,
+    /// Capabilities supported by both peers
+    capabilities: Capabilities,
+    /// Verify the client proof while authenticating
+    authenticator: Option<Authenticator>,
+    /// Nonce of the last message received, used to encrypt its response
+    request_nonce: Option<Nonce>,
+    /// Handshake frames to send to the client
+    outgoing: Vec<NetFrame>,
 }
 
-impl<Stream> Connection<Stream>
-where
-    Stream: Sized + Read + Write,
-{
-    pub fn new(stream: Stream, cipher: Cipher) -> Self {
+impl Protocol {
+    pub fn new(keys: Arc<ServerKeys>, settings: ConnectionSettings) -> Self {
         Self {
-            stream,
-            cipher,
+            accepted_at: Instant::now(),
+            keys,
+            client: None,
+            auth_method: AuthMethod::Secret,
+            cipher: Cipher::new(&Key::default()),
+            settings,
             state: crate::ConnectionState::New,
+            version: MIN_PROTOCOL_VERSION,
+            capabilities: Capabilities::empty(),
+            authenticator: None,
+            request_nonce: None,
+            outgoing: Vec::new(),
         }
     }
 
-    fn next_frame(&mut self) -> Result<FrameEvent, ServerError> {
-        let frame = NetFrame::from_net(&mut self.stream)?;
+    pub fn max_frame_size(&self) -> FrameSizeType {
+        self.settings.max_frame_size
+    }
+
+    /// Frames queued while handling the last frame.
+    pub fn take_outgoing(&mut self) -> Vec<NetFrame> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// Time to wait for the next frame and what the connection is waiting for: the rest
+    /// of the handshake timeout if the connection is not opened yet, the idle timeout
+    /// otherwise.
+    pub fn next_timeout(&self) -> Result<(Option<Duration>, &'static str), ServerError> {
+        match self.state {
+            crate::ConnectionState::New | crate::ConnectionState::Authenticating(_) => {
+                let context = "opening the connection";
+                let remaining = match self.settings.timeouts.handshake {
+                    None => None,
+                    Some(timeout) => Some(
+                        timeout
+                            .checked_sub(self.accepted_at.elapsed())
+                            .filter(|remaining| !remaining.is_zero())
+                            .ok_or_else(|| ServerError::Timeout(String::from(context)))?,
+                    ),
+                };
+                Ok((remaining, context))
+            }
+            _ => Ok((self.settings.timeouts.idle, "waiting for a message")),
+        }
+    }
+
+    /// Whether the handshake is over. Handshake errors are reported in plaintext while
+    /// opening the connection, only later errors are reported to the client.
+    pub fn is_opened(&self) -> bool {
+        matches!(self.state, crate::ConnectionState::Opened(_))
+    }
+
+    /// Response to the last message received, `None` for legacy clients.
+    pub fn response_frame(&mut self, response: Response) -> Result<Option<NetFrame>, ServerError> {
+        match response {
+            Response::Ack(message) => self.status_frame(
+                NetFrameType::Ack,
+                Status {
+                    code: StatusCode::Ok,
+                    message,
+                },
+            ),
+            Response::Clipboard(message) => {
+                let bytes = message
+                    .to_bytes(self.version)
+                    .map_err(|e| ServerError::MalformedMessage(e.to_string()))?;
+                self.encrypted_frame(NetFrameType::Clipboard, &bytes)
+            }
+        }
+    }
+
+    /// Rejection of the last message received, `None` for legacy clients.
+    pub fn rejection_frame(
+        &mut self,
+        error: &ServerError,
+    ) -> Result<Option<NetFrame>, ServerError> {
+        self.status_frame(
+            NetFrameType::Error,
+            Status {
+                code: error.status_code(),
+                message: error.to_string(),
+            },
+        )
+    }
+
+    fn status_frame(
+        &mut self,
+        frame_type: NetFrameType,
+        status: Status,
+    ) -> Result<Option<NetFrame>, ServerError> {
+        self.encrypted_frame(frame_type, &status.to_bytes())
+    }
+
+    fn encrypted_frame(
+        &mut self,
+        frame_type: NetFrameType,
+        message: &[u8],
+    ) -> Result<Option<NetFrame>, ServerError> {
+        if !crate::has_acks(self.version) {
+            log::trace!("Legacy client, not sending {frame_type:?} frame");
+            return Ok(None);
+        }
+
+        let nounce = match self.request_nonce.take() {
+            Some(nounce) => nounce.reply(),
+            None => {
+                log::error!("No message to respond to");
+                return Err(ServerError::InvalidState);
+            }
+        };
+        let frame = NetFrame::encrypted(self.version, frame_type, &self.cipher, &nounce, message)
+            .map_err(ServerError::Encryption)?;
+        Ok(Some(frame))
+    }
+
+    /// Reject the connection before it is opened with a plaintext error.
+    fn reject_handshake(&mut self, error: &ServerError) {
+        self.outgoing.push(handshake_error_frame(error));
+    }
+
+    /// Handle a frame read from the client, or the error reading it.
+    pub fn handle_frame(
+        &mut self,
+        frame: Result<NetFrame, FrameError>,
+    ) -> Result<FrameEvent, ServerError> {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(FrameError::UnsupportedVersion(version)) => {
+                let error = ServerError::UnsupportedVersion(format!(
+                    "client protocol version {version} is not supported by the server"
+                ));
+                if matches!(self.state, crate::ConnectionState::New) {
+                    self.reject_handshake(&error);
+                }
+                return Err(error);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if !matches!(self.state, crate::ConnectionState::New)
+            && frame.protocol_version != self.version
+        {
+            log::error!(
+                "Received frame with protocol version {}, expected {}",
+                frame.protocol_version,
+                self.version
+            );
+            return Err(ServerError::InvalidState);
+        }
 
         match frame.frame_type {
             crate::NetFrameType::Open => self.handle_open(&frame),
             crate::NetFrameType::CopyMessage => self.handle_copy_message(&frame),
             crate::NetFrameType::ExecMessage => self.handle_exec_message(&frame),
+            crate::NetFrameType::GetClipboard => self.handle_get_clipboard(&frame),
+            crate::NetFrameType::Auth => self.handle_auth(&frame),
             crate::NetFrameType::Close => self.handle_close(&frame),
+            crate::NetFrameType::Pair => self.handle_pair(),
+            crate::NetFrameType::Ack
+            | crate::NetFrameType::Error
+            | crate::NetFrameType::Clipboard => {
+                log::error!("Received unexpected {:?} frame", frame.frame_type);
+                Err(ServerError::InvalidState)
+            }
         }
     }
 
+    /// Clients can only pair with a server waiting for them with a one-time code.
+    fn handle_pair(&mut self) -> Result<FrameEvent, ServerError> {
+        if !matches!(self.state, crate::ConnectionState::New) {
+            log::error!("Received pair frame on an already opened connection");
+            return Err(ServerError::InvalidState);
+        }
+        let error =
+            ServerError::Forbidden(String::from("server is not waiting for a client to pair"));
+        self.reject_handshake(&error);
+        Err(error)
+    }
+
     fn handle_close(&self, frame: &NetFrame) -> Result<FrameEvent, ServerError> {
         log::trace!("Received end of stream");
         let nounce = match &self.state {
@@ -70,9 +329,8 @@ where
                 return Err(ServerError::InvalidState);
             }
         };
-        let message = self
-            .cipher
-            .decrypt(nounce.cipher_nonce(), frame.payload.as_ref())
+        let message = frame
+            .decrypt(&self.cipher, nounce)
             .map_err(ServerError::Decryption)?;
 
         if message != CLOSE_PAYLOAD {
@@ -82,34 +340,270 @@ where
         Ok(FrameEvent::Closed)
     }
 
-    fn handle_open(&mut self, _frame: &NetFrame) -> Result<FrameEvent, ServerError> {
+    fn handle_open(&mut self, frame: &NetFrame) -> Result<FrameEvent, ServerError> {
         log::trace!("Received open connection");
-        // TODO: create state machine/other to make sure only one nounce is sent
+        if !matches!(self.state, crate::ConnectionState::New) {
+            log::error!("Received open frame on an already opened connection");
+            return Err(ServerError::InvalidState);
+        }
+
+        // Borrow the key of the client from the server keys rather than copying it
+        let keys = self.keys.clone();
         let nounce = Nonce::default();
-        let nounce_frame = NetFrame::nounce_frame(&nounce);
-        self.stream.write_all(&nounce_frame.to_net())?;
+        let response = if frame.payload.is_empty() {
+            // Legacy clients only speak the version of their header, and expect a nonce
+            if crate::has_negotiation(frame.protocol_version) {
+                let error = ServerError::UnsupportedVersion(format!(
+                    "client protocol version {} sent an empty Open frame",
+                    frame.protocol_version
+                ));
+                self.reject_handshake(&error);
+                return Err(error);
+            }
+            self.version = frame.protocol_version;
+            self.select_key(&keys, None, AuthMethod::Secret, None)?;
+            nounce.value.to_vec()
+        } else {
+            let hello = ClientHello::from_bytes(&frame.payload)?;
+            self.version = match hello.negotiate(self.settings.min_protocol_version) {
+                Some(version) => version,
+                None => {
+                    let error = ServerError::UnsupportedVersion(format!(
+                        "client supports protocol versions {} to {}, server supports {} to {}",
+                        hello.min_version,
+                        hello.max_version,
+                        self.settings.min_protocol_version,
+                        crate::PROTOCOL_VERSION
+                    ));
+                    self.reject_handshake(&error);
+                    return Err(error);
+                }
+            };
+            if hello.auth_method == AuthMethod::Ssh && !crate::has_ssh_authentication(self.version)
+            {
+                let error = ServerError::UnsupportedVersion(format!(
+                    "SSH authentication requires protocol version {}",
+                    crate::PROTOCOL_VERSION
+                ));
+                self.reject_handshake(&error);
+                return Err(error);
+            }
+            let key = self.select_key(
+                &keys,
+                hello.key_id.as_deref(),
+                hello.auth_method,
+                hello.kdf.as_ref(),
+            )?;
+            self.capabilities = hello.capabilities.intersection(Capabilities::supported());
+            let (public_key, shared_secret) = if crate::has_key_exchange(self.version) {
+                match self.exchange_keys(key, &hello, &nounce) {
+                    Ok((public_key, shared_secret)) => (Some(public_key), Some(shared_secret)),
+                    Err(error) => {
+                        self.reject_handshake(&error);
+                        return Err(error);
+                    }
+                }
+            } else {
+                (None, None)
+            };
+            let response = ServerHello {
+                nonce: nounce,
+                capabilities: self.capabilities,
+                public_key,
+            }
+            .to_bytes();
+
+            if let Some(shared_secret) = shared_secret {
+                if crate::has_mutual_authentication(self.version) {
+                    self.authenticator = Some(Authenticator::new(
+                        key,
+                        &shared_secret,
+                        self.version,
+                        &frame.payload,
+                        &response,
+                    ));
+                }
+            }
+            response
+        };
+
+        if self.version < self.settings.min_protocol_version {
+            let error = ServerError::UnsupportedVersion(format!(
+                "client protocol version {} is older than the minimum version {}",
+                self.version, self.settings.min_protocol_version
+            ));
+            self.reject_handshake(&error);
+            return Err(error);
+        }
+
+        log::debug!("Opening connection with protocol version {}", self.version);
+        self.outgoing
+            .push(NetFrame::new(self.version, NetFrameType::Open, response));
+        self.state = if self.authenticator.is_some() {
+            crate::ConnectionState::Authenticating(nounce)
+        } else {
+            crate::ConnectionState::Opened(nounce)
+        };
+        Ok(FrameEvent::Open)
+    }
+
+    /// Use the key named by the client, or the default key.
+    fn select_key<'k>(
+        &mut self,
+        keys: &'k ServerKeys,
+        key_id: Option<&str>,
+        auth_method: AuthMethod,
+        kdf: Option<&KdfParams>,
+    ) -> Result<&'k Key, ServerError> {
+        match keys.select(key_id, auth_method, kdf) {
+            Ok((key, client)) => {
+                self.client = client;
+                self.auth_method = auth_method;
+                self.cipher = Cipher::new(key);
+                Ok(key)
+            }
+            Err(error) => {
+                self.reject_handshake(&error);
+                Err(error)
+            }
+        }
+    }
+
+    fn handle_auth(&mut self, frame: &NetFrame) -> Result<FrameEvent, ServerError> {
+        log::trace!("Received client proof");
+        let (nounce, authenticator) = match (&self.state, self.authenticator.take()) {
+            (crate::ConnectionState::Authenticating(nounce), Some(authenticator)) => {
+                (*nounce, authenticator)
+            }
+            (s, _) => {
+                log::error!("Invalid state '{s:?}' while handling client proof");
+                return Err(ServerError::InvalidState);
+            }
+        };
+
+        let proof = match self
+            .verify_client(&authenticator, &frame.payload)
+            .and_then(|()| self.server_proof(&authenticator))
+        {
+            Ok(proof) => proof,
+            Err(e) => {
+                let error = ServerError::Authentication;
+                log::error!("{e}");
+                self.reject_handshake(&error);
+                return Err(error);
+            }
+        };
+        self.outgoing
+            .push(NetFrame::new(self.version, NetFrameType::Auth, proof));
         self.state = crate::ConnectionState::Opened(nounce);
         Ok(FrameEvent::Open)
     }
 
+    /// Proof of the knowledge of the secret, or signature of the host key for clients
+    /// authenticating with an SSH key.
+    fn server_proof(&self, authenticator: &Authenticator) -> Result<Vec<u8>, String> {
+        if self.auth_method == AuthMethod::Secret {
+            return Ok(authenticator.proof(Role::Server).to_vec());
+        }
+        let host_key = self
+            .keys
+            .host_key
+            .as_ref()
+            .ok_or("Server has no host key")?;
+        host_key
+            .sign(&authenticator.ssh_challenge(Role::Server))
+            .map_err(|e| format!("Failed to sign with the host key: {e}"))
+    }
+
+    /// Check the client proof, or the signature of its SSH key.
+    fn verify_client(&mut self, authenticator: &Authenticator, proof: &[u8]) -> Result<(), String> {
+        if self.auth_method == AuthMethod::Secret {
+            if !authenticator.verify(Role::Client, proof) {
+                return Err(String::from("Client failed to prove it knows the secret"));
+            }
+            return Ok(());
+        }
+
+        let public_key = self
+            .keys
+            .authorized_keys
+            .verify(proof, &authenticator.ssh_challenge(Role::Client))
+            .map_err(|e| format!("Client failed to authenticate with its SSH key: {e}"))?;
+        let client = ClientIdentity {
+            key_id: public_key.fingerprint(ssh_key::HashAlg::Sha256).to_string(),
+            name: String::from(public_key.comment()),
+        };
+        log::debug!("Client authenticated as {client}");
+        self.client = Some(client);
+        Ok(())
+    }
+
+    /// Derive the session cipher from the client ephemeral key, returns the server
+    /// ephemeral public key and the shared secret.
+    fn exchange_keys(
+        &mut self,
+        key: &Key,
+        hello: &ClientHello,
+        nounce: &Nonce,
+    ) -> Result<(PublicKey, SharedSecret), ServerError> {
+        let client_public_key = hello.public_key.ok_or_else(|| {
+            log::error!("Client did not send its public key");
+            ServerError::KeyExchange
+        })?;
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret);
+        let shared_secret = secret.diffie_hellman(&client_public_key);
+        if !shared_secret.was_contributory() {
+            log::error!("Client sent a low order public key");
+            return Err(ServerError::KeyExchange);
+        }
+
+        self.cipher =
+            handshake::session_cipher(key, &shared_secret, &client_public_key, &public_key, nounce);
+        Ok((public_key, shared_secret))
+    }
+
     fn handle_copy_message(&mut self, frame: &NetFrame) -> Result<FrameEvent, ServerError> {
         log::trace!("Received new copy message");
-        let payload = self.parse_message(frame)?;
+        let message = self.parse_message(frame)?;
 
-        log::debug!("Received message: '{}'", &payload);
-        Ok(FrameEvent::Message(PasteEvent { payload }))
+        log::debug!("Received message: {message:?}");
+        Ok(FrameEvent::Message(PasteEvent {
+            message,
+            client: self.client.clone(),
+        }))
     }
 
     fn handle_exec_message(&mut self, frame: &NetFrame) -> Result<FrameEvent, ServerError> {
         log::trace!("Received new event message");
-        let payload = self.parse_message(frame)?;
+        let message = self.parse_message(frame)?;
+
+        log::debug!("Received message: {message:?}");
+        Ok(FrameEvent::Exec(ExecEvent {
+            message,
+            client: self.client.clone(),
+        }))
+    }
 
-        log::debug!("Received message: '{}'", &payload);
-        Ok(FrameEvent::Exec(ExecEvent { payload }))
+    fn handle_get_clipboard(&mut self, frame: &NetFrame) -> Result<FrameEvent, ServerError> {
+        log::trace!("Received new clipboard request");
+        self.decrypt_message(frame)?;
+        if !self.capabilities.contains(Capabilities::FETCH) {
+            log::error!("Client did not negotiate clipboard requests");
+            return Err(ServerError::InvalidState);
+        }
+        Ok(FrameEvent::Fetch(FetchEvent {
+            client: self.client.clone(),
+        }))
     }
 
-    fn parse_message(&mut self, frame: &NetFrame) -> Result<String, ServerError> {
-        // TODO: Solve issue for frame_type leaking issue (parse if opened, otherwise decrypt?)
+    fn parse_message(&mut self, frame: &NetFrame) -> Result<Message, ServerError> {
+        let bytes = self.decrypt_message(frame)?;
+        Message::from_bytes(self.version, &bytes)
+            .map_err(|_| ServerError::MalformedMessage(String::from("invalid message header")))
+    }
+
+    fn decrypt_message(&mut self, frame: &NetFrame) -> Result<Vec<u8>, ServerError> {
         let nounce = match &self.state {
             crate::ConnectionState::Opened(nounce) => nounce,
             s => {
@@ -117,23 +611,133 @@ where
                 return Err(ServerError::InvalidState);
             }
         };
-        let message = self
-            .cipher
-            .decrypt(nounce.cipher_nonce(), frame.payload.as_ref())
+        self.request_nonce = Some(*nounce);
+        let size = frame.payload.len().saturating_sub(TAG_SIZE);
+        if size > self.settings.max_payload_size {
+            return Err(ServerError::PayloadTooLarge {
+                size,
+                max: self.settings.max_payload_size,
+            });
+        }
+        let message = frame
+            .decrypt(&self.cipher, nounce)
             .map_err(ServerError::Decryption)?;
         self.state = crate::ConnectionState::Opened(nounce.consume());
+        Ok(message)
+    }
+}
 
-        // Using lossy conversion here in case copy event from the other system is not utf-8.
-        // A better implementation would perhaps be passing the encoding in the protocol
-        // Are there cases where we might paste non-string message?
-        let content_string = String::from_utf8_lossy(&message);
-        Ok(content_string.into_owned())
+/// Connection of a client over a blocking stream.
+pub struct Connection<Stream>
+where
+    Stream: Sized + Read + Write + Timeout,
+{
+    stream: Stream,
+    protocol: Protocol,
+}
+
+impl<Stream> Connection<Stream>
+where
+    Stream: Sized + Read + Write + Timeout,
+{
+    pub(super) fn new(stream: Stream, keys: Arc<ServerKeys>, settings: ConnectionSettings) -> Self {
+        Self {
+            stream,
+            protocol: Protocol::new(keys, settings),
+        }
+    }
+
+    /// Whether the connection is opened, errors can then be reported with `reject`.
+    pub fn is_opened(&self) -> bool {
+        self.protocol.is_opened()
+    }
+
+    /// Respond to the last message received.
+    pub fn respond(&mut self, response: Response) -> Result<(), ServerError> {
+        let frame = self.protocol.response_frame(response)?;
+        self.send(frame)
+    }
+
+    /// Reject the last message received.
+    pub fn reject(&mut self, error: &ServerError) -> Result<(), ServerError> {
+        let frame = self.protocol.rejection_frame(error)?;
+        self.send(frame)
+    }
+
+    fn send(&mut self, frame: Option<NetFrame>) -> Result<(), ServerError> {
+        if let Some(frame) = frame {
+            self.stream.write_all(&frame.to_net())?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the first byte of the next frame, until the end of the handshake
+    /// timeout if the connection is not opened yet, or until the idle timeout. `None` if
+    /// the server shuts down first.
+    fn wait_for_frame(&mut self) -> Result<Option<u8>, ServerError> {
+        let (timeout, context) = self.protocol.next_timeout()?;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.stream
+            .set_write_timeout(self.protocol.settings.timeouts.read)?;
+
+        let mut first_byte = [0; 1];
+        loop {
+            if self.protocol.settings.shutdown.is_shutdown() {
+                return Ok(None);
+            }
+
+            let wait = match deadline {
+                None => SHUTDOWN_POLL_INTERVAL,
+                Some(deadline) => deadline
+                    .saturating_duration_since(Instant::now())
+                    .min(SHUTDOWN_POLL_INTERVAL),
+            };
+            if wait.is_zero() {
+                return Err(ServerError::Timeout(String::from(context)));
+            }
+            self.stream.set_read_timeout(Some(wait))?;
+
+            match self.stream.read(&mut first_byte) {
+                Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                Ok(_) => break,
+                Err(e) if crate::is_timeout(&e) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        self.stream
+            .set_read_timeout(self.protocol.settings.timeouts.read)?;
+        Ok(Some(first_byte[0]))
+    }
+
+    fn next_frame(&mut self) -> Result<FrameEvent, ServerError> {
+        let first_byte = match self.wait_for_frame()? {
+            Some(first_byte) => [first_byte],
+            None => {
+                log::debug!("Closing connection, the server is shutting down");
+                return Ok(FrameEvent::Closed);
+            }
+        };
+        let mut reader = first_byte.as_slice().chain(&mut self.stream);
+        let frame = match NetFrame::from_net(&mut reader, self.protocol.max_frame_size()) {
+            Err(FrameError::Io(e)) if crate::is_timeout(&e) => {
+                return Err(ServerError::Timeout(String::from("reading a frame")));
+            }
+            frame => frame,
+        };
+
+        let event = self.protocol.handle_frame(frame);
+        for frame in self.protocol.take_outgoing() {
+            self.stream.write_all(&frame.to_net())?;
+        }
+        event
     }
 }
 
 impl<Stream> Iterator for Connection<Stream>
 where
-    Stream: Sized + Read + Write,
+    Stream: Sized + Read + Write + Timeout,
 {
     type Item = Result<Event, ServerError>;
 
@@ -150,7 +754,511 @@ where
                 FrameEvent::Open => (), // Wait for next frame on Open
                 FrameEvent::Message(m) => return Some(Ok(Event::PasteEvent(m))),
                 FrameEvent::Exec(m) => return Some(Ok(Event::ExecEvent(m))),
+                FrameEvent::Fetch(m) => return Some(Ok(Event::FetchEvent(m))),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::thread::JoinHandle;
+
+    use super::*;
+    use crate::{
+        NetFrameType, DEFAULT_MAX_FRAME_SIZE, DEFAULT_MAX_PAYLOAD_SIZE, HEADER_SIZE,
+        PROTOCOL_VERSION,
+    };
+
+    const TESTING_KEY: &[u8; crate::KEY_SIZE] = b"__WARNING_UNSECURE_KEY_TESTING__";
+
+    fn key() -> Key {
+        *Key::from_slice(TESTING_KEY)
+    }
+
+    fn keys() -> Arc<ServerKeys> {
+        Arc::new(ServerKeys {
+            default: Some(crate::secret_key(TESTING_KEY)),
+            kdf: None,
+            keyring: Default::default(),
+            authorized_keys: Default::default(),
+            host_key: None,
+            allow_secret: false,
+        })
+    }
+
+    /// Start a connection, returning the client stream and the server events.
+    fn start_connection() -> (TcpStream, JoinHandle<Vec<Result<Event, ServerError>>>) {
+        start_connection_with(MIN_PROTOCOL_VERSION)
+    }
+
+    fn start_connection_with(
+        min_protocol_version: ProtocolVersionType,
+    ) -> (TcpStream, JoinHandle<Vec<Result<Event, ServerError>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let settings = ConnectionSettings {
+                max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+                max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+                min_protocol_version,
+                timeouts: Timeouts::default(),
+                shutdown: ServerHandle::default(),
+            };
+            let mut events = Vec::new();
+            for event in Connection::new(stream, keys(), settings) {
+                let is_err = event.is_err();
+                events.push(event);
+                if is_err {
+                    break;
+                }
+            }
+            events
+        });
+        (client, server)
+    }
+
+    fn read_frame(stream: &mut TcpStream) -> NetFrame {
+        NetFrame::from_net(stream, DEFAULT_MAX_FRAME_SIZE).unwrap()
+    }
+
+    /// Open the connection without authenticating, returning the nonce, the session
+    /// cipher and the authenticator derived with `key`.
+    fn open_unauthenticated(stream: &mut TcpStream, key: &Key) -> (Nonce, Cipher, Authenticator) {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret);
+        let open_frame = NetFrame::open_frame(&ClientHello::new(
+            MIN_PROTOCOL_VERSION,
+            public_key,
+            None,
+            AuthMethod::Secret,
+            None,
+        ));
+        stream.write_all(&open_frame.to_net()).unwrap();
+
+        let frame = read_frame(stream);
+        assert_eq!(PROTOCOL_VERSION, frame.protocol_version);
+        let hello = ServerHello::from_bytes(&frame.payload).unwrap();
+        let server_public_key = hello.public_key.unwrap();
+        let shared_secret = secret.diffie_hellman(&server_public_key);
+        let cipher = handshake::session_cipher(
+            key,
+            &shared_secret,
+            &public_key,
+            &server_public_key,
+            &hello.nonce,
+        );
+        let authenticator = Authenticator::new(
+            key,
+            &shared_secret,
+            PROTOCOL_VERSION,
+            &open_frame.payload,
+            &frame.payload,
+        );
+        (hello.nonce, cipher, authenticator)
+    }
+
+    /// Open and authenticate the connection, returning the nonce and the session cipher.
+    fn open(stream: &mut TcpStream) -> (Nonce, Cipher) {
+        let (nonce, cipher, authenticator) = open_unauthenticated(stream, &key());
+        let proof = authenticator.proof(Role::Client).to_vec();
+        stream
+            .write_all(&NetFrame::new(PROTOCOL_VERSION, NetFrameType::Auth, proof).to_net())
+            .unwrap();
+
+        let frame = read_frame(stream);
+        assert_eq!(NetFrameType::Auth, frame.frame_type);
+        assert!(authenticator.verify(Role::Server, &frame.payload));
+        (nonce, cipher)
+    }
+
+    /// Send a copy message to a new connection, applying `mutate` to the raw frame.
+    fn send_copy_message(
+        message: &Message,
+        mutate: impl FnOnce(&mut Vec<u8>),
+    ) -> Vec<Result<Event, ServerError>> {
+        let (mut stream, server) = start_connection();
+        let (nonce, cipher) = open(&mut stream);
+
+        let frame = NetFrame::encrypted(
+            PROTOCOL_VERSION,
+            NetFrameType::CopyMessage,
+            &cipher,
+            &nonce,
+            &message.to_bytes(PROTOCOL_VERSION).unwrap(),
+        )
+        .unwrap();
+        let mut bytes = frame.to_net();
+        mutate(&mut bytes);
+        // Ignore errors, the server might have already closed the connection
+        let _ = stream.write_all(&bytes);
+        let _ = stream.shutdown(Shutdown::Write);
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_untampered_frame_accepted() {
+        let message = Message::text(String::from("message"));
+        match send_copy_message(&message, |_| ()).first() {
+            Some(Ok(Event::PasteEvent(e))) => assert_eq!(message, e.message),
+            e => panic!("Expected paste event, got {e:?}"),
+        }
+    }
+
+    #[test]
+    fn test_binary_message_preserved() {
+        let message = Message::new(vec![0x00, 0xff, 0xfe, b'\n', 0xc3]);
+        match send_copy_message(&message, |_| ()).first() {
+            Some(Ok(Event::PasteEvent(e))) => assert_eq!(message, e.message),
+            e => panic!("Expected paste event, got {e:?}"),
+        }
+
+        let message = Message::new(vec![0x89, b'P', b'N', b'G']).with_content_type("image/png");
+        match send_copy_message(&message, |_| ()).first() {
+            Some(Ok(Event::PasteEvent(e))) => {
+                assert_eq!(message, e.message);
+                assert!(!e.message.is_text());
+            }
+            e => panic!("Expected paste event, got {e:?}"),
+        }
+
+        let message = Message::new(vec![b'c', 0xe9]).with_charset("iso-8859-1");
+        match send_copy_message(&message, |_| ()).first() {
+            Some(Ok(Event::PasteEvent(e))) => {
+                assert_eq!(Some(String::from("c\u{e9}")), e.message.to_text())
+            }
+            e => panic!("Expected paste event, got {e:?}"),
+        }
+    }
+
+    #[test]
+    fn test_tampered_header_rejected() {
+        for index in 0..HEADER_SIZE {
+            for mask in [0x01, 0x80] {
+                let events = send_copy_message(&Message::new(b"message".to_vec()), |bytes| {
+                    bytes[index] ^= mask
+                });
+                assert!(
+                    matches!(events.first(), Some(Err(_))),
+                    "Tampered byte {index} with mask {mask:#x} accepted: {events:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_tampered_header_fails_decryption() {
+        const FRAME_SIZE_OFFSET: usize = crate::PROTOCOL_VERSION_SIZE;
+        const FRAME_TYPE_OFFSET: usize = FRAME_SIZE_OFFSET + crate::FRAME_SIZE_SIZE;
+        let set_frame_type = |frame_type: NetFrameType| {
+            move |bytes: &mut Vec<u8>| {
+                let frame_type = num_traits::ToPrimitive::to_u32(&frame_type).unwrap();
+                bytes[FRAME_TYPE_OFFSET..HEADER_SIZE].copy_from_slice(&frame_type.to_le_bytes());
+            }
+        };
+        let shrink_frame = |bytes: &mut Vec<u8>| {
+            let size = &mut bytes[FRAME_SIZE_OFFSET..FRAME_TYPE_OFFSET];
+            let shrunk = u64::from_le_bytes(size.try_into().unwrap()) - 1;
+            size.copy_from_slice(&shrunk.to_le_bytes());
+            bytes.pop();
+        };
+
+        // Headers that still parse reach the AEAD, which must refuse them
+        let message = Message::new(b"message".to_vec());
+        for events in [
+            send_copy_message(&message, set_frame_type(NetFrameType::ExecMessage)),
+            send_copy_message(&message, set_frame_type(NetFrameType::GetClipboard)),
+            send_copy_message(&message, shrink_frame),
+        ] {
+            assert!(
+                matches!(events.first(), Some(Err(ServerError::Decryption(_)))),
+                "Expected decryption error, got {events:?}"
+            );
+        }
+    }
+
+    /// Send an exec message encrypted with the header of a copy message, or of the exec
+    /// message itself, as associated data.
+    fn send_exec_message(authenticated_type: NetFrameType) -> Vec<Result<Event, ServerError>> {
+        use chacha20poly1305::aead::{Aead, Payload};
+
+        let (mut stream, server) = start_connection();
+        let (nonce, cipher) = open(&mut stream);
+        let message = Message::new(b"message".to_vec())
+            .to_bytes(PROTOCOL_VERSION)
+            .unwrap();
+        let authenticated = NetFrame::encrypted(
+            PROTOCOL_VERSION,
+            authenticated_type,
+            &cipher,
+            &nonce,
+            &message,
+        )
+        .unwrap();
+        let payload = cipher
+            .encrypt(
+                nonce.cipher_nonce(),
+                Payload {
+                    msg: &message,
+                    aad: &authenticated.header(),
+                },
+            )
+            .unwrap();
+        let frame = NetFrame {
+            frame_type: NetFrameType::ExecMessage,
+            payload,
+            ..authenticated
+        };
+        stream.write_all(&frame.to_net()).unwrap();
+        let _ = stream.shutdown(Shutdown::Write);
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_header_bound_to_ciphertext() {
+        // The server authenticates the header it received...
+        assert!(matches!(
+            &send_exec_message(NetFrameType::ExecMessage)[..],
+            [Ok(Event::ExecEvent(_)), ..]
+        ));
+        // ...so a ciphertext authenticating another header is refused
+        assert!(matches!(
+            &send_exec_message(NetFrameType::CopyMessage)[..],
+            [Err(ServerError::Decryption(_))]
+        ));
+    }
+
+    #[test]
+    fn test_legacy_client_refused() {
+        // An attacker in the middle replacing the client Open with a legacy one
+        let (mut stream, server) = start_connection_with(crate::MIN_AUTHENTICATED_PROTOCOL_VERSION);
+        let open_frame = NetFrame::new(MIN_PROTOCOL_VERSION, NetFrameType::Open, vec![]);
+        stream.write_all(&open_frame.to_net()).unwrap();
+
+        let frame = read_frame(&mut stream);
+        assert_eq!(NetFrameType::Error, frame.frame_type);
+        let status = Status::from_bytes(&frame.payload).unwrap();
+        assert_eq!(StatusCode::UnsupportedVersion, status.code);
+        assert!(matches!(
+            &server.join().unwrap()[..],
+            [Err(ServerError::UnsupportedVersion(_))]
+        ));
+    }
+
+    #[test]
+    fn test_empty_open_refused_after_negotiation() {
+        // Skipping the key exchange and authentication with a recent header version
+        let (mut stream, server) = start_connection();
+        let open_frame = NetFrame::new(PROTOCOL_VERSION, NetFrameType::Open, vec![]);
+        stream.write_all(&open_frame.to_net()).unwrap();
+
+        let frame = read_frame(&mut stream);
+        assert_eq!(NetFrameType::Error, frame.frame_type);
+        let status = Status::from_bytes(&frame.payload).unwrap();
+        assert_eq!(StatusCode::UnsupportedVersion, status.code);
+        assert!(matches!(
+            &server.join().unwrap()[..],
+            [Err(ServerError::UnsupportedVersion(_))]
+        ));
+    }
+
+    #[test]
+    fn test_legacy_client() {
+        let (mut stream, server) = start_connection();
+        let open_frame = NetFrame::new(MIN_PROTOCOL_VERSION, NetFrameType::Open, vec![]);
+        stream.write_all(&open_frame.to_net()).unwrap();
+
+        let frame = read_frame(&mut stream);
+        assert_eq!(MIN_PROTOCOL_VERSION, frame.protocol_version);
+        let nonce: Nonce = frame.payload.try_into().unwrap();
+
+        let cipher = Cipher::new(&key());
+        for (nonce, frame_type, message) in [
+            (nonce, NetFrameType::CopyMessage, b"message".as_slice()),
+            (
+                nonce.consume(),
+                NetFrameType::Close,
+                CLOSE_PAYLOAD.as_slice(),
+            ),
+        ] {
+            let frame =
+                NetFrame::encrypted(MIN_PROTOCOL_VERSION, frame_type, &cipher, &nonce, message)
+                    .unwrap();
+            stream.write_all(&frame.to_net()).unwrap();
+        }
+
+        let events = server.join().unwrap();
+        assert!(
+            matches!(&events[..], [Ok(Event::PasteEvent(e))] if e.message.content == b"message")
+        );
+        // Legacy clients do not expect any acknowledgement
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let (mut stream, server) = start_connection();
+        let hello = ClientHello {
+            min_version: PROTOCOL_VERSION + 1,
+            max_version: PROTOCOL_VERSION + 2,
+            capabilities: Capabilities::supported(),
+            public_key: None,
+            key_id: None,
+            auth_method: AuthMethod::Secret,
+            kdf: None,
+        };
+        stream
+            .write_all(&NetFrame::open_frame(&hello).to_net())
+            .unwrap();
+
+        let frame = read_frame(&mut stream);
+        assert_eq!(NetFrameType::Error, frame.frame_type);
+        let status = Status::from_bytes(&frame.payload).unwrap();
+        assert_eq!(StatusCode::UnsupportedVersion, status.code);
+        assert!(matches!(
+            &server.join().unwrap()[..],
+            [Err(ServerError::UnsupportedVersion(_))]
+        ));
+    }
+
+    #[test]
+    fn test_pre_shared_key_rejected_with_key_exchange() {
+        let (mut stream, server) = start_connection();
+        let (nonce, _) = open(&mut stream);
+
+        let frame = NetFrame::encrypted(
+            PROTOCOL_VERSION,
+            NetFrameType::CopyMessage,
+            &Cipher::new(&key()),
+            &nonce,
+            b"message",
+        )
+        .unwrap();
+        stream.write_all(&frame.to_net()).unwrap();
+
+        assert!(matches!(
+            &server.join().unwrap()[..],
+            [Err(ServerError::Decryption(_))]
+        ));
+    }
+
+    #[test]
+    fn test_missing_public_key() {
+        let (mut stream, server) = start_connection();
+        let hello = ClientHello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+            public_key: None,
+            key_id: None,
+            auth_method: AuthMethod::Secret,
+            kdf: None,
+        };
+        stream
+            .write_all(&NetFrame::open_frame(&hello).to_net())
+            .unwrap();
+
+        let frame = read_frame(&mut stream);
+        assert_eq!(NetFrameType::Error, frame.frame_type);
+        assert!(matches!(
+            &server.join().unwrap()[..],
+            [Err(ServerError::KeyExchange)]
+        ));
+    }
+
+    #[test]
+    fn test_unknown_key_id_rejected() {
+        let (mut stream, server) = start_connection();
+        let public_key = PublicKey::from(&EphemeralSecret::random_from_rng(OsRng));
+        let hello = ClientHello::new(
+            MIN_PROTOCOL_VERSION,
+            public_key,
+            Some(String::from("unknown")),
+            AuthMethod::Secret,
+            None,
+        );
+        stream
+            .write_all(&NetFrame::open_frame(&hello).to_net())
+            .unwrap();
+
+        let frame = read_frame(&mut stream);
+        assert_eq!(NetFrameType::Error, frame.frame_type);
+        let status = Status::from_bytes(&frame.payload).unwrap();
+        assert_eq!(StatusCode::AuthenticationFailed, status.code);
+        assert!(matches!(
+            &server.join().unwrap()[..],
+            [Err(ServerError::Authentication)]
+        ));
+    }
+
+    #[test]
+    fn test_kdf_without_passphrase_rejected() {
+        let (mut stream, server) = start_connection();
+        let public_key = PublicKey::from(&EphemeralSecret::random_from_rng(OsRng));
+        let hello = ClientHello::new(
+            MIN_PROTOCOL_VERSION,
+            public_key,
+            None,
+            AuthMethod::Secret,
+            Some(KdfParams::new(b"copiepate salt")),
+        );
+        stream
+            .write_all(&NetFrame::open_frame(&hello).to_net())
+            .unwrap();
+
+        let frame = read_frame(&mut stream);
+        assert_eq!(NetFrameType::Error, frame.frame_type);
+        let status = Status::from_bytes(&frame.payload).unwrap();
+        assert_eq!(StatusCode::KdfMismatch, status.code);
+        assert!(matches!(
+            &server.join().unwrap()[..],
+            [Err(ServerError::KdfMismatch(_))]
+        ));
+    }
+
+    #[test]
+    fn test_wrong_key_proof_rejected() {
+        let (mut stream, server) = start_connection();
+        let wrong_key = *Key::from_slice(b"__WARNING_WRONG_KEY_TESTING_____");
+        let (_, _, authenticator) = open_unauthenticated(&mut stream, &wrong_key);
+        let proof = authenticator.proof(Role::Client).to_vec();
+        stream
+            .write_all(&NetFrame::new(PROTOCOL_VERSION, NetFrameType::Auth, proof).to_net())
+            .unwrap();
+
+        let frame = read_frame(&mut stream);
+        assert_eq!(NetFrameType::Error, frame.frame_type);
+        let status = Status::from_bytes(&frame.payload).unwrap();
+        assert_eq!(StatusCode::AuthenticationFailed, status.code);
+        assert!(matches!(
+            &server.join().unwrap()[..],
+            [Err(ServerError::Authentication)]
+        ));
+    }
+
+    #[test]
+    fn test_message_before_authentication_rejected() {
+        let (mut stream, server) = start_connection();
+        let (nonce, cipher, _) = open_unauthenticated(&mut stream, &key());
+
+        let frame = NetFrame::encrypted(
+            PROTOCOL_VERSION,
+            NetFrameType::CopyMessage,
+            &cipher,
+            &nonce,
+            b"message",
+        )
+        .unwrap();
+        stream.write_all(&frame.to_net()).unwrap();
+
+        assert!(matches!(
+            &server.join().unwrap()[..],
+            [Err(ServerError::InvalidState)]
+        ));
+    }
+}
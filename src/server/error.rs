@@ -1,13 +1,106 @@
 use thiserror::Error;
 
+use crate::{FrameError, StatusCode};
+
 #[derive(Error, Debug)]
 pub enum ServerError {
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
+    #[error("Malformed frame: {0}")]
+    Frame(FrameError),
+
     #[error("Invalid state")]
     InvalidState,
 
     #[error("Decryption error: {0}")]
     Decryption(chacha20poly1305::aead::Error),
+
+    #[error("Encryption error: {0}")]
+    Encryption(chacha20poly1305::aead::Error),
+
+    #[error("Clipboard error: {0}")]
+    Clipboard(String),
+
+    #[error("Failed to execute custom command: {0}")]
+    Exec(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Key exchange failed")]
+    KeyExchange,
+
+    #[error("Authentication failed")]
+    Authentication,
+
+    #[error("Key derivation mismatch: {0}")]
+    KdfMismatch(String),
+
+    #[error("Unsupported protocol version: {0}")]
+    UnsupportedVersion(String),
+
+    #[error("Malformed message: {0}")]
+    MalformedMessage(String),
+
+    #[error("Unsupported content: {0}")]
+    UnsupportedContent(String),
+
+    #[error("Timed out {0}")]
+    Timeout(String),
+
+    #[error("Server is already handling {0} connections")]
+    TooManyConnections(usize),
+
+    #[error("Payload of {size} bytes exceeds the maximum payload size {max}")]
+    PayloadTooLarge { size: usize, max: usize },
+}
+
+impl From<FrameError> for ServerError {
+    fn from(error: FrameError) -> Self {
+        match error {
+            FrameError::Io(e) => ServerError::Io(e),
+            e => ServerError::Frame(e),
+        }
+    }
+}
+
+impl ServerError {
+    /// Status code reported to the client for this error.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ServerError::InvalidState => StatusCode::InvalidState,
+            ServerError::KeyExchange => StatusCode::HandshakeFailed,
+            ServerError::Authentication => StatusCode::AuthenticationFailed,
+            ServerError::KdfMismatch(_) => StatusCode::KdfMismatch,
+            ServerError::Decryption(_) => StatusCode::DecryptionFailed,
+            ServerError::Clipboard(_) => StatusCode::ClipboardFailed,
+            ServerError::Exec(_) => StatusCode::ExecFailed,
+            ServerError::Forbidden(_) => StatusCode::Forbidden,
+            ServerError::PayloadTooLarge { .. } => StatusCode::PayloadTooLarge,
+            ServerError::UnsupportedVersion(_) => StatusCode::UnsupportedVersion,
+            ServerError::Frame(_) | ServerError::MalformedMessage(_) => StatusCode::MalformedFrame,
+            ServerError::UnsupportedContent(_) => StatusCode::UnsupportedContent,
+            ServerError::TooManyConnections(_) => StatusCode::Busy,
+            ServerError::Io(_) | ServerError::Encryption(_) | ServerError::Timeout(_) => {
+                StatusCode::ServerFailed
+            }
+        }
+    }
+
+    /// Whether the client may still be listening for a response after this error.
+    pub(crate) fn is_reportable(&self) -> bool {
+        // Handshake errors are reported in plaintext while opening the connection
+        !matches!(
+            self,
+            ServerError::Io(_)
+                | ServerError::Frame(_)
+                | ServerError::UnsupportedVersion(_)
+                | ServerError::KeyExchange
+                | ServerError::Authentication
+                | ServerError::KdfMismatch(_)
+                | ServerError::TooManyConnections(_)
+                | ServerError::Timeout(_)
+        )
+    }
 }
@@ -0,0 +1,72 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::transport::{self, LocalAddress};
+
+/// Interval at which connections waiting for a message check if the server is shutting
+/// down.
+pub(super) const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Handle to shut down a running server, obtained with `Server::handle`.
+///
+/// Once shut down the server stops accepting connections. Connections being handled are
+/// closed once their current message is handled, and `Server::start` returns once they
+/// are all closed.
+#[derive(Debug, Clone, Default)]
+pub struct ServerHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    shutdown: AtomicBool,
+    /// Address the server is bound to, connected to to wake up the accept loop
+    address: Mutex<Option<LocalAddress>>,
+}
+
+impl ServerHandle {
+    /// Shut down the server, calling it several times has no effect.
+    pub fn shutdown(&self) {
+        if self.inner.shutdown.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        log::info!("Shutting down server");
+
+        // The accept loop checks the shutdown flag after each connection
+        let wake_up = match &*self.inner.address.lock().unwrap() {
+            None => return,
+            Some(LocalAddress::Tcp(address)) => {
+                TcpStream::connect_timeout(&wake_address(*address), Duration::from_secs(1))
+                    .map(drop)
+            }
+            Some(LocalAddress::Unix(path)) => transport::connect_unix(path).map(drop),
+        };
+        if let Err(e) = wake_up {
+            log::debug!("Failed to wake up the server: {e}");
+        }
+    }
+
+    /// Whether the server is shutting down.
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.shutdown.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn set_address(&self, address: LocalAddress) {
+        *self.inner.address.lock().unwrap() = Some(address);
+    }
+}
+
+/// Address to connect to to reach a server bound to `address`.
+fn wake_address(address: SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, address.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, address.port()).into(),
+        _ => address,
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use crate::{
+    backend::{BackendError, ClipboardBackend},
+    Message, Selection,
+};
+
+use super::{
+    connection::{Event, ExecEvent, PasteEvent, Response},
+    error::ServerError,
+};
+
+/// Owner of the clipboard, handling the events of the connections one at a time.
+///
+/// It is moved out of the [`Server`](super::Server) while the server runs, so that the
+/// async server can handle events on the blocking thread pool.
+pub(super) struct EventHandler<P>
+where
+    P: ClipboardBackend,
+{
+    pub clipboard_ctx: P,
+    pub exec_command: Option<String>,
+    pub allow_fetch: bool,
+    pub once: bool,
+    pub default_selection: Selection,
+    pub allowed_selections: Vec<Selection>,
+    /// Serving a session over stdin and stdout, which the exec command can't write to
+    pub stdio: bool,
+}
+
+impl<P> EventHandler<P>
+where
+    P: ClipboardBackend,
+{
+    /// Handle an event of any connection, the message saved to the clipboard in `once`
+    /// mode is stored in `pasted`.
+    pub(super) fn handle_event(
+        &mut self,
+        event: Event,
+        pasted: &mut Option<Message>,
+    ) -> Result<Response, ServerError> {
+        if pasted.is_some() {
+            return Err(ServerError::Forbidden(String::from(
+                "server only accepted a single message",
+            )));
+        }
+        if let Some(client) = event.client() {
+            log::info!("Handling event of {client}");
+        }
+
+        match event {
+            Event::PasteEvent(e) => {
+                let response = self.handle_paste_event(&e)?;
+                if self.once {
+                    *pasted = Some(e.message);
+                }
+                Ok(response)
+            }
+            Event::ExecEvent(e) => self.handle_exec_event(&e),
+            Event::FetchEvent(_) => self.handle_fetch_event(),
+        }
+    }
+
+    fn handle_paste_event(&mut self, event: &PasteEvent) -> Result<Response, ServerError> {
+        let mime_type = event.message.mime_type();
+        if !self.clipboard_ctx.supports(&mime_type) {
+            return Err(ServerError::UnsupportedContent(format!(
+                "server clipboard can't store '{mime_type}' content"
+            )));
+        }
+
+        let selection = self.selection(&event.message)?;
+        for target in selection.targets() {
+            match target {
+                Selection::Primary => self.clipboard_ctx.set_primary(&event.message),
+                _ => self.clipboard_ctx.set_contents(&event.message),
+            }
+            .map_err(|e| clipboard_error("failed to write to clipboard", e))?;
+        }
+
+        log::info!("New message saved to {selection:?}");
+        if let Err(e) = self.exec_command(&event.message) {
+            log::error!("Failed to execute custom command: {}", e);
+        };
+        Ok(Response::Ack(String::from("Message saved to clipboard")))
+    }
+
+    /// Selection a message is written to, if the client is allowed to.
+    fn selection(&self, message: &Message) -> Result<Selection, ServerError> {
+        let selection = match message.selection {
+            None => return Ok(self.default_selection),
+            Some(selection) => selection,
+        };
+
+        let allowed = self
+            .allowed_selections
+            .iter()
+            .flat_map(|s| s.targets())
+            .collect::<Vec<_>>();
+        if let Some(target) = selection.targets().iter().find(|t| !allowed.contains(t)) {
+            return Err(ServerError::Forbidden(format!(
+                "server does not allow writing to the {target:?} selection"
+            )));
+        }
+        if selection.targets().contains(&Selection::Primary)
+            && !self.clipboard_ctx.supports_primary()
+        {
+            return Err(ServerError::UnsupportedContent(String::from(
+                "server clipboard has no primary selection",
+            )));
+        }
+        Ok(selection)
+    }
+
+    fn handle_exec_event(&mut self, event: &ExecEvent) -> Result<Response, ServerError> {
+        log::info!("New message saved to clipboard");
+        self.exec_command(&event.message)
+            .map_err(|e| ServerError::Exec(e.to_string()))?;
+        Ok(Response::Ack(String::from("Command executed")))
+    }
+
+    fn handle_fetch_event(&mut self) -> Result<Response, ServerError> {
+        if !self.allow_fetch {
+            return Err(ServerError::Forbidden(String::from(
+                "server does not allow reading its clipboard",
+            )));
+        }
+
+        let message = self
+            .clipboard_ctx
+            .get_contents()
+            .map_err(|e| clipboard_error("failed to read clipboard", e))?;
+        log::info!("Clipboard content sent to client");
+        Ok(Response::Clipboard(message))
+    }
+
+    /// Run the custom command with the raw message content as its stdin, the declared
+    /// content type and charset are exposed as `COPIEPATE_CONTENT_TYPE` and
+    /// `COPIEPATE_CHARSET`.
+    fn exec_command(&self, message: &Message) -> Result<(), ServerError> {
+        let exec_command = match &self.exec_command {
+            None => return Ok(()),
+            Some(c) => c,
+        };
+
+        log::debug!("Executing command: {}", exec_command);
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(exec_command);
+        command.env("COPIEPATE_CONTENT_TYPE", message.mime_type());
+        if let Some(charset) = &message.charset {
+            command.env("COPIEPATE_CHARSET", charset);
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let mut child_stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| ServerError::Exec(String::from("failed to open command stdin")))?;
+
+        let payload = message.content.clone();
+        std::thread::spawn(move || {
+            // Commands may exit without reading their stdin
+            if let Err(e) = child_stdin
+                .write_all(&payload)
+                .and_then(|_| child_stdin.flush())
+            {
+                log::warn!("Failed to write the message to the command stdin: {}", e);
+            }
+        });
+
+        let output = child.wait_with_output()?;
+        if self.stdio {
+            std::io::stderr().write_all(&output.stdout)?;
+        } else {
+            std::io::stdout().write_all(&output.stdout)?;
+        }
+        std::io::stderr().write_all(&output.stderr)?;
+
+        std::io::stdout().flush()?;
+        std::io::stderr().flush()?;
+
+        // Empty stderr line to have a separation between stdout message and service messages
+        eprintln!();
+        Ok(())
+    }
+}
+
+fn clipboard_error(context: &str, error: BackendError) -> ServerError {
+    match error {
+        BackendError::Unsupported(reason) => ServerError::UnsupportedContent(reason),
+        e => ServerError::Clipboard(format!("{context}: {e}")),
+    }
+}
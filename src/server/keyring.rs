@@ -0,0 +1,305 @@
+use std::{fmt, time::SystemTime};
+
+use chacha20poly1305::Key;
+use thiserror::Error;
+
+use crate::{
+    handshake::{AuthMethod, MAX_KEY_ID_SIZE},
+    kdf::KdfParams,
+    ssh::{AuthorizedKeys, SshIdentity},
+    SecretKey, KEY_SIZE,
+};
+
+use super::error::ServerError;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum KeyringError {
+    #[error("Key ID '{0}' is used by several keys")]
+    DuplicateId(String),
+
+    #[error("Key ID '{0}' must be 1 to {MAX_KEY_ID_SIZE} bytes long")]
+    InvalidId(String),
+}
+
+/// Secret of a single client, used by clients that send its key ID.
+#[derive(Clone)]
+pub struct ClientKey {
+    /// ID sent by the client when opening a connection
+    pub id: String,
+    /// Name of the client, reported in its events
+    pub name: String,
+    /// Pre-shared key
+    key: SecretKey,
+    /// Key refused before this date
+    pub not_before: Option<SystemTime>,
+    /// Key refused after this date
+    pub not_after: Option<SystemTime>,
+}
+
+impl ClientKey {
+    pub fn new(id: &str, name: &str, key: &[u8]) -> Self {
+        Self {
+            id: String::from(id),
+            name: String::from(name),
+            key: crate::secret_key(key),
+            not_before: None,
+            not_after: None,
+        }
+    }
+
+    /// Whether the key may be used at `now`.
+    pub fn is_valid_at(&self, now: SystemTime) -> bool {
+        self.not_before.is_none_or(|date| now >= date)
+            && self.not_after.is_none_or(|date| now <= date)
+    }
+
+    pub fn identity(&self) -> ClientIdentity {
+        ClientIdentity {
+            key_id: self.id.clone(),
+            name: self.name.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientKey")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("key", &"<redacted>")
+            .field("not_before", &self.not_before)
+            .field("not_after", &self.not_after)
+            .finish()
+    }
+}
+
+/// Client that sent an event, known from the key it authenticated with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    pub key_id: String,
+    pub name: String,
+}
+
+impl fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (key {})", self.name, self.key_id)
+    }
+}
+
+/// Keys of the clients of a server, looked up by the key ID clients send when opening a
+/// connection.
+///
+/// Keys are rotated without downtime by adding the new key of a client with a
+/// validity window overlapping the one of its old key, until every machine uses it.
+#[derive(Debug, Clone, Default)]
+pub struct Keyring {
+    keys: Vec<ClientKey>,
+}
+
+impl Keyring {
+    /// Add a key, its ID must not be used by another key.
+    pub fn add(&mut self, key: ClientKey) -> Result<(), KeyringError> {
+        if key.id.is_empty() || key.id.len() > MAX_KEY_ID_SIZE {
+            return Err(KeyringError::InvalidId(key.id.clone()));
+        }
+        if self.keys.iter().any(|k| k.id == key.id) {
+            return Err(KeyringError::DuplicateId(key.id.clone()));
+        }
+        self.keys.push(key);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Key with the ID `id`, if it is valid at `now`. Unknown and expired keys fail
+    /// authentication.
+    pub fn get(&self, id: &str, now: SystemTime) -> Result<&ClientKey, ServerError> {
+        let key = self.keys.iter().find(|k| k.id == id).ok_or_else(|| {
+            log::error!("Client sent unknown key ID '{id}'");
+            ServerError::Authentication
+        })?;
+        if !key.is_valid_at(now) {
+            log::error!("Key '{id}' of {} is outside its validity window", key.name);
+            return Err(ServerError::Authentication);
+        }
+        Ok(key)
+    }
+}
+
+/// Pre-shared key of clients authenticating with an SSH key, their session key is derived
+/// from the key exchange only.
+static NO_KEY: [u8; KEY_SIZE] = [0; KEY_SIZE];
+
+/// Keys a server accepts: its default key, used by clients that don't send a key ID, the
+/// keys of its keyring, and the SSH keys of its authorized keys.
+pub(super) struct ServerKeys {
+    pub default: Option<SecretKey>,
+    /// Parameters the default key was derived from a passphrase with
+    pub kdf: Option<KdfParams>,
+    pub keyring: Keyring,
+    pub authorized_keys: AuthorizedKeys,
+    /// Key the server signs the handshake with for clients authenticating with an SSH key
+    pub host_key: Option<SshIdentity>,
+    /// Accept clients authenticating with a secret even though authorized keys are set
+    pub allow_secret: bool,
+}
+
+impl ServerKeys {
+    /// Pre-shared key a client authenticates with, and the identity of the client if it
+    /// sent a key ID. Clients authenticating with an SSH key have no pre-shared key,
+    /// they are identified by their signature. Once authorized keys are set, clients
+    /// authenticating with a secret are refused unless `allow_secret` is set.
+    pub fn select(
+        &self,
+        key_id: Option<&str>,
+        auth_method: AuthMethod,
+        kdf: Option<&KdfParams>,
+    ) -> Result<(&Key, Option<ClientIdentity>), ServerError> {
+        self.check_kdf(key_id, kdf)?;
+        if auth_method == AuthMethod::Ssh {
+            if self.authorized_keys.is_empty() || self.host_key.is_none() {
+                log::error!(
+                    "Client authenticates with an SSH key, the server has no authorized keys or no host key"
+                );
+                return Err(ServerError::Authentication);
+            }
+            return Ok((Key::from_slice(&NO_KEY), None));
+        }
+        if !self.authorized_keys.is_empty() && !self.allow_secret {
+            log::error!("Client authenticates with a secret, the server only accepts SSH keys");
+            return Err(ServerError::Authentication);
+        }
+
+        match key_id {
+            Some(id) => {
+                let key = self.keyring.get(id, SystemTime::now())?;
+                log::debug!("Client authenticates as {}", key.name);
+                Ok((Key::from_slice(&*key.key), Some(key.identity())))
+            }
+            None => match &self.default {
+                Some(key) => Ok((Key::from_slice(&**key), None)),
+                None => {
+                    log::error!("Client did not send a key ID, and the server has no default key");
+                    Err(ServerError::Authentication)
+                }
+            },
+        }
+    }
+
+    /// Clients deriving their key from a passphrase must use the parameters of the
+    /// default key. Clients that don't send parameters are left to fail authentication.
+    fn check_kdf(&self, key_id: Option<&str>, kdf: Option<&KdfParams>) -> Result<(), ServerError> {
+        let Some(client) = kdf else {
+            return Ok(());
+        };
+        match (key_id, &self.kdf) {
+            (None, Some(server)) if server == client => Ok(()),
+            (None, Some(server)) => Err(ServerError::KdfMismatch(format!(
+                "client key is derived with {client}, server key with {server}"
+            ))),
+            _ => Err(ServerError::KdfMismatch(String::from(
+                "client key is derived from a passphrase, server key is not",
+            ))),
+        }
+    }
+}
+
+impl fmt::Debug for ServerKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerKeys")
+            .field("default", &self.default.as_ref().map(|_| "<redacted>"))
+            .field("kdf", &self.kdf)
+            .field("keyring", &self.keyring)
+            .field("authorized_keys", &self.authorized_keys)
+            .field("host_key", &self.host_key)
+            .field("allow_secret", &self.allow_secret)
+            .finish()
+    }
+}
+
+impl TryFrom<Vec<ClientKey>> for Keyring {
+    type Error = KeyringError;
+
+    fn try_from(keys: Vec<ClientKey>) -> Result<Self, Self::Error> {
+        let mut keyring = Keyring::default();
+        for key in keys {
+            keyring.add(key)?;
+        }
+        Ok(keyring)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const TESTING_KEY: &[u8] = b"__WARNING_UNSECURE_KEY_TESTING__";
+
+    #[test]
+    fn test_duplicate_id() {
+        let mut keyring = Keyring::default();
+        keyring
+            .add(ClientKey::new("laptop", "Laptop", TESTING_KEY))
+            .unwrap();
+        assert_eq!(
+            Err(KeyringError::DuplicateId(String::from("laptop"))),
+            keyring.add(ClientKey::new("laptop", "Other laptop", TESTING_KEY))
+        );
+        assert_eq!(
+            Err(KeyringError::InvalidId(String::new())),
+            keyring.add(ClientKey::new("", "Unnamed", TESTING_KEY))
+        );
+    }
+
+    #[test]
+    fn test_validity_window() {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let mut old = ClientKey::new("laptop-1", "Laptop", TESTING_KEY);
+        old.not_after = Some(now + day);
+        let mut new = ClientKey::new("laptop-2", "Laptop", TESTING_KEY);
+        new.not_before = Some(now - day);
+        let keyring = Keyring::try_from(vec![old, new]).unwrap();
+
+        // Both keys are valid while their windows overlap
+        assert!(keyring.get("laptop-1", now).is_ok());
+        assert!(keyring.get("laptop-2", now).is_ok());
+
+        // Only the new key once the old one expired
+        let later = now + 2 * day;
+        assert!(matches!(
+            keyring.get("laptop-1", later),
+            Err(ServerError::Authentication)
+        ));
+        assert!(keyring.get("laptop-2", later).is_ok());
+
+        // Neither before the new one is valid
+        assert!(keyring.get("laptop-2", now - 2 * day).is_err());
+        assert!(keyring.get("unknown", now).is_err());
+    }
+
+    #[test]
+    fn test_debug_redacts_keys() {
+        let keys = ServerKeys {
+            default: Some(crate::secret_key(TESTING_KEY)),
+            kdf: None,
+            keyring: Keyring::try_from(vec![ClientKey::new("laptop", "Laptop", TESTING_KEY)])
+                .unwrap(),
+            authorized_keys: Default::default(),
+            host_key: None,
+            allow_secret: false,
+        };
+        let debug = format!("{keys:?}");
+        let key = format!("{TESTING_KEY:?}");
+        assert!(
+            !debug.contains(key.trim_matches(&['[', ']'][..])),
+            "{debug}"
+        );
+        assert_eq!(2, debug.matches("<redacted>").count(), "{debug}");
+        assert!(debug.contains("Laptop"), "{debug}");
+    }
+}
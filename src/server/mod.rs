@@ -1,146 +1,323 @@
 use std::{
-    io::{Read, Write},
-    net::TcpListener,
-    process::{Command, Stdio},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    thread,
 };
 
-use chacha20poly1305::Key;
-use chacha20poly1305::KeyInit;
-use clipboard::ClipboardProvider;
 use derive_builder::Builder;
 
-use crate::Cipher;
+use crate::{
+    backend::ClipboardBackend,
+    kdf::KdfParams,
+    ssh::{AuthorizedKeys, SshIdentity},
+    transport::{Listener, LocalAddress, Pipe, Stream},
+    FrameSizeType, Message, ProtocolVersionType, SecretKey, Selection, Timeouts,
+    DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_FRAME_SIZE, DEFAULT_MAX_PAYLOAD_SIZE,
+    MIN_PROTOCOL_VERSION,
+};
 
 use self::{
-    connection::{Connection, Event, ExecEvent, PasteEvent},
+    connection::ConnectionSettings,
+    handler::EventHandler,
+    keyring::ServerKeys,
+    worker::{Request, WorkerPool, WorkerSettings},
+};
+
+#[cfg(feature = "async")]
+pub use self::asynchronous::{AsyncConnection, AsyncServer};
+pub use self::{
+    connection::{Event, ExecEvent, FetchEvent, PasteEvent, Response},
     error::ServerError,
+    handle::ServerHandle,
+    keyring::{ClientIdentity, ClientKey, Keyring, KeyringError},
 };
 
+#[cfg(feature = "async")]
+mod asynchronous;
 mod connection;
 mod error;
+mod handle;
+mod handler;
+mod keyring;
+mod worker;
 
 /// Copiepate server.
+///
+/// Connections are handled by a pool of workers, the server owns the clipboard and
+/// handles their events one at a time.
 #[derive(Builder)]
-#[builder(pattern = "owned")]
-pub struct Server<'a, 'b, P>
+#[builder(pattern = "owned", build_fn(validate = "Self::validate"))]
+pub struct Server<'a, P>
 where
-    P: ClipboardProvider,
+    P: ClipboardBackend,
 {
     address: &'a str,
-    clipboard_ctx: &'b mut P,
 
-    #[builder(setter(name = "key", custom = true))]
-    cipher: Cipher,
+    /// Clipboard messages are written to, moved to the event handler while the server
+    /// runs
+    #[builder(setter(custom))]
+    clipboard_ctx: Option<P>,
+
+    /// Key of clients that don't send a key ID, wiped from memory once dropped
+    #[builder(setter(name = "key", custom = true), default)]
+    key: Option<SecretKey>,
+
+    /// Parameters `key` was derived from a passphrase with, clients deriving their key
+    /// with other parameters are rejected
+    #[builder(default)]
+    kdf: Option<KdfParams>,
+
+    /// Keys of clients sending their key ID
+    #[builder(default)]
+    keyring: Keyring,
+
+    /// SSH keys of clients authenticating with an SSH key, clients authenticating with a
+    /// secret are then refused unless `allow_secret` is set
+    #[builder(default)]
+    authorized_keys: AuthorizedKeys,
+
+    /// Key the server signs the handshake with for clients authenticating with an SSH
+    /// key, required with `authorized_keys`
+    #[builder(setter(into), default)]
+    host_key: Option<SshIdentity>,
+
+    /// Accept clients authenticating with a secret even though `authorized_keys` is set
+    #[builder(default)]
+    allow_secret: bool,
 
     #[builder(setter(into), default)]
     exec_command: Option<String>,
+
+    /// Allow clients to read the content of the clipboard
+    #[builder(default)]
+    allow_fetch: bool,
+
+    /// Maximum size of a frame read from a client, larger frames close the connection
+    #[builder(default = "DEFAULT_MAX_FRAME_SIZE")]
+    max_frame_size: FrameSizeType,
+
+    /// Maximum size of a message, larger messages are rejected
+    #[builder(default = "DEFAULT_MAX_PAYLOAD_SIZE")]
+    max_payload_size: usize,
+
+    /// Oldest protocol version accepted from clients, see
+    /// [`MIN_AUTHENTICATED_PROTOCOL_VERSION`](crate::MIN_AUTHENTICATED_PROTOCOL_VERSION)
+    #[builder(default = "MIN_PROTOCOL_VERSION")]
+    min_protocol_version: ProtocolVersionType,
+
+    /// Maximum number of connections handled at the same time, other connections are
+    /// refused
+    #[builder(default = "DEFAULT_MAX_CONNECTIONS")]
+    max_connections: usize,
+
+    /// Handshake, read and idle timeouts of connections, the connect timeout is not used
+    #[builder(default)]
+    timeouts: Timeouts,
+
+    /// Stop the server after the first message saved to the clipboard
+    #[builder(default)]
+    once: bool,
+
+    /// Selection of messages that don't name one
+    #[builder(default = "Selection::Clipboard")]
+    default_selection: Selection,
+
+    /// Selections clients may write to
+    #[builder(default = "vec![Selection::Clipboard, Selection::Primary]")]
+    allowed_selections: Vec<Selection>,
+
+    /// Listener bound with `Server::bind`, used by the next `Server::start`
+    #[builder(setter(skip))]
+    listener: Option<Listener>,
+
+    #[builder(setter(skip))]
+    handle: ServerHandle,
+
+    /// Serving a session over stdin and stdout, which the exec command can't write to
+    #[builder(setter(skip))]
+    stdio: bool,
 }
 
-impl<'a, 'b, P> ServerBuilder<'a, 'b, P>
+impl<'a, P> ServerBuilder<'a, P>
 where
-    P: ClipboardProvider,
+    P: ClipboardBackend,
 {
+    pub fn clipboard_ctx(mut self, value: P) -> Self {
+        self.clipboard_ctx = Some(Some(value));
+        self
+    }
+
     pub fn key(mut self, value: &[u8]) -> Self {
-        let key = Key::from_slice(value).to_owned();
-        let cipher = Cipher::new(&key);
-        self.cipher = Some(cipher);
+        self.key = Some(Some(crate::secret_key(value)));
         self
     }
+
+    fn validate(&self) -> Result<(), String> {
+        let has_key = matches!(self.key, Some(Some(_)));
+        let has_keyring = self.keyring.as_ref().is_some_and(|k| !k.is_empty());
+        let has_authorized_keys = self.authorized_keys.as_ref().is_some_and(|k| !k.is_empty());
+        if !has_key && !has_keyring && !has_authorized_keys {
+            return Err(String::from(
+                "`key`, `keyring` or `authorized_keys` must be set",
+            ));
+        }
+        if has_authorized_keys && !matches!(self.host_key, Some(Some(_))) {
+            return Err(String::from(
+                "`host_key` must be set with `authorized_keys`",
+            ));
+        }
+        Ok(())
+    }
 }
 
-impl<'a, 'b, P> Server<'a, 'b, P>
+impl<'a, P> Server<'a, P>
 where
-    P: ClipboardProvider,
+    P: ClipboardBackend,
 {
-    /// Start Copiepate server. Listen for ever.
-    pub fn start(&mut self) -> Result<(), ServerError> {
-        log::info!("Starting server {}", self.address);
-        let listener = TcpListener::bind(self.address)?;
-
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    self.handle_connection(stream);
-                }
-                Err(e) => {
-                    log::error!("Connection failed: {}", e);
-                }
-            }
+    /// Bind the server to its address without accepting connections yet, and return the
+    /// local address it is bound to, for instance the port picked for port 0.
+    pub fn bind(&mut self) -> Result<LocalAddress, ServerError> {
+        if self.listener.is_none() {
+            self.listener = Some(Listener::bind(self.address)?);
         }
+        let address = self.listener.as_ref().unwrap().local_address()?;
+        self.handle.set_address(address.clone());
+        Ok(address)
+    }
 
-        Ok(())
+    /// Handle to shut down the server from another thread.
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
     }
 
-    fn handle_connection<Stream>(&mut self, stream: Stream)
-    where
-        Stream: Sized + Read + Write,
-    {
-        let connection = Connection::new(stream, self.cipher.clone());
-        for paste_event in connection {
-            match paste_event {
-                Ok(Event::PasteEvent(e)) => self.handle_paste_event(&e),
-                Ok(Event::ExecEvent(e)) => self.handle_exec_event(&e),
-                Err(e) => {
-                    log::error!("Error handling connection: {e}");
+    /// Start Copiepate server. Listen until the server is shut down, or until the first
+    /// message saved to the clipboard in `once` mode, which is then returned.
+    pub fn start(&mut self) -> Result<Option<Message>, ServerError> {
+        let listener = self.listener()?;
+
+        let (requests, receiver) = mpsc::channel();
+        let pool = WorkerPool::new(self.max_connections, self.worker_settings(), requests);
+        let handle = self.handle.clone();
+        let accept_loop = thread::spawn(move || {
+            loop {
+                let stream = listener.accept();
+                if handle.is_shutdown() {
                     break;
                 }
+                match stream {
+                    Ok(stream) => pool.dispatch(stream),
+                    Err(e) => {
+                        log::error!("Connection failed: {}", e);
+                    }
+                }
             }
+            // Dropping the pool stops the workers once their connections are closed
+        });
+
+        let result = self.handle_requests(receiver);
+        // Closes the listener, so that its address can be bound again
+        if accept_loop.join().is_err() {
+            log::error!("Accept loop panicked");
         }
+        result
     }
 
-    fn handle_paste_event(&mut self, event: &PasteEvent) {
-        if let Err(e) = self.clipboard_ctx.set_contents(event.payload.clone()) {
-            log::error!("Failed to write to clipboard: {}", e);
-            return;
-        }
+    /// Serve a single session over stdin and stdout, for servers spawned by ssh or inetd
+    /// for each connection. Timeouts don't apply to the session.
+    pub fn serve_stdio(&mut self) -> Result<Option<Message>, ServerError> {
+        log::info!("Serving a session over stdin and stdout");
+        self.stdio = true;
 
-        log::info!("New message saved to clipboard");
-        if let Err(e) = self.exec_command(&event.payload) {
-            log::error!("Failed to execute custom command: {}", e);
-        };
+        let (requests, receiver) = mpsc::channel();
+        let settings = self.worker_settings();
+        thread::spawn(move || {
+            worker::handle_connection(Stream::Pipe(Pipe::stdio()), &settings, &requests)
+        });
+
+        self.handle_requests(receiver)
     }
 
-    fn handle_exec_event(&mut self, event: &ExecEvent) {
-        log::info!("New message saved to clipboard");
-        if let Err(e) = self.exec_command(&event.payload) {
-            log::error!("Failed to execute custom command: {}", e);
-        };
+    /// Handle the events of the connections until they are all closed, or until the
+    /// first message saved to the clipboard in `once` mode.
+    fn handle_requests(
+        &mut self,
+        receiver: Receiver<Request>,
+    ) -> Result<Option<Message>, ServerError> {
+        let mut handler = self.event_handler()?;
+        let mut pasted = None;
+        for request in receiver {
+            match request {
+                Request::Event(event, reply) => {
+                    let result = handler.handle_event(event, &mut pasted);
+                    if reply.send(result).is_err() {
+                        log::error!("Connection closed before its event was handled");
+                    }
+                }
+                Request::Done => {
+                    // Stop accepting connections, the server is not shut down otherwise
+                    self.handle.shutdown();
+                    self.restore_handler(handler);
+                    return Ok(pasted);
+                }
+            }
+        }
+
+        log::info!("Server stopped");
+        self.restore_handler(handler);
+        Ok(pasted)
     }
 
-    fn exec_command(&self, payload: &str) -> Result<(), ServerError> {
-        let exec_command = match &self.exec_command {
-            None => return Ok(()),
-            Some(c) => c,
-        };
-
-        log::debug!("Executing command: {}", exec_command);
-        let mut child = Command::new("sh")
-            .arg("-c")
-            .arg(exec_command)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
-
-        let mut child_stdin = child.stdin.take().expect("Failed to take child stdin");
-
-        let payload = payload.to_owned();
-        std::thread::spawn(move || {
-            child_stdin
-                .write_all(payload.as_bytes())
-                .expect("Failed to write to stdin");
-            child_stdin.flush().expect("Failed to flush stdin");
-        });
+    /// Move the clipboard to a handler of the events, it is given back to the server
+    /// once the server stops.
+    fn event_handler(&mut self) -> Result<EventHandler<P>, ServerError> {
+        let clipboard_ctx = self.clipboard_ctx.take().ok_or_else(|| {
+            ServerError::Clipboard(String::from(
+                "clipboard was lost by a previous run of the server",
+            ))
+        })?;
+        Ok(EventHandler {
+            clipboard_ctx,
+            exec_command: self.exec_command.clone(),
+            allow_fetch: self.allow_fetch,
+            once: self.once,
+            default_selection: self.default_selection,
+            allowed_selections: self.allowed_selections.clone(),
+            stdio: self.stdio,
+        })
+    }
 
-        let output = child.wait_with_output()?;
-        std::io::stdout().write_all(&output.stdout)?;
-        std::io::stderr().write_all(&output.stderr)?;
+    /// Give the clipboard of a handler back to the server.
+    fn restore_handler(&mut self, handler: EventHandler<P>) {
+        self.clipboard_ctx = Some(handler.clipboard_ctx);
+    }
 
-        std::io::stdout().flush()?;
-        std::io::stderr().flush()?;
+    /// Listener bound with `Server::bind`, or bound now.
+    fn listener(&mut self) -> Result<Listener, ServerError> {
+        let address = self.bind()?;
+        log::info!("Starting server {address}");
+        Ok(self.listener.take().expect("Server is bound"))
+    }
 
-        // Empty stderr line to have a separation between stdout message and service messages
-        eprintln!();
-        Ok(())
+    fn worker_settings(&self) -> WorkerSettings {
+        WorkerSettings {
+            keys: Arc::new(ServerKeys {
+                default: self.key.clone(),
+                kdf: self.kdf.clone(),
+                keyring: self.keyring.clone(),
+                authorized_keys: self.authorized_keys.clone(),
+                host_key: self.host_key.clone(),
+                allow_secret: self.allow_secret,
+            }),
+            connection: ConnectionSettings {
+                max_frame_size: self.max_frame_size,
+                max_payload_size: self.max_payload_size,
+                min_protocol_version: self.min_protocol_version,
+                timeouts: self.timeouts,
+                shutdown: self.handle.clone(),
+            },
+            once: self.once,
+        }
     }
 }
@@ -0,0 +1,211 @@
+use std::{
+    io::{Read, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use crate::{transport::Stream, Timeout};
+
+use super::{
+    connection::{self, Connection, ConnectionSettings, Event, Response},
+    error::ServerError,
+    keyring::ServerKeys,
+};
+
+/// Request of a worker to the owner of the clipboard.
+pub enum Request {
+    /// Handle an event, its result is sent back to the worker
+    Event(Event, Sender<Result<Response, ServerError>>),
+    /// A connection that saved a message in `once` mode was closed
+    Done,
+}
+
+/// Settings of the connections handled by the workers.
+#[derive(Debug, Clone)]
+pub struct WorkerSettings {
+    pub keys: Arc<ServerKeys>,
+    pub connection: ConnectionSettings,
+    /// Notify the owner once the connection that saved a message is closed
+    pub once: bool,
+}
+
+/// Fixed number of threads handling connections. Events are forwarded to the owner of
+/// the clipboard, which handles them one at a time.
+pub struct WorkerPool {
+    connections: Sender<Stream>,
+    /// Number of connections being handled
+    active: Arc<AtomicUsize>,
+    size: usize,
+}
+
+impl WorkerPool {
+    /// Start `size` workers, at least one.
+    pub fn new(size: usize, settings: WorkerSettings, requests: Sender<Request>) -> Self {
+        let size = size.max(1);
+        let (connections, queue) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+        let active = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..size {
+            let queue = queue.clone();
+            let active = active.clone();
+            let settings = settings.clone();
+            let requests = requests.clone();
+            thread::spawn(move || work(&queue, &active, &settings, &requests));
+        }
+
+        Self {
+            connections,
+            active,
+            size,
+        }
+    }
+
+    /// Hand a connection to an idle worker, refuse it if every worker is busy.
+    pub fn dispatch(&self, stream: Stream) {
+        // Connections are only dispatched from the accept loop, no other connection can
+        // be counted between the check and the increment.
+        if self.active.load(Ordering::SeqCst) >= self.size {
+            let error = ServerError::TooManyConnections(self.size);
+            log::warn!("Refusing connection: {error}");
+            if let Err(e) = connection::reject_connection(stream, &error) {
+                log::error!("Failed to send error to client: {e}");
+            }
+            return;
+        }
+
+        self.active.fetch_add(1, Ordering::SeqCst);
+        if self.connections.send(stream).is_err() {
+            log::error!("No worker left to handle the connection");
+            self.active.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+fn work(
+    queue: &Mutex<Receiver<Stream>>,
+    active: &AtomicUsize,
+    settings: &WorkerSettings,
+    requests: &Sender<Request>,
+) {
+    loop {
+        let stream = match queue.lock().unwrap().recv() {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+        handle_connection(stream, settings, requests);
+        active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Handle the events of a connection until it is closed.
+pub fn handle_connection<Stream>(
+    stream: Stream,
+    settings: &WorkerSettings,
+    requests: &Sender<Request>,
+) where
+    Stream: Sized + Read + Write + Timeout,
+{
+    let mut connection =
+        Connection::new(stream, settings.keys.clone(), settings.connection.clone());
+    let (reply, results) = mpsc::channel();
+    let mut pasted = false;
+
+    while let Some(event) = connection.next() {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                log::error!("Error handling connection: {e}");
+                // Handshake errors are already reported to the client
+                if connection.is_opened() && e.is_reportable() {
+                    if let Err(e) = connection.reject(&e) {
+                        log::error!("Failed to send error to client: {e}");
+                    }
+                }
+                break;
+            }
+        };
+
+        let is_paste = matches!(event, Event::PasteEvent(_));
+        let result = requests
+            .send(Request::Event(event, reply.clone()))
+            .ok()
+            .and_then(|_| results.recv().ok())
+            .unwrap_or_else(|| {
+                Err(ServerError::Io(std::io::Error::other(
+                    "server is shutting down",
+                )))
+            });
+        pasted |= is_paste && result.is_ok();
+
+        let response = match result {
+            Ok(response) => connection.respond(response),
+            Err(e) => {
+                log::error!("{e}");
+                connection.reject(&e)
+            }
+        };
+        if let Err(e) = response {
+            log::error!("Failed to respond to client: {e}");
+            break;
+        }
+    }
+
+    if pasted && settings.once {
+        // The owner may already be gone
+        let _ = requests.send(Request::Done);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::{
+        server::handle::ServerHandle, NetFrame, NetFrameType, Timeouts, DEFAULT_MAX_FRAME_SIZE,
+        DEFAULT_MAX_PAYLOAD_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    };
+
+    #[test]
+    fn test_handshake_error_reported_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let settings = WorkerSettings {
+            keys: Arc::new(ServerKeys {
+                default: Some(Default::default()),
+                kdf: None,
+                keyring: Default::default(),
+                authorized_keys: Default::default(),
+                host_key: None,
+                allow_secret: false,
+            }),
+            connection: ConnectionSettings {
+                max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+                max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+                min_protocol_version: MIN_PROTOCOL_VERSION,
+                timeouts: Timeouts::default(),
+                shutdown: ServerHandle::default(),
+            },
+            once: false,
+        };
+
+        // The server is not pairing, the client is turned away while opening the connection
+        let pair = NetFrame::new(PROTOCOL_VERSION, NetFrameType::Pair, vec![0; 32]);
+        client.write_all(&pair.to_net()).unwrap();
+        let (requests, _receiver) = mpsc::channel();
+        handle_connection(stream, &settings, &requests);
+
+        let mut bytes = Vec::new();
+        client.read_to_end(&mut bytes).unwrap();
+        let mut reader = bytes.as_slice();
+        let frame = NetFrame::from_net(&mut reader, DEFAULT_MAX_FRAME_SIZE).unwrap();
+        assert_eq!(NetFrameType::Error, frame.frame_type);
+        assert!(reader.is_empty(), "Unexpected bytes after the error frame");
+    }
+}
@@ -0,0 +1,522 @@
+//! SSH ed25519 keys clients authenticate with instead of a shared secret: the client
+//! signs the handshake with its private key, read from an OpenSSH private key file or
+//! held by ssh-agent, and the server looks its public key up in an `authorized_keys`
+//! file. The server signs the handshake with its host key in turn, which the client looks
+//! up in a `known_hosts` file.
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use signature::{Signer, Verifier};
+use ssh_key::{known_hosts::HostPatterns, Algorithm, HashAlg, PrivateKey, PublicKey, Signature};
+use thiserror::Error;
+
+/// Environment variable of the ssh-agent socket.
+pub const SSH_AUTH_SOCK: &str = "SSH_AUTH_SOCK";
+
+/// Maximum size of an ssh-agent message.
+const MAX_AGENT_MESSAGE_SIZE: usize = 256 * 1024;
+
+// ssh-agent protocol messages, see draft-miller-ssh-agent
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+
+#[derive(Error, Debug)]
+pub enum SshError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid SSH key: {0}")]
+    Key(#[from] ssh_key::Error),
+
+    #[error("Unsupported SSH key: {0}")]
+    Unsupported(String),
+
+    #[error("Invalid signature: {0}")]
+    Signature(#[from] signature::Error),
+
+    #[error("Key {0} is not authorized")]
+    Unauthorized(String),
+
+    #[error("Host key {0} is not known")]
+    UnknownHost(String),
+
+    #[error("Malformed SSH message")]
+    Malformed,
+
+    #[error("ssh-agent error: {0}")]
+    Agent(String),
+}
+
+/// Private key a client authenticates with, or the host key of a server.
+#[derive(Debug, Clone)]
+pub enum SshIdentity {
+    /// Key read from an unencrypted OpenSSH private key file
+    Key(PrivateKey),
+    /// Key held by the ssh-agent listening on `socket`
+    Agent {
+        socket: PathBuf,
+        public_key: PublicKey,
+    },
+}
+
+impl SshIdentity {
+    /// Read an OpenSSH private key file. Encrypted keys are used through ssh-agent,
+    /// which must hold them.
+    pub fn from_file(path: &Path) -> Result<Self, SshError> {
+        let private_key = PrivateKey::read_openssh_file(path)?;
+        check_algorithm(private_key.algorithm())?;
+        if private_key.is_encrypted() {
+            log::debug!("{path:?} is encrypted, signing with ssh-agent");
+            return Self::from_agent(Some(private_key.public_key()));
+        }
+        Ok(SshIdentity::Key(private_key))
+    }
+
+    /// Key held by the ssh-agent of `SSH_AUTH_SOCK`: `public_key`, or the first ed25519
+    /// key of the agent.
+    pub fn from_agent(public_key: Option<&PublicKey>) -> Result<Self, SshError> {
+        let socket = std::env::var_os(SSH_AUTH_SOCK)
+            .map(PathBuf::from)
+            .ok_or_else(|| SshError::Agent(format!("{SSH_AUTH_SOCK} is not set")))?;
+        let identities = agent_identities(&socket)?;
+        let public_key = identities
+            .into_iter()
+            .find(|key| match public_key {
+                Some(public_key) => key.key_data() == public_key.key_data(),
+                None => key.algorithm() == Algorithm::Ed25519,
+            })
+            .ok_or_else(|| {
+                SshError::Agent(String::from("the agent does not hold the ed25519 key"))
+            })?;
+        Ok(SshIdentity::Agent { socket, public_key })
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        match self {
+            SshIdentity::Key(private_key) => private_key.public_key(),
+            SshIdentity::Agent { public_key, .. } => public_key,
+        }
+    }
+
+    /// Sign `data`, returns the payload of an Auth frame: the public key followed by the
+    /// signature, both in the SSH wire format.
+    pub(crate) fn sign(&self, data: &[u8]) -> Result<Vec<u8>, SshError> {
+        let signature = match self {
+            SshIdentity::Key(private_key) => Vec::try_from(private_key.try_sign(data)?)?,
+            SshIdentity::Agent { socket, public_key } => agent_sign(socket, public_key, data)?,
+        };
+        let mut payload = Vec::new();
+        write_string(&mut payload, &self.public_key().to_bytes()?);
+        write_string(&mut payload, &signature);
+        Ok(payload)
+    }
+}
+
+/// Keys of the clients allowed to authenticate with SSH, as listed in an
+/// `authorized_keys` file. Options are ignored, key comments name the clients.
+#[derive(Debug, Clone, Default)]
+pub struct AuthorizedKeys {
+    keys: Vec<PublicKey>,
+}
+
+impl AuthorizedKeys {
+    pub fn read_file(path: &Path) -> Result<Self, SshError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Check the payload of a client Auth frame, returns the public key that signed
+    /// `data`.
+    pub(crate) fn verify(&self, payload: &[u8], data: &[u8]) -> Result<&PublicKey, SshError> {
+        let (public_key, signature) = read_signed(payload)?;
+        let authorized_key = self
+            .keys
+            .iter()
+            .find(|key| key.key_data() == public_key.key_data())
+            .ok_or_else(|| {
+                SshError::Unauthorized(public_key.fingerprint(HashAlg::Sha256).to_string())
+            })?;
+        Verifier::verify(authorized_key, data, &signature)?;
+        Ok(authorized_key)
+    }
+}
+
+impl FromStr for AuthorizedKeys {
+    type Err = SshError;
+
+    fn from_str(authorized_keys: &str) -> Result<Self, Self::Err> {
+        let mut keys = Vec::new();
+        for entry in ssh_key::AuthorizedKeys::new(authorized_keys) {
+            let key = entry?.public_key().clone();
+            if key.algorithm() != Algorithm::Ed25519 {
+                log::warn!(
+                    "Ignoring {} authorized key {}",
+                    key.algorithm(),
+                    key.comment()
+                );
+                continue;
+            }
+            keys.push(key);
+        }
+        Ok(Self { keys })
+    }
+}
+
+/// Host keys of the servers clients authenticating with SSH trust, as listed in a
+/// `known_hosts` file: `<host patterns> ssh-ed25519 <key> [comment]`. Host patterns are
+/// matched against the address the client connects to, such as `127.0.0.1:2323`, and
+/// may use the `*` and `?` wildcards and `!` negations. Hashed host names and markers are
+/// not supported, their entries are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct KnownHosts {
+    entries: Vec<(Vec<String>, PublicKey)>,
+}
+
+impl KnownHosts {
+    pub fn read_file(path: &Path) -> Result<Self, SshError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Check the payload of a server Auth frame, returns the host key of `address` that
+    /// signed `data`.
+    pub(crate) fn verify(
+        &self,
+        address: &str,
+        payload: &[u8],
+        data: &[u8],
+    ) -> Result<&PublicKey, SshError> {
+        let (public_key, signature) = read_signed(payload)?;
+        let host_key = self
+            .entries
+            .iter()
+            .filter(|(patterns, _)| matches_host(patterns, address))
+            .map(|(_, key)| key)
+            .find(|key| key.key_data() == public_key.key_data())
+            .ok_or_else(|| {
+                SshError::UnknownHost(format!(
+                    "{} of {address}",
+                    public_key.fingerprint(HashAlg::Sha256)
+                ))
+            })?;
+        Verifier::verify(host_key, data, &signature)?;
+        Ok(host_key)
+    }
+}
+
+impl FromStr for KnownHosts {
+    type Err = SshError;
+
+    fn from_str(known_hosts: &str) -> Result<Self, Self::Err> {
+        let mut entries = Vec::new();
+        for entry in ssh_key::KnownHosts::new(known_hosts) {
+            let entry = entry?;
+            let key = entry.public_key();
+            let patterns = match entry.host_patterns() {
+                HostPatterns::Patterns(patterns) if entry.marker().is_none() => patterns,
+                _ => {
+                    log::warn!("Ignoring hashed or marked known host {}", key.comment());
+                    continue;
+                }
+            };
+            if key.algorithm() != Algorithm::Ed25519 {
+                log::warn!(
+                    "Ignoring {} known host key {}",
+                    key.algorithm(),
+                    key.comment()
+                );
+                continue;
+            }
+            entries.push((patterns.clone(), key.clone()));
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// Whether `address` matches the host patterns of a known_hosts entry: one of its
+/// patterns matches, and none of its negated patterns does.
+fn matches_host(patterns: &[String], address: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        match pattern.strip_prefix('!') {
+            Some(negated) if matches_glob(negated.as_bytes(), address.as_bytes()) => return false,
+            Some(_) => (),
+            None => matched |= matches_glob(pattern.as_bytes(), address.as_bytes()),
+        }
+    }
+    matched
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any sequence and `?` any byte.
+fn matches_glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|start| matches_glob(rest, &text[start..])),
+        Some((b'?', rest)) => !text.is_empty() && matches_glob(rest, &text[1..]),
+        Some((byte, rest)) => text.first() == Some(byte) && matches_glob(rest, &text[1..]),
+    }
+}
+
+/// Public key and signature of an Auth frame payload.
+fn read_signed(payload: &[u8]) -> Result<(PublicKey, Signature), SshError> {
+    let mut reader = payload;
+    let public_key = PublicKey::from_bytes(read_string(&mut reader)?)?;
+    let signature = Signature::try_from(read_string(&mut reader)?)?;
+    Ok((public_key, signature))
+}
+
+fn check_algorithm(algorithm: Algorithm) -> Result<(), SshError> {
+    if algorithm != Algorithm::Ed25519 {
+        return Err(SshError::Unsupported(format!(
+            "{algorithm} keys are not supported, use an ed25519 key"
+        )));
+    }
+    Ok(())
+}
+
+fn write_string(bytes: &mut Vec<u8>, value: &[u8]) {
+    bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(value);
+}
+
+fn read_u32(bytes: &mut &[u8]) -> Result<u32, SshError> {
+    let value = bytes.get(..4).ok_or(SshError::Malformed)?;
+    *bytes = &bytes[4..];
+    Ok(u32::from_be_bytes(
+        value.try_into().expect("Slice with incorrect length"),
+    ))
+}
+
+fn read_string<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8], SshError> {
+    let size = read_u32(bytes)? as usize;
+    let value = bytes.get(..size).ok_or(SshError::Malformed)?;
+    *bytes = &bytes[size..];
+    Ok(value)
+}
+
+fn agent_identities(socket: &Path) -> Result<Vec<PublicKey>, SshError> {
+    let response = agent_request(socket, SSH_AGENTC_REQUEST_IDENTITIES, &[])?;
+    let mut reader = expect_response(&response, SSH_AGENT_IDENTITIES_ANSWER)?;
+    let count = read_u32(&mut reader)?;
+
+    let mut keys = Vec::new();
+    for _ in 0..count {
+        let key = read_string(&mut reader)?;
+        let comment = read_string(&mut reader)?;
+        // Keys unknown to ssh-key, such as certificates, can't be used
+        if let Ok(mut key) = PublicKey::from_bytes(key) {
+            key.set_comment(String::from_utf8_lossy(comment));
+            keys.push(key);
+        }
+    }
+    Ok(keys)
+}
+
+fn agent_sign(socket: &Path, public_key: &PublicKey, data: &[u8]) -> Result<Vec<u8>, SshError> {
+    let mut request = Vec::new();
+    write_string(&mut request, &public_key.to_bytes()?);
+    write_string(&mut request, data);
+    request.extend_from_slice(&0u32.to_be_bytes());
+
+    let response = agent_request(socket, SSH_AGENTC_SIGN_REQUEST, &request)?;
+    let mut reader = expect_response(&response, SSH_AGENT_SIGN_RESPONSE)?;
+    Ok(read_string(&mut reader)?.to_vec())
+}
+
+/// Content of an agent response of type `expected`.
+fn expect_response(response: &[u8], expected: u8) -> Result<&[u8], SshError> {
+    match response.split_first() {
+        Some((&message_type, content)) if message_type == expected => Ok(content),
+        Some((&SSH_AGENT_FAILURE, _)) => Err(SshError::Agent(String::from("request refused"))),
+        _ => Err(SshError::Agent(String::from("unexpected response"))),
+    }
+}
+
+/// Send a request to the agent and read its response, messages are preceded by their
+/// size.
+#[cfg(unix)]
+fn agent_request(socket: &Path, message_type: u8, content: &[u8]) -> Result<Vec<u8>, SshError> {
+    let mut stream = UnixStream::connect(socket)?;
+    let mut request = Vec::with_capacity(5 + content.len());
+    request.extend_from_slice(&(content.len() as u32 + 1).to_be_bytes());
+    request.push(message_type);
+    request.extend_from_slice(content);
+    stream.write_all(&request)?;
+
+    let mut size = [0; 4];
+    stream.read_exact(&mut size)?;
+    let size = u32::from_be_bytes(size) as usize;
+    if size > MAX_AGENT_MESSAGE_SIZE {
+        return Err(SshError::Agent(format!(
+            "response of {size} bytes is too large"
+        )));
+    }
+    let mut response = vec![0; size];
+    stream.read_exact(&mut response)?;
+    Ok(response)
+}
+
+#[cfg(not(unix))]
+fn agent_request(_socket: &Path, _message_type: u8, _content: &[u8]) -> Result<Vec<u8>, SshError> {
+    Err(SshError::Agent(String::from(
+        "ssh-agent is only supported on Unix",
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use ssh_key::private::Ed25519Keypair;
+
+    use super::*;
+
+    fn private_key(seed: u8, comment: &str) -> PrivateKey {
+        let mut key = PrivateKey::from(Ed25519Keypair::from_seed(&[seed; 32]));
+        key.set_comment(comment);
+        key
+    }
+
+    fn authorized_keys(keys: &[&PrivateKey]) -> AuthorizedKeys {
+        keys.iter()
+            .map(|key| key.public_key().to_openssh().unwrap())
+            .collect::<Vec<_>>()
+            .join("\n")
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_authorized_key() {
+        let laptop = private_key(1, "laptop");
+        let authorized_keys = authorized_keys(&[&private_key(2, "desktop"), &laptop]);
+
+        let payload = SshIdentity::Key(laptop).sign(b"challenge").unwrap();
+        let key = authorized_keys.verify(&payload, b"challenge").unwrap();
+        assert_eq!("laptop", key.comment());
+
+        // The signature is bound to the challenge
+        assert!(matches!(
+            authorized_keys.verify(&payload, b"other challenge"),
+            Err(SshError::Signature(_))
+        ));
+        assert!(matches!(
+            authorized_keys.verify(&payload[..payload.len() - 1], b"challenge"),
+            Err(SshError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn test_unauthorized_key() {
+        let authorized_keys = authorized_keys(&[&private_key(2, "desktop")]);
+        let payload = SshIdentity::Key(private_key(3, "intruder"))
+            .sign(b"challenge")
+            .unwrap();
+        assert!(matches!(
+            authorized_keys.verify(&payload, b"challenge"),
+            Err(SshError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn test_known_host() {
+        let server = private_key(1, "server");
+        let known_hosts: KnownHosts = format!(
+            "127.0.0.1:*,!127.0.0.1:22 {}\nother {}",
+            server.public_key().to_openssh().unwrap(),
+            private_key(2, "other").public_key().to_openssh().unwrap()
+        )
+        .parse()
+        .unwrap();
+
+        let payload = SshIdentity::Key(server).sign(b"challenge").unwrap();
+        let key = known_hosts
+            .verify("127.0.0.1:2323", &payload, b"challenge")
+            .unwrap();
+        assert_eq!("server", key.comment());
+        assert!(matches!(
+            known_hosts.verify("127.0.0.1:2323", &payload, b"other challenge"),
+            Err(SshError::Signature(_))
+        ));
+
+        // The key is only trusted for the hosts it is listed for
+        for address in ["127.0.0.1:22", "other", "localhost:2323"] {
+            assert!(matches!(
+                known_hosts.verify(address, &payload, b"challenge"),
+                Err(SshError::UnknownHost(_))
+            ));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_agent() {
+        use std::os::unix::net::UnixListener;
+
+        let laptop = private_key(1, "laptop");
+        let socket = std::env::temp_dir().join(format!("copiepate-agent-{}", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+
+        // 1. Fake agent holding the key, answering a single request per connection
+        let agent_key = laptop.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut size = [0; 4];
+                stream.read_exact(&mut size).unwrap();
+                let mut request = vec![0; u32::from_be_bytes(size) as usize];
+                stream.read_exact(&mut request).unwrap();
+
+                let mut response = Vec::new();
+                match request[0] {
+                    SSH_AGENTC_REQUEST_IDENTITIES => {
+                        response.push(SSH_AGENT_IDENTITIES_ANSWER);
+                        response.extend_from_slice(&1u32.to_be_bytes());
+                        write_string(&mut response, &agent_key.public_key().to_bytes().unwrap());
+                        write_string(&mut response, b"laptop");
+                    }
+                    SSH_AGENTC_SIGN_REQUEST => {
+                        let mut reader = &request[1..];
+                        read_string(&mut reader).unwrap();
+                        let data = read_string(&mut reader).unwrap();
+                        let signature = agent_key.try_sign(data).unwrap();
+                        response.push(SSH_AGENT_SIGN_RESPONSE);
+                        write_string(&mut response, &Vec::try_from(signature).unwrap());
+                    }
+                    _ => response.push(SSH_AGENT_FAILURE),
+                }
+                stream
+                    .write_all(&(response.len() as u32).to_be_bytes())
+                    .unwrap();
+                stream.write_all(&response).unwrap();
+            }
+        });
+
+        // 2. Sign through the agent
+        let public_key = agent_identities(&socket).unwrap().remove(0);
+        let identity = SshIdentity::Agent {
+            socket: socket.clone(),
+            public_key,
+        };
+        let payload = identity.sign(b"challenge").unwrap();
+        let authorized_keys = authorized_keys(&[&laptop]);
+        let key = authorized_keys.verify(&payload, b"challenge").unwrap();
+        assert_eq!(laptop.public_key().key_data(), key.key_data());
+
+        std::fs::remove_file(&socket).unwrap();
+    }
+}
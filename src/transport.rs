@@ -0,0 +1,582 @@
+//! Streams clients and servers communicate over: TCP, Unix domain sockets for addresses
+//! starting with `unix:`, or the stdin and stdout of a process.
+use std::{
+    fmt,
+    io::{Error, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{
+        fs::{FileTypeExt, MetadataExt},
+        net::{UnixListener, UnixStream},
+    },
+    sync::{Mutex, PoisonError},
+};
+
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::Timeout;
+
+/// Prefix of Unix domain socket addresses, as in `unix:/run/user/1000/copiepate.sock`.
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// Path of the Unix domain socket of an address, `None` for TCP addresses.
+pub fn unix_socket_path(address: &str) -> Option<&Path> {
+    address.strip_prefix(UNIX_PREFIX).map(Path::new)
+}
+
+/// Prefix of command transports, as in `command:ssh laptop copiepate --stdio`.
+pub const COMMAND_PREFIX: &str = "command:";
+
+/// Time a command transport is given to exit once its stdin is closed, before it is
+/// killed.
+const COMMAND_EXIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How a client reaches the server.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// Connect to the server address
+    #[default]
+    Socket,
+    /// Speak to a server through the stdin and stdout of a shell command, for instance
+    /// `ssh laptop copiepate --stdio`. Timeouts don't apply to commands.
+    Command(String),
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(transport: &str) -> Result<Self, Self::Err> {
+        if transport == "socket" {
+            return Ok(Transport::Socket);
+        }
+        match transport.strip_prefix(COMMAND_PREFIX) {
+            Some(command) if !command.trim().is_empty() => {
+                Ok(Transport::Command(command.to_owned()))
+            }
+            _ => Err(format!(
+                "unknown transport '{transport}', expected socket or {COMMAND_PREFIX}<command>"
+            )),
+        }
+    }
+}
+
+/// Address a server is bound to. Formatted as an address clients can connect to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocalAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for LocalAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalAddress::Tcp(address) => write!(f, "{address}"),
+            LocalAddress::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+/// Connected stream.
+#[derive(Debug)]
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Pipe(Pipe),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::Pipe(pipe) => pipe.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::Pipe(pipe) => pipe.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+            Stream::Pipe(pipe) => pipe.flush(),
+        }
+    }
+}
+
+impl Timeout for Stream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+            // Pipes can't time out
+            Stream::Pipe(_) => Ok(()),
+        }
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+            // Pipes can't time out
+            Stream::Pipe(_) => Ok(()),
+        }
+    }
+}
+
+/// Stdin and stdout of the current process, or of a command.
+pub(crate) struct Pipe {
+    reader: Box<dyn Read + Send>,
+    /// Closed before waiting for the command to exit
+    writer: Option<Box<dyn Write + Send>>,
+    child: Option<Child>,
+}
+
+impl Pipe {
+    /// Read from stdin and write to stdout.
+    pub fn stdio() -> Self {
+        Self {
+            reader: Box::new(std::io::stdin()),
+            writer: Some(Box::new(std::io::stdout())),
+            child: None,
+        }
+    }
+
+    /// Spawn a shell command, writing to its stdin and reading from its stdout.
+    pub fn spawn(command: &str) -> Result<Self, Error> {
+        log::debug!("Spawning transport command: {command}");
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let reader = child.stdout.take().expect("Failed to take child stdout");
+        let writer = child.stdin.take().expect("Failed to take child stdin");
+        Ok(Self {
+            reader: Box::new(reader),
+            writer: Some(Box::new(writer)),
+            child: Some(child),
+        })
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.reader.read(buf)
+    }
+
+    /// Written bytes are flushed right away, stdout is line buffered.
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| Error::from(ErrorKind::BrokenPipe))?;
+        let written = writer.write(buf)?;
+        writer.flush()?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match &mut self.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for Pipe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pipe")
+            .field("child", &self.child)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Pipe {
+    /// Close the stdin of the command and wait for it to exit, kill it if it does not.
+    fn drop(&mut self) {
+        self.writer = None;
+        let mut child = match self.child.take() {
+            None => return,
+            Some(child) => child,
+        };
+
+        let started = Instant::now();
+        while started.elapsed() < COMMAND_EXIT_TIMEOUT {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    log::debug!("Transport command exited with {status}");
+                    return;
+                }
+                Ok(None) => thread::sleep(Duration::from_millis(10)),
+                Err(e) => {
+                    log::warn!("Failed to wait for transport command: {e}");
+                    break;
+                }
+            }
+        }
+        log::debug!("Killing transport command");
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// Listening socket of a server.
+#[derive(Debug)]
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, SocketFile),
+}
+
+impl Listener {
+    /// Bind to a TCP address, or create a Unix domain socket only the current user can
+    /// connect to.
+    pub fn bind(address: &str) -> Result<Self, Error> {
+        match unix_socket_path(address) {
+            None => Ok(Listener::Tcp(TcpListener::bind(address)?)),
+            Some(path) => bind_unix(path),
+        }
+    }
+
+    pub fn local_address(&self) -> Result<LocalAddress, Error> {
+        match self {
+            Listener::Tcp(listener) => Ok(LocalAddress::Tcp(listener.local_addr()?)),
+            #[cfg(unix)]
+            Listener::Unix(_, file) => Ok(LocalAddress::Unix(file.0.clone())),
+        }
+    }
+
+    pub fn accept(&self) -> Result<Stream, Error> {
+        match self {
+            Listener::Tcp(listener) => Ok(Stream::Tcp(listener.accept()?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => Ok(Stream::Unix(listener.accept()?.0)),
+        }
+    }
+}
+
+/// Socket file of a Unix listener, removed once the listener is closed.
+#[cfg(unix)]
+#[derive(Debug)]
+pub(crate) struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            log::warn!("Failed to remove socket {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> Result<Listener, Error> {
+    check_parent(path)?;
+
+    // Replace the socket left by a server that did not stop cleanly
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::new(
+                ErrorKind::AddrInUse,
+                format!("a server is already listening on {}", path.display()),
+            ));
+        }
+        log::debug!("Removing stale socket {}", path.display());
+        fs::remove_file(path)?;
+    }
+
+    let listener = bind_private(path)?;
+    Ok(Listener::Unix(listener, SocketFile(path.to_owned())))
+}
+
+/// Bind a socket only the current user can connect to. The socket is created with the
+/// permissions left by the umask, which is restricted while binding rather than changing
+/// the permissions afterwards, when other users could already have connected.
+#[cfg(unix)]
+fn bind_private(path: &Path) -> Result<UnixListener, Error> {
+    // The umask is shared by every thread of the process
+    static UMASK: Mutex<()> = Mutex::new(());
+    let _guard = UMASK.lock().unwrap_or_else(PoisonError::into_inner);
+    // SAFETY: umask never fails and only changes the umask, restored right after
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    listener
+}
+
+/// Refuse directories other users can write to, where they could replace the socket.
+#[cfg(unix)]
+fn check_parent(path: &Path) -> Result<(), Error> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if fs::metadata(parent)?.mode() & 0o022 != 0 {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "{} is writable by other users, put the socket in a private directory",
+                parent.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &Path) -> Result<Listener, Error> {
+    Err(unix_unsupported())
+}
+
+/// Connect to the Unix domain socket of a server, if it belongs to the current user.
+#[cfg(unix)]
+pub(crate) fn connect_unix(path: &Path) -> Result<Stream, Error> {
+    check_owner(path)?;
+    Ok(Stream::Unix(UnixStream::connect(path)?))
+}
+
+/// Refuse sockets created by other users, which could impersonate the server.
+#[cfg(unix)]
+fn check_owner(path: &Path) -> Result<(), Error> {
+    let metadata = fs::metadata(path)?;
+    // SAFETY: geteuid never fails and has no side effect
+    let uid = unsafe { libc::geteuid() };
+    if metadata.uid() != uid {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{} belongs to another user", path.display()),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn connect_unix(_path: &Path) -> Result<Stream, Error> {
+    Err(unix_unsupported())
+}
+
+/// Connected async socket.
+#[cfg(feature = "async")]
+pub(crate) trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+#[cfg(feature = "async")]
+impl<T> AsyncStream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// Listening socket of an async server.
+#[cfg(feature = "async")]
+pub(crate) enum AsyncListener {
+    Tcp(tokio::net::TcpListener),
+    /// The socket file is kept to remove it once the listener is closed
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, #[allow(dead_code)] SocketFile),
+}
+
+#[cfg(feature = "async")]
+impl AsyncListener {
+    /// Register a listener bound with `Listener::bind` on the tokio runtime.
+    pub fn from_std(listener: Listener) -> Result<Self, Error> {
+        match listener {
+            Listener::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                Ok(AsyncListener::Tcp(tokio::net::TcpListener::from_std(
+                    listener,
+                )?))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, file) => {
+                listener.set_nonblocking(true)?;
+                Ok(AsyncListener::Unix(
+                    tokio::net::UnixListener::from_std(listener)?,
+                    file,
+                ))
+            }
+        }
+    }
+
+    pub async fn accept(&self) -> Result<Box<dyn AsyncStream>, Error> {
+        match self {
+            AsyncListener::Tcp(listener) => Ok(Box::new(listener.accept().await?.0)),
+            #[cfg(unix)]
+            AsyncListener::Unix(listener, _) => Ok(Box::new(listener.accept().await?.0)),
+        }
+    }
+}
+
+/// Spawn a shell command, writing to its stdin and reading from its stdout.
+#[cfg(feature = "async")]
+pub(crate) fn spawn_async(command: &str) -> Result<Box<dyn AsyncStream>, Error> {
+    log::debug!("Spawning transport command: {command}");
+    let mut child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let reader = child.stdout.take().expect("Failed to take child stdout");
+    let writer = child.stdin.take().expect("Failed to take child stdin");
+    // The command exits once its stdin is closed, it is then reaped by tokio
+    Ok(Box::new(tokio::io::join(reader, writer)))
+}
+
+/// Connect to a server over TCP, or to its Unix domain socket if it belongs to the
+/// current user.
+#[cfg(feature = "async")]
+pub(crate) async fn connect_async(address: &str) -> Result<Box<dyn AsyncStream>, Error> {
+    match unix_socket_path(address) {
+        None => Ok(Box::new(tokio::net::TcpStream::connect(address).await?)),
+        #[cfg(unix)]
+        Some(path) => {
+            check_owner(path)?;
+            Ok(Box::new(tokio::net::UnixStream::connect(path).await?))
+        }
+        #[cfg(not(unix))]
+        Some(_) => Err(unix_unsupported()),
+    }
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    )
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    /// Path of a socket in a directory only the current user can write to.
+    fn socket_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("copiepate-{name}-{}", std::process::id()));
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .unwrap();
+        dir.join("copiepate.sock")
+    }
+
+    #[test]
+    fn test_parse_transport() {
+        assert_eq!(Ok(Transport::Socket), "socket".parse());
+        assert_eq!(
+            Ok(Transport::Command(String::from(
+                "ssh laptop copiepate --stdio"
+            ))),
+            "command:ssh laptop copiepate --stdio".parse()
+        );
+        assert!("command:".parse::<Transport>().is_err());
+        assert!("ssh laptop".parse::<Transport>().is_err());
+    }
+
+    #[test]
+    fn test_command_pipe() {
+        let mut pipe = Pipe::spawn("tr a-z A-Z").unwrap();
+        assert_eq!(5, pipe.write(b"hello").unwrap());
+        // The command exits once its stdin is closed
+        pipe.writer = None;
+        let mut output = String::new();
+        Stream::Pipe(pipe).read_to_string(&mut output).unwrap();
+        assert_eq!("HELLO", output);
+    }
+
+    #[test]
+    fn test_unix_socket_path() {
+        assert_eq!(
+            Some(Path::new("/run/copiepate.sock")),
+            unix_socket_path("unix:/run/copiepate.sock")
+        );
+        assert_eq!(None, unix_socket_path("127.0.0.1:2323"));
+    }
+
+    #[test]
+    fn test_bind_unix() {
+        let path = socket_path("bind");
+        let address = format!("{UNIX_PREFIX}{}", path.display());
+        let listener = Listener::bind(&address).unwrap();
+        assert_eq!(address, listener.local_address().unwrap().to_string());
+
+        // Only the current user can connect to the socket
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+
+        // The socket is in use until the listener is closed, and then removed
+        assert_eq!(
+            ErrorKind::AddrInUse,
+            Listener::bind(&address).unwrap_err().kind()
+        );
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_bind_unix_stale_socket() {
+        let path = socket_path("stale");
+        let address = format!("{UNIX_PREFIX}{}", path.display());
+
+        // Sockets nobody listens on are replaced
+        drop(UnixListener::bind(&path).unwrap());
+        let listener = Listener::bind(&address).unwrap();
+        connect_unix(&path).unwrap();
+        drop(listener);
+
+        // Other files are left untouched
+        fs::write(&path, b"not a socket").unwrap();
+        assert_eq!(
+            ErrorKind::AlreadyExists,
+            Listener::bind(&address).unwrap_err().kind()
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bind_unix_shared_directory() {
+        let path = socket_path("shared");
+        let dir = path.parent().unwrap();
+        let address = format!("{UNIX_PREFIX}{}", path.display());
+
+        // Directories other users can write to are refused
+        for mode in [0o1777, 0o770] {
+            fs::set_permissions(dir, fs::Permissions::from_mode(mode)).unwrap();
+            assert_eq!(
+                ErrorKind::PermissionDenied,
+                Listener::bind(&address).unwrap_err().kind()
+            );
+            assert!(!path.exists());
+        }
+        fs::remove_dir(dir).unwrap();
+    }
+}
@@ -0,0 +1,295 @@
+#![cfg(feature = "async")]
+
+use std::{error::Error, path::PathBuf, thread, time::Duration};
+
+use copiepate::{
+    backend::FileBackend,
+    client::{AsyncClient, Client},
+    server::{AsyncServer, Event, Response, ServerBuilder},
+    Message,
+};
+use futures::StreamExt;
+
+const TESTING_INSECURE_KEY: &[u8; copiepate::KEY_SIZE] = b"__WARNING_UNSECURE_KEY_TESTING__";
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("copiepate-async-{name}-{}", std::process::id()))
+}
+
+#[tokio::test]
+async fn test_async_client() -> Result<(), Box<dyn Error>> {
+    let path = temp_path("client");
+
+    // 1. Start blocking server
+    let mut server = ServerBuilder::<FileBackend>::default()
+        .address("127.0.0.1:0")
+        .clipboard_ctx(FileBackend::File(path.clone()))
+        .key(TESTING_INSECURE_KEY)
+        .allow_fetch(true)
+        .build()?;
+    let address = server.bind()?.to_string();
+    thread::spawn(move || server.start());
+
+    // 2. Send and fetch the clipboard
+    let mut client = AsyncClient::new(&address, TESTING_INSECURE_KEY);
+    client.send(b"Async message").await?;
+    assert_eq!("Async message", std::fs::read_to_string(&path)?);
+    assert_eq!(
+        Message::text(String::from("Async message")),
+        client.fetch().await?
+    );
+
+    // 3. Several messages over a single session
+    let mut session = client.session().await?;
+    session.send(b"First").await?;
+    session.send(b"Second").await?;
+    session.close().await?;
+    assert_eq!("Second", std::fs::read_to_string(&path)?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_async_server() -> Result<(), Box<dyn Error>> {
+    let path = temp_path("server");
+
+    // 1. Start async server, stopping after the first message
+    let mut server = AsyncServer::from(
+        ServerBuilder::<FileBackend>::default()
+            .address("127.0.0.1:0")
+            .clipboard_ctx(FileBackend::File(path.clone()))
+            .key(TESTING_INSECURE_KEY)
+            .once(true)
+            .build()?,
+    );
+    let address = server.bind()?.to_string();
+    let server = tokio::spawn(async move { server.start().await.unwrap() });
+
+    // 2. Blocking clients are served
+    let client_address = address.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut client = Client::new(&client_address, TESTING_INSECURE_KEY);
+        client.send(b"Blocking message")
+    })
+    .await??;
+
+    assert_eq!(
+        Some(Message::new(b"Blocking message".to_vec())),
+        server.await?
+    );
+    assert_eq!("Blocking message", std::fs::read_to_string(&path)?);
+
+    // 3. The listener is closed, its address can be bound again
+    std::net::TcpListener::bind(&address)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_async_server_slow_exec() -> Result<(), Box<dyn Error>> {
+    let path = temp_path("slow-exec");
+
+    // 1. Start async server, with an exec command slow to handle some messages
+    let mut server = AsyncServer::from(
+        ServerBuilder::<FileBackend>::default()
+            .address("127.0.0.1:0")
+            .clipboard_ctx(FileBackend::File(path.clone()))
+            .key(TESTING_INSECURE_KEY)
+            .exec_command(Some(String::from("grep -q Slow && sleep 2; true")))
+            .build()?,
+    );
+    let address = server.bind()?.to_string();
+    tokio::spawn(async move { server.start().await.unwrap() });
+
+    // 2. A client waits for the slow command, while another client is opened in time
+    // and gets its Ack once the command exits
+    let fast_address = address.clone();
+    let fast = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(500));
+        let mut client = Client::new(&fast_address, TESTING_INSECURE_KEY);
+        client.timeouts.handshake = Some(Duration::from_secs(1));
+        client.send(b"Fast message")
+    });
+    AsyncClient::new(&address, TESTING_INSECURE_KEY)
+        .send(b"Slow message")
+        .await?;
+    tokio::task::spawn_blocking(move || fast.join().expect("Client panicked")).await??;
+    assert_eq!("Fast message", std::fs::read_to_string(&path)?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_async_connection_stream() -> Result<(), Box<dyn Error>> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?.to_string();
+    let server = AsyncServer::from(
+        ServerBuilder::<FileBackend>::default()
+            .address("127.0.0.1:0")
+            .clipboard_ctx(FileBackend::Stdout)
+            .key(TESTING_INSECURE_KEY)
+            .build()?,
+    );
+
+    // 1. Handle the events of a connection as a stream
+    let client = tokio::spawn(async move {
+        let mut client = AsyncClient::new(&address, TESTING_INSECURE_KEY);
+        let mut session = client.session().await?;
+        session.send(b"First").await?;
+        session.exec(b"Second").await?;
+        session.close().await
+    });
+
+    let (stream, _) = listener.accept().await?;
+    let mut connection = server.connection(stream);
+    let mut events = Vec::new();
+    while let Some(event) = connection.next().await {
+        let event = event?;
+        connection
+            .respond(Response::Ack(String::from("Handled")))
+            .await?;
+        events.push(event);
+    }
+    client.await??;
+
+    // 2. Events are received in order until the client closes the connection
+    assert_eq!(2, events.len());
+    assert!(matches!(&events[0], Event::PasteEvent(e) if e.message.content == b"First"));
+    assert!(matches!(&events[1], Event::ExecEvent(e) if e.message.content == b"Second"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_async_connection_client() -> Result<(), Box<dyn Error>> {
+    use copiepate::server::{ClientKey, Keyring};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?.to_string();
+    let server = AsyncServer::from(
+        ServerBuilder::<FileBackend>::default()
+            .address("127.0.0.1:0")
+            .clipboard_ctx(FileBackend::Stdout)
+            .keyring(Keyring::try_from(vec![ClientKey::new(
+                "laptop",
+                "Laptop",
+                TESTING_INSECURE_KEY,
+            )])?)
+            .build()?,
+    );
+
+    // 1. Send a message with the key of a client
+    let client = tokio::spawn(async move {
+        let mut client = Client::new(&address, TESTING_INSECURE_KEY);
+        client.key_id = Some(String::from("laptop"));
+        AsyncClient::from(client).send(b"From laptop").await
+    });
+
+    let (stream, _) = listener.accept().await?;
+    let mut connection = server.connection(stream);
+    let event = connection.next().await.expect("Connection closed")?;
+    connection
+        .respond(Response::Ack(String::from("Handled")))
+        .await?;
+    client.await??;
+
+    // 2. The event records which client sent it
+    let client = event.client().expect("Event without client");
+    assert_eq!("laptop", client.key_id);
+    assert_eq!("Laptop", client.name);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_async_shutdown() -> Result<(), Box<dyn Error>> {
+    let path = temp_path("shutdown");
+
+    // 1. Start async server
+    let mut server = AsyncServer::from(
+        ServerBuilder::<FileBackend>::default()
+            .address("127.0.0.1:0")
+            .clipboard_ctx(FileBackend::File(path.clone()))
+            .key(TESTING_INSECURE_KEY)
+            .build()?,
+    );
+    let address = server.bind()?.to_string();
+    let handle = server.handle();
+    let server = tokio::spawn(async move { server.start().await.unwrap() });
+
+    // 2. Idle connections don't prevent the server from stopping
+    let mut client = AsyncClient::new(&address, TESTING_INSECURE_KEY);
+    let mut session = client.session().await?;
+    session.send(b"Before shutdown").await?;
+    handle.shutdown();
+    let stopped = tokio::time::timeout(Duration::from_secs(5), server).await??;
+    assert_eq!(None, stopped);
+    assert_eq!("Before shutdown", std::fs::read_to_string(&path)?);
+
+    // 3. New connections are refused
+    assert!(AsyncClient::new(&address, TESTING_INSECURE_KEY)
+        .send(b"After shutdown")
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_async_unix_socket() -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    let path = temp_path("unix");
+    // Sockets are refused in directories other users can write to
+    let dir = temp_path("socket");
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)?;
+    let socket = dir.join("copiepate.sock");
+    let address: &'static str = format!("unix:{}", socket.display()).leak();
+
+    // 1. Start async server listening on a Unix domain socket
+    let mut server = AsyncServer::from(
+        ServerBuilder::<FileBackend>::default()
+            .address(address)
+            .clipboard_ctx(FileBackend::File(path.clone()))
+            .key(TESTING_INSECURE_KEY)
+            .build()?,
+    );
+    server.bind()?;
+    tokio::spawn(async move { server.start().await.unwrap() });
+
+    // 2. Send clipboard
+    AsyncClient::new(address, TESTING_INSECURE_KEY)
+        .send(b"Over a Unix socket")
+        .await?;
+    assert_eq!("Over a Unix socket", std::fs::read_to_string(&path)?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_async_command_transport() -> Result<(), Box<dyn Error>> {
+    use copiepate::transport::Transport;
+
+    const TESTING_INSECURE_SECRET: &str = "X19XQVJOSU5HX1VOU0VDVVJFX0tFWV9URVNUSU5HX18=";
+    let path = temp_path("command");
+    let config = temp_path("command-config").with_extension("toml");
+    std::fs::write(&config, "")?;
+
+    // 1. Speak to a server spawned over stdin and stdout
+    let mut client = Client::new("", TESTING_INSECURE_KEY);
+    client.transport = Transport::Command(format!(
+        "'{}' --stdio --config '{}' --secret {} --backend file --output-path '{}'",
+        env!("CARGO_BIN_EXE_copiepate"),
+        config.display(),
+        TESTING_INSECURE_SECRET,
+        path.display(),
+    ));
+    AsyncClient::from(client).send(b"Over stdio").await?;
+    assert_eq!("Over stdio", std::fs::read_to_string(&path)?);
+
+    Ok(())
+}
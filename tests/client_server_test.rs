@@ -6,10 +6,18 @@ use std::{
 };
 
 use clipboard::ClipboardProvider;
+use copiepate::{
+    backend::{ClipboardBackend, ClipboardProviderBackend},
+    server::{Server, ServerError, ServerHandle},
+    Message,
+};
 
-const ADDRESS: &str = "127.0.0.1:2423";
+/// Servers are bound to a free port.
+const ADDRESS: &str = "127.0.0.1:0";
 const TESTING_INSECURE_KEY: &[u8; copiepate::KEY_SIZE] = b"__WARNING_UNSECURE_KEY_TESTING__";
 
+type TestBackend = ClipboardProviderBackend<TestClipboardContext>;
+
 struct TestClipboardContext {
     pub clipboard_content: Arc<RwLock<String>>,
 }
@@ -33,6 +41,18 @@ impl ClipboardProvider for TestClipboardContext {
     }
 }
 
+type ServerThread = thread::JoinHandle<Result<Option<Message>, ServerError>>;
+
+/// Bind the server and start it in a new thread, returning the address it listens on.
+fn start<P>(mut server: Server<'static, P>) -> (String, ServerHandle, ServerThread)
+where
+    P: ClipboardBackend + Send + 'static,
+{
+    let address = server.bind().expect("Could not bind server").to_string();
+    let handle = server.handle();
+    (address, handle, thread::spawn(move || server.start()))
+}
+
 #[test]
 fn test_happy_path() -> Result<(), Box<dyn Error>> {
     let test_message = "Test Message";
@@ -42,30 +62,993 @@ fn test_happy_path() -> Result<(), Box<dyn Error>> {
     };
 
     // 1. Start server
+    let backend = TestBackend::new(TestClipboardContext {
+        clipboard_content: clipboard_content.clone(),
+    });
+    let server = copiepate::server::ServerBuilder::<TestBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .key(TESTING_INSECURE_KEY)
+        .build()
+        .expect("Could not build server");
+    let (address, _handle, _server) = start(server);
+
+    // 2. Send clipboard
+    let mut client = copiepate::client::Client::new(&address, TESTING_INSECURE_KEY);
+    client.send(test_message.as_bytes())?;
+
+    // 3. Wait
+    thread::sleep(Duration::from_millis(100));
+
+    // 4. Check clipboard
+    assert_eq!(test_message, clipboard_ctx.get_contents()?);
+
+    Ok(())
+}
+
+#[test]
+fn test_wrong_secret_rejected() -> Result<(), Box<dyn Error>> {
+    const OTHER_KEY: &[u8; copiepate::KEY_SIZE] = b"__WARNING_OTHER_KEY_TESTING_____";
+
+    // 1. Start server
+    let clipboard_ctx = TestClipboardContext::new().unwrap();
+    let backend = TestBackend::new(clipboard_ctx);
+    let server = copiepate::server::ServerBuilder::<TestBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .key(TESTING_INSECURE_KEY)
+        .build()
+        .expect("Could not build server");
+    let (address, _handle, _server) = start(server);
+
+    // 2. Send clipboard with another secret, rejected while opening the connection
+    let mut client = copiepate::client::Client::new(&address, OTHER_KEY);
+    match client.send(b"Test Message") {
+        Err(copiepate::client::ClientError::Rejected { code, .. }) => {
+            assert_eq!(copiepate::StatusCode::AuthenticationFailed, code)
+        }
+        r => panic!("Expected rejection, got {r:?}"),
+    }
+
+    Ok(())
+}
+
+/// Forward a single connection to `server`, like an attacker in the middle, applying
+/// `rewrite_client` and `rewrite_server` to the first frame header and payload bytes
+/// sent by each peer.
+fn start_proxy(
+    server: String,
+    rewrite_client: fn(&mut [u8]),
+    rewrite_server: fn(&mut [u8]),
+) -> String {
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+
+    fn pipe(mut from: TcpStream, mut to: TcpStream, rewrite: fn(&mut [u8])) {
+        // Frame header and the first 8 bytes of the payload
+        let mut head = [0; 24];
+        if from.read_exact(&mut head).is_ok() {
+            rewrite(&mut head);
+            let _ = to.write_all(&head);
+            let _ = std::io::copy(&mut from, &mut to);
+        }
+        let _ = to.shutdown(Shutdown::Write);
+    }
+
+    let listener = TcpListener::bind(ADDRESS).expect("Could not bind proxy");
+    let address = listener.local_addr().unwrap().to_string();
     thread::spawn(move || {
-        let mut clipboard_ctx = TestClipboardContext {
+        let (client, _) = listener.accept().unwrap();
+        let server = TcpStream::connect(server).unwrap();
+        let (client_reader, server_reader) = (client.try_clone().unwrap(), server.try_clone());
+        thread::spawn(move || pipe(client_reader, server, rewrite_client));
+        pipe(server_reader.unwrap(), client, rewrite_server);
+    });
+    address
+}
+
+#[test]
+fn test_legacy_client() -> Result<(), Box<dyn Error>> {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    use chacha20poly1305::{aead::Aead, KeyInit};
+    use copiepate::{Cipher, Nonce, CLOSE_PAYLOAD, NOUNCE_SIZE};
+
+    // Frame sent by copiepate 0.2, which speaks protocol version 1
+    fn legacy_frame(frame_type: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&1u32.to_le_bytes());
+        frame.extend_from_slice(&(16 + payload.len() as u64).to_le_bytes());
+        frame.extend_from_slice(&frame_type.to_le_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    // 1. Start server with default settings
+    let clipboard_content = Arc::new(RwLock::new(String::new()));
+    let backend = TestBackend::new(TestClipboardContext {
+        clipboard_content: clipboard_content.clone(),
+    });
+    let server = copiepate::server::ServerBuilder::<TestBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .key(TESTING_INSECURE_KEY)
+        .build()
+        .expect("Could not build server");
+    let (address, handle, _) = start(server);
+
+    // 2. Open a legacy connection, the server answers with its nonce
+    let mut stream = TcpStream::connect(address)?;
+    stream.write_all(&legacy_frame(0, &[]))?;
+    let mut open = [0; 16 + NOUNCE_SIZE];
+    stream.read_exact(&mut open)?;
+    assert_eq!(1u32.to_le_bytes(), open[..4]);
+    let nonce = Nonce::from(<[u8; NOUNCE_SIZE]>::try_from(&open[16..])?);
+
+    // 3. Paste a message and close the connection, legacy clients get no response
+    let cipher = Cipher::new(TESTING_INSECURE_KEY.into());
+    let encrypt = |nonce: &Nonce, message: &[u8]| {
+        cipher
+            .encrypt(nonce.cipher_nonce(), message)
+            .expect("Could not encrypt")
+    };
+    stream.write_all(&legacy_frame(2, &encrypt(&nonce, b"Legacy message")))?;
+    stream.write_all(&legacy_frame(1, &encrypt(&nonce.consume(), &CLOSE_PAYLOAD)))?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert!(response.is_empty());
+    assert_eq!("Legacy message", *clipboard_content.read().unwrap());
+    handle.shutdown();
+
+    Ok(())
+}
+
+#[test]
+fn test_downgrade_refused() -> Result<(), Box<dyn Error>> {
+    use copiepate::{
+        client::{Client, ClientError},
+        StatusCode, MIN_AUTHENTICATED_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION,
+    };
+
+    // Protocol version 4 doesn't authenticate the handshake transcript
+    fn downgrade_header(bytes: &mut [u8]) {
+        bytes[..4].copy_from_slice(&4u32.to_le_bytes());
+    }
+    fn downgrade_max_version(bytes: &mut [u8]) {
+        bytes[20..24].copy_from_slice(&4u32.to_le_bytes());
+    }
+    fn keep(_: &mut [u8]) {}
+
+    let start_server = |min_protocol_version| {
+        let clipboard_content = Arc::new(RwLock::new(String::new()));
+        let backend = TestBackend::new(TestClipboardContext {
             clipboard_content: clipboard_content.clone(),
-        };
-        let mut server = copiepate::server::ServerBuilder::<TestClipboardContext>::default()
+        });
+        let server = copiepate::server::ServerBuilder::<TestBackend>::default()
             .address(ADDRESS)
-            .clipboard_ctx(&mut clipboard_ctx)
+            .clipboard_ctx(backend)
             .key(TESTING_INSECURE_KEY)
+            .min_protocol_version(min_protocol_version)
             .build()
             .expect("Could not build server");
-        server.start().unwrap();
+        let (address, handle, _) = start(server);
+        (address, handle, clipboard_content)
+    };
+
+    let authenticated_client = |address| {
+        let mut client = Client::new(address, TESTING_INSECURE_KEY);
+        client.min_protocol_version = MIN_AUTHENTICATED_PROTOCOL_VERSION;
+        client
+    };
+
+    // 1. Opted in, the client refuses a server Open rewritten to version 4
+    let (address, handle, _) = start_server(MIN_AUTHENTICATED_PROTOCOL_VERSION);
+    let proxy = start_proxy(address.clone(), keep, downgrade_header);
+    match authenticated_client(&proxy).send(b"Downgraded") {
+        Err(ClientError::Unsupported(_)) => (),
+        r => panic!("Expected unsupported server, got {r:?}"),
+    }
+
+    // 2. The server refuses a client Open rewritten to support version 4 at most
+    let proxy = start_proxy(address, downgrade_max_version, keep);
+    match authenticated_client(&proxy).send(b"Downgraded") {
+        Err(ClientError::Rejected { code, .. }) => {
+            assert_eq!(StatusCode::UnsupportedVersion, code)
+        }
+        r => panic!("Expected rejection, got {r:?}"),
+    }
+    handle.shutdown();
+
+    // 3. With default settings, peers accept legacy versions
+    let (address, handle, clipboard_content) = start_server(MIN_PROTOCOL_VERSION);
+    let proxy = start_proxy(address, downgrade_max_version, keep);
+    Client::new(&proxy, TESTING_INSECURE_KEY).send(b"Legacy")?;
+    assert_eq!("Legacy", *clipboard_content.read().unwrap());
+    handle.shutdown();
+
+    Ok(())
+}
+
+#[test]
+fn test_keyring() -> Result<(), Box<dyn Error>> {
+    use copiepate::{
+        client::{Client, ClientError},
+        server::{ClientKey, Keyring},
+        StatusCode,
+    };
+    use std::time::SystemTime;
+
+    const OLD_KEY: &[u8; copiepate::KEY_SIZE] = b"__WARNING_OLD_KEY_TESTING_______";
+    const NEW_KEY: &[u8; copiepate::KEY_SIZE] = b"__WARNING_NEW_KEY_TESTING_______";
+    const DESKTOP_KEY: &[u8; copiepate::KEY_SIZE] = b"__WARNING_DESKTOP_KEY_TESTING___";
+    let day = Duration::from_secs(24 * 60 * 60);
+    let now = SystemTime::now();
+
+    // 1. Start server with the old and new keys of a laptop, and the key of a desktop.
+    //    The old laptop key expired, the server has no default key
+    let mut expired = ClientKey::new("laptop-1", "Laptop", OLD_KEY);
+    expired.not_after = Some(now - day);
+    let mut rotated = ClientKey::new("laptop-2", "Laptop", NEW_KEY);
+    rotated.not_before = Some(now - 2 * day);
+    let keyring = Keyring::try_from(vec![
+        expired,
+        rotated,
+        ClientKey::new("desktop", "Desktop", DESKTOP_KEY),
+    ])?;
+    let clipboard_content = Arc::new(RwLock::new(String::new()));
+    let backend = TestBackend::new(TestClipboardContext {
+        clipboard_content: clipboard_content.clone(),
     });
+    let server = copiepate::server::ServerBuilder::<TestBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .keyring(keyring)
+        .build()
+        .expect("Could not build server");
+    let (address, _handle, _server) = start(server);
 
-    thread::sleep(Duration::from_millis(100));
+    // 2. Each client uses its own key
+    for (key_id, key, message) in [
+        ("laptop-2", NEW_KEY, "From laptop"),
+        ("desktop", DESKTOP_KEY, "From desktop"),
+    ] {
+        let mut client = Client::new(&address, key);
+        client.key_id = Some(String::from(key_id));
+        client.send(message.as_bytes())?;
+        assert_eq!(message, *clipboard_content.read().unwrap());
+    }
+
+    // 3. Expired keys, keys of another client and missing key IDs are rejected
+    for (key_id, key) in [
+        (Some("laptop-1"), OLD_KEY),
+        (Some("desktop"), NEW_KEY),
+        (None, NEW_KEY),
+    ] {
+        let mut client = Client::new(&address, key);
+        client.key_id = key_id.map(String::from);
+        match client.send(b"Rejected") {
+            Err(ClientError::Rejected { code, .. }) => {
+                assert_eq!(StatusCode::AuthenticationFailed, code)
+            }
+            r => panic!("Expected rejection, got {r:?}"),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_passphrase() -> Result<(), Box<dyn Error>> {
+    use copiepate::{
+        client::{Client, ClientError},
+        kdf::KdfParams,
+        StatusCode,
+    };
+
+    // Low costs to keep the test fast
+    let kdf = KdfParams {
+        memory_cost: 64,
+        time_cost: 1,
+        ..KdfParams::new(b"copiepate testing salt")
+    };
+    let passphrase = b"correct horse battery staple";
+
+    // 1. Start server with a key derived from a passphrase
+    let clipboard_content = Arc::new(RwLock::new(String::new()));
+    let backend = TestBackend::new(TestClipboardContext {
+        clipboard_content: clipboard_content.clone(),
+    });
+    let server = copiepate::server::ServerBuilder::<TestBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .key(&kdf.derive_key(passphrase)?[..])
+        .kdf(Some(kdf.clone()))
+        .build()
+        .expect("Could not build server");
+    let (address, _handle, _server) = start(server);
+
+    // 2. Client derives the same key with the same parameters
+    let mut client = Client::new(&address, &kdf.derive_key(passphrase)?[..]);
+    client.kdf = Some(kdf.clone());
+    client.send(b"From passphrase")?;
+    assert_eq!("From passphrase", *clipboard_content.read().unwrap());
+
+    // 3. Clients deriving their key with other parameters are told so
+    for other in [
+        KdfParams {
+            time_cost: 2,
+            ..kdf.clone()
+        },
+        KdfParams {
+            salt: b"another testing salt".to_vec(),
+            ..kdf.clone()
+        },
+    ] {
+        let mut client = Client::new(&address, &other.derive_key(passphrase)?[..]);
+        client.kdf = Some(other);
+        match client.send(b"Rejected") {
+            Err(ClientError::Rejected { code, .. }) => assert_eq!(StatusCode::KdfMismatch, code),
+            r => panic!("Expected rejection, got {r:?}"),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_ssh_key() -> Result<(), Box<dyn Error>> {
+    use copiepate::{
+        client::{Client, ClientError},
+        ssh::{KnownHosts, SshIdentity},
+        StatusCode,
+    };
+    use ssh_key::{private::Ed25519Keypair, LineEnding, PrivateKey};
+
+    let ssh_key = |seed: u8, comment: &str| {
+        let mut key = PrivateKey::from(Ed25519Keypair::from_seed(&[seed; 32]));
+        key.set_comment(comment);
+        key
+    };
+    let laptop = ssh_key(1, "laptop");
+    let intruder = ssh_key(2, "intruder");
+    let host = ssh_key(3, "server");
+    let impostor = ssh_key(4, "impostor");
+
+    // 1. Start server accepting the SSH key of the laptop only, signing with its host key
+    let clipboard_content = Arc::new(RwLock::new(String::new()));
+    let backend = TestBackend::new(TestClipboardContext {
+        clipboard_content: clipboard_content.clone(),
+    });
+    let server = copiepate::server::ServerBuilder::<TestBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .key(TESTING_INSECURE_KEY)
+        .authorized_keys(laptop.public_key().to_openssh()?.parse()?)
+        .host_key(SshIdentity::Key(host.clone()))
+        .build()
+        .expect("Could not build server");
+    let (address, _handle, _server) = start(server);
+    let known_hosts = |key: &PrivateKey| -> Result<KnownHosts, Box<dyn Error>> {
+        Ok(format!("{address} {}", key.public_key().to_openssh()?).parse()?)
+    };
+
+    // 2. Send clipboard signed with the private key file of the laptop, without secret
+    let path = std::env::temp_dir().join(format!("copiepate-ssh-key-{}", std::process::id()));
+    std::fs::write(&path, laptop.to_openssh(LineEnding::LF)?)?;
+    let identity = SshIdentity::from_file(&path)?;
+    std::fs::remove_file(&path)?;
+    let mut client = Client::with_ssh_identity(&address, identity, known_hosts(&host)?);
+    client.send(b"Signed message")?;
+    assert_eq!("Signed message", *clipboard_content.read().unwrap());
+
+    // 3. Other SSH keys and secrets are rejected
+    for mut client in [
+        Client::with_ssh_identity(&address, SshIdentity::Key(intruder), known_hosts(&host)?),
+        Client::new(&address, TESTING_INSECURE_KEY),
+    ] {
+        match client.send(b"Rejected") {
+            Err(ClientError::Rejected { code, .. }) => {
+                assert_eq!(StatusCode::AuthenticationFailed, code)
+            }
+            r => panic!("Expected rejection, got {r:?}"),
+        }
+    }
+
+    // 4. Servers signing with a host key the client doesn't know are rejected
+    for known_hosts in [known_hosts(&impostor)?, KnownHosts::default()] {
+        let identity = SshIdentity::Key(laptop.clone());
+        match Client::with_ssh_identity(&address, identity, known_hosts).send(b"Rejected") {
+            Err(ClientError::Authentication(_)) => (),
+            r => panic!("Expected authentication failure, got {r:?}"),
+        }
+    }
+    assert_eq!("Signed message", *clipboard_content.read().unwrap());
+
+    // 5. Secrets are accepted alongside SSH keys once allowed
+    let backend = TestBackend::new(TestClipboardContext {
+        clipboard_content: clipboard_content.clone(),
+    });
+    let server = copiepate::server::ServerBuilder::<TestBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .key(TESTING_INSECURE_KEY)
+        .authorized_keys(laptop.public_key().to_openssh()?.parse()?)
+        .host_key(SshIdentity::Key(host))
+        .allow_secret(true)
+        .build()
+        .expect("Could not build server");
+    let (address, _handle, _server) = start(server);
+    Client::new(&address, TESTING_INSECURE_KEY).send(b"Secret message")?;
+    assert_eq!("Secret message", *clipboard_content.read().unwrap());
+
+    Ok(())
+}
+
+#[test]
+fn test_fetch() -> Result<(), Box<dyn Error>> {
+    let test_message = "Server clipboard";
+
+    // 1. Start server with some clipboard content
+    let mut clipboard_ctx = TestClipboardContext::new().unwrap();
+    clipboard_ctx.set_contents(test_message.to_owned()).unwrap();
+    let backend = TestBackend::new(clipboard_ctx);
+    let server = copiepate::server::ServerBuilder::<TestBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .key(TESTING_INSECURE_KEY)
+        .allow_fetch(true)
+        .build()
+        .expect("Could not build server");
+    let (address, _handle, _server) = start(server);
+
+    // 2. Fetch clipboard
+    let mut client = copiepate::client::Client::new(&address, TESTING_INSECURE_KEY);
+    assert_eq!(
+        copiepate::Message::text(test_message.to_owned()),
+        client.fetch()?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_session() -> Result<(), Box<dyn Error>> {
+    let clipboard_content = Arc::new(RwLock::new(String::new()));
+    let server_clipboard_content = clipboard_content.clone();
+
+    // 1. Start server
+    let clipboard_ctx = TestClipboardContext {
+        clipboard_content: server_clipboard_content,
+    };
+    let backend = TestBackend::new(clipboard_ctx);
+    let server = copiepate::server::ServerBuilder::<TestBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .key(TESTING_INSECURE_KEY)
+        .allow_fetch(true)
+        .build()
+        .expect("Could not build server");
+    let (address, _handle, _server) = start(server);
+
+    // 2. Send several messages over the same connection
+    let mut client = copiepate::client::Client::new(&address, TESTING_INSECURE_KEY);
+    let mut session = client.session()?;
+    for message in ["First message", "Second message", "Third message"] {
+        session.send(message.as_bytes())?;
+        assert_eq!(message.as_bytes(), session.fetch()?.content);
+    }
+    session.exec(b"Not saved to clipboard")?;
+
+    // 3. Rejected messages don't close the session
+    let image =
+        copiepate::Message::new(vec![0x89, b'P', b'N', b'G']).with_content_type("image/png");
+    match session.send_message(&image) {
+        Err(copiepate::client::ClientError::Rejected { code, .. }) => {
+            assert_eq!(copiepate::StatusCode::UnsupportedContent, code)
+        }
+        r => panic!("Expected rejection, got {r:?}"),
+    }
+    session.send(b"Third message")?;
+    session.close()?;
+
+    // 4. Check clipboard
+    assert_eq!("Third message", *clipboard_content.read().unwrap());
+
+    // 5. The client can open a new session
+    client.send(b"New session")?;
+    assert_eq!(b"New session".as_slice(), client.fetch()?.content);
+
+    Ok(())
+}
+
+#[test]
+fn test_exec_command_ignoring_stdin() -> Result<(), Box<dyn Error>> {
+    // 1. Start server running a command that exits without reading the message
+    let backend = TestBackend::new(TestClipboardContext::new().unwrap());
+    let server = copiepate::server::ServerBuilder::<TestBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .key(TESTING_INSECURE_KEY)
+        .exec_command(Some(String::from("true")))
+        .build()
+        .expect("Could not build server");
+    let (address, _handle, _server) = start(server);
+
+    // 2. Messages larger than a pipe buffer are still acknowledged, again and again
+    let mut client = copiepate::client::Client::new(&address, TESTING_INSECURE_KEY);
+    let mut session = client.session()?;
+    for _ in 0..2 {
+        session.exec(&vec![b'a'; 1024 * 1024])?;
+    }
+    session.close()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_once() -> Result<(), Box<dyn Error>> {
+    let path = std::env::temp_dir().join(format!("copiepate-once-{}", std::process::id()));
+
+    // 1. Start server writing to a file, until the first message
+    let server_path = path.clone();
+    let backend = copiepate::backend::FileBackend::File(server_path);
+    let server = copiepate::server::ServerBuilder::<copiepate::backend::FileBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .key(TESTING_INSECURE_KEY)
+        .once(true)
+        .build()
+        .expect("Could not build server");
+    let (address, _handle, server) = start(server);
 
     // 2. Send clipboard
-    let mut client = copiepate::client::Client::new(ADDRESS, TESTING_INSECURE_KEY);
-    client.send(test_message.as_bytes())?;
+    let mut client = copiepate::client::Client::new(&address, TESTING_INSECURE_KEY);
+    client.send(b"Test Message")?;
 
-    // 3. Wait
+    // 3. Server returns the message and stops listening
+    let message = server.join().unwrap()?.expect("Expected a message");
+    assert_eq!(b"Test Message".as_slice(), message.content);
+    assert_eq!(b"Test Message".as_slice(), std::fs::read(&path)?);
+    assert!(client.send(b"Other Message").is_err());
+
+    // 4. The listener is closed, its address can be bound again
+    std::net::TcpListener::bind(&address)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_selection() -> Result<(), Box<dyn Error>> {
+    use copiepate::{client::ClientError, Message, Selection, StatusCode};
+
+    let path = std::env::temp_dir().join(format!("copiepate-selection-{}", std::process::id()));
+    std::fs::create_dir_all(&path)?;
+
+    // 1. Start server writing each selection to its own file, clients may only write to
+    // the primary selection
+    let copy_command = format!("cat > '{}'/$COPIEPATE_SELECTION", path.display());
+    let backend = copiepate::backend::CommandBackend::shell(&copy_command, None)
+        .with_shell_primary(&copy_command);
+    let server = copiepate::server::ServerBuilder::<copiepate::backend::CommandBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .key(TESTING_INSECURE_KEY)
+        .allowed_selections(vec![Selection::Primary])
+        .build()
+        .expect("Could not build server");
+    let (address, _handle, _server) = start(server);
+
+    // 2. Messages without selection are written to the server default selection
+    let mut client = copiepate::client::Client::new(&address, TESTING_INSECURE_KEY);
+    client.send(b"Default")?;
+    assert_eq!("Default", std::fs::read_to_string(path.join("clipboard"))?);
+
+    client.send_message(&Message::new(b"Primary".to_vec()).with_selection(Selection::Primary))?;
+    assert_eq!("Primary", std::fs::read_to_string(path.join("primary"))?);
+
+    // 3. Selections outside of the allowlist are refused
+    for selection in [Selection::Clipboard, Selection::Both] {
+        let message = Message::new(b"Refused".to_vec()).with_selection(selection);
+        match client.send_message(&message) {
+            Err(ClientError::Rejected { code, .. }) => assert_eq!(StatusCode::Forbidden, code),
+            r => panic!("Expected rejection, got {r:?}"),
+        }
+    }
+    assert_eq!("Default", std::fs::read_to_string(path.join("clipboard"))?);
+
+    Ok(())
+}
+
+#[test]
+fn test_stuck_client_does_not_block_others() -> Result<(), Box<dyn Error>> {
+    use std::{io::Write, net::TcpStream};
+
+    let clipboard_content = Arc::new(RwLock::new(String::new()));
+    let mut clipboard_ctx = TestClipboardContext {
+        clipboard_content: clipboard_content.clone(),
+    };
+
+    // 1. Start server
+    let backend = TestBackend::new(TestClipboardContext { clipboard_content });
+    let server = copiepate::server::ServerBuilder::<TestBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .key(TESTING_INSECURE_KEY)
+        .build()
+        .expect("Could not build server");
+    let (address, _handle, _server) = start(server);
+
+    // 2. Open connections that never complete their handshake
+    let idle = TcpStream::connect(&address)?;
+    let mut partial = TcpStream::connect(&address)?;
+    partial.write_all(&[0x01, 0x00])?;
     thread::sleep(Duration::from_millis(100));
 
-    // 4. Check clipboard
-    assert_eq!(test_message, clipboard_ctx.get_contents()?);
+    // 3. Other clients are still served
+    let (sender, receiver) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let mut client = copiepate::client::Client::new(&address, TESTING_INSECURE_KEY);
+        sender.send(client.send(b"Not blocked").is_ok()).unwrap();
+    });
+    assert!(receiver.recv_timeout(Duration::from_secs(5))?);
+    assert_eq!("Not blocked", clipboard_ctx.get_contents()?);
+
+    drop((idle, partial));
+    Ok(())
+}
+
+#[test]
+fn test_max_connections() -> Result<(), Box<dyn Error>> {
+    use copiepate::{client::ClientError, StatusCode};
+    use std::net::TcpStream;
+
+    // 1. Start server handling a single connection at a time
+    let backend = TestBackend::new(TestClipboardContext::new().unwrap());
+    let server = copiepate::server::ServerBuilder::<TestBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .key(TESTING_INSECURE_KEY)
+        .max_connections(1)
+        .build()
+        .expect("Could not build server");
+    let (address, _handle, _server) = start(server);
+
+    // 2. Other connections are refused while a client holds the only worker
+    let stuck = TcpStream::connect(&address)?;
+    thread::sleep(Duration::from_millis(100));
+    let mut client = copiepate::client::Client::new(&address, TESTING_INSECURE_KEY);
+    match client.send(b"Refused") {
+        Err(ClientError::Rejected { code, .. }) => assert_eq!(StatusCode::Busy, code),
+        r => panic!("Expected rejection, got {r:?}"),
+    }
+
+    // 3. Connections are accepted again once the worker is released
+    drop(stuck);
+    thread::sleep(Duration::from_millis(100));
+    client.send(b"Accepted")?;
+
+    Ok(())
+}
+
+#[test]
+fn test_client_timeout() -> Result<(), Box<dyn Error>> {
+    use copiepate::client::ClientError;
+    use std::net::TcpListener;
+
+    // 1. Start a server that never answers
+    let listener = TcpListener::bind(ADDRESS)?;
+    let address = listener.local_addr()?.to_string();
+    thread::spawn(move || {
+        let _streams: Vec<_> = listener.incoming().collect();
+    });
+
+    // 2. The client gives up opening the connection
+    let mut client = copiepate::client::Client::new(&address, TESTING_INSECURE_KEY);
+    client.timeouts.handshake = Some(Duration::from_millis(200));
+    match client.send(b"Test Message") {
+        Err(ClientError::Timeout(_)) => (),
+        r => panic!("Expected timeout, got {r:?}"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_server_handshake_timeout() -> Result<(), Box<dyn Error>> {
+    use std::{io::Read, net::TcpStream};
+
+    // 1. Start server handling a single connection at a time
+    let backend = TestBackend::new(TestClipboardContext::new().unwrap());
+    let server = copiepate::server::ServerBuilder::<TestBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .key(TESTING_INSECURE_KEY)
+        .max_connections(1)
+        .timeouts(copiepate::Timeouts {
+            handshake: Some(Duration::from_millis(200)),
+            ..Default::default()
+        })
+        .build()
+        .expect("Could not build server");
+    let (address, _handle, _server) = start(server);
+
+    // 2. A client that never opens its connection is disconnected
+    let mut stuck = TcpStream::connect(&address)?;
+    stuck.set_read_timeout(Some(Duration::from_secs(5)))?;
+    assert_eq!(0, stuck.read(&mut [0; 1])?);
+    thread::sleep(Duration::from_millis(100));
+
+    // 3. Other clients are served again
+    let mut client = copiepate::client::Client::new(&address, TESTING_INSECURE_KEY);
+    client.send(b"Test Message")?;
+
+    Ok(())
+}
+
+#[test]
+fn test_shutdown() -> Result<(), Box<dyn Error>> {
+    use copiepate::client::Client;
+    use std::net::TcpStream;
+
+    let clipboard_content = Arc::new(RwLock::new(String::new()));
+
+    // 1. Start server running a slow command after each message
+    let backend = TestBackend::new(TestClipboardContext {
+        clipboard_content: clipboard_content.clone(),
+    });
+    let server = copiepate::server::ServerBuilder::<TestBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .key(TESTING_INSECURE_KEY)
+        .exec_command(Some(String::from("sleep 0.5")))
+        .build()
+        .expect("Could not build server");
+    let (address, handle, server) = start(server);
+
+    // 2. Shut down the server while a message is handled and another client is idle
+    let mut idle_client = Client::new(&address, TESTING_INSECURE_KEY);
+    let idle = idle_client.session()?;
+    let client_address = address.clone();
+    let client = thread::spawn(move || {
+        Client::new(&client_address, TESTING_INSECURE_KEY).send(b"In flight")
+    });
+    thread::sleep(Duration::from_millis(200));
+    handle.shutdown();
+
+    // 3. The message being handled is acknowledged before the server stops
+    client.join().unwrap()?;
+    assert_eq!(None, server.join().unwrap()?);
+    assert_eq!("In flight", *clipboard_content.read().unwrap());
+
+    // 4. New connections are refused
+    assert!(TcpStream::connect(&address).is_err());
+
+    drop(idle);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unix_socket() -> Result<(), Box<dyn Error>> {
+    use copiepate::client::Client;
+    use std::os::unix::fs::DirBuilderExt;
+
+    let clipboard_content = Arc::new(RwLock::new(String::new()));
+    // Sockets are refused in directories other users can write to
+    let dir = std::env::temp_dir().join(format!("copiepate-unix-{}", std::process::id()));
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)?;
+    let path = dir.join("copiepate.sock");
+    let address: &'static str = format!("unix:{}", path.display()).leak();
+
+    // 1. Start server listening on a Unix domain socket
+    let backend = TestBackend::new(TestClipboardContext {
+        clipboard_content: clipboard_content.clone(),
+    });
+    let server = copiepate::server::ServerBuilder::<TestBackend>::default()
+        .address(address)
+        .clipboard_ctx(backend)
+        .key(TESTING_INSECURE_KEY)
+        .build()
+        .expect("Could not build server");
+    let (local_address, handle, server) = start(server);
+    assert_eq!(address, local_address);
+
+    // 2. Send clipboard
+    Client::new(address, TESTING_INSECURE_KEY).send(b"Over a Unix socket")?;
+    assert_eq!("Over a Unix socket", *clipboard_content.read().unwrap());
+
+    // 3. The socket is removed once the server stops
+    handle.shutdown();
+    server.join().unwrap()?;
+    assert!(!path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_stdio_transport() -> Result<(), Box<dyn Error>> {
+    use copiepate::{client::Client, transport::Transport};
+
+    const TESTING_INSECURE_SECRET: &str = "X19XQVJOSU5HX1VOU0VDVVJFX0tFWV9URVNUSU5HX18=";
+    let dir = std::env::temp_dir().join(format!("copiepate-stdio-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let config = dir.join("config.toml");
+    std::fs::write(&config, "")?;
+    let output = dir.join("received");
+
+    // 1. Each session spawns a server speaking over its stdin and stdout
+    let mut client = Client::new("", TESTING_INSECURE_KEY);
+    client.transport = Transport::Command(format!(
+        "'{}' --stdio --config '{}' --secret {} --allow-paste --backend file --output-path '{}'",
+        env!("CARGO_BIN_EXE_copiepate"),
+        config.display(),
+        TESTING_INSECURE_SECRET,
+        output.display(),
+    ));
+
+    // 2. Send clipboard
+    client.send(b"Over stdio")?;
+    assert_eq!("Over stdio", std::fs::read_to_string(&output)?);
+
+    // 3. Several messages over a single session
+    let mut session = client.session()?;
+    session.send(b"First")?;
+    session.send(b"Second")?;
+    assert_eq!(b"Second".as_slice(), session.fetch()?.content);
+    session.close()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_pairing() -> Result<(), Box<dyn Error>> {
+    use copiepate::{
+        client::{Client, ClientError},
+        pairing::{PairingCode, PairingServer},
+        StatusCode,
+    };
+
+    // 1. Wait for a client to pair
+    let mut pairing = PairingServer::new(ADDRESS, PairingCode::generate());
+    let address = pairing.bind()?.to_string();
+    let code = pairing.code().clone();
+    let pairing = thread::spawn(move || pairing.accept());
+
+    // 2. Clients that don't pair are turned away
+    match Client::new(&address, TESTING_INSECURE_KEY).send(b"Not paired") {
+        Err(ClientError::Rejected { code, .. }) => assert_eq!(StatusCode::Forbidden, code),
+        r => panic!("Expected rejection, got {r:?}"),
+    }
+
+    // 3. Both peers derive the same secret from the code
+    let secret = Client::new(&address, TESTING_INSECURE_KEY).pair(&code)?;
+    assert_eq!(secret, pairing.join().unwrap()?);
+
+    // 4. Servers that are not pairing reject pairing clients
+    let clipboard_content = Arc::new(RwLock::new(String::new()));
+    let backend = TestBackend::new(TestClipboardContext {
+        clipboard_content: clipboard_content.clone(),
+    });
+    let server = copiepate::server::ServerBuilder::<TestBackend>::default()
+        .address(ADDRESS)
+        .clipboard_ctx(backend)
+        .key(&secret)
+        .build()
+        .expect("Could not build server");
+    let (address, _handle, _server) = start(server);
+    assert!(matches!(
+        Client::new(&address, TESTING_INSECURE_KEY).pair(&code),
+        Err(ClientError::Pairing(_))
+    ));
+
+    // 5. The paired secret is used as the pre-shared key
+    Client::new(&address, &secret).send(b"Paired")?;
+    assert_eq!("Paired", *clipboard_content.read().unwrap());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_pair_command() -> Result<(), Box<dyn Error>> {
+    use std::{
+        io::{BufRead, BufReader},
+        os::unix::fs::DirBuilderExt,
+        process::{Command, Stdio},
+    };
+
+    let dir = std::env::temp_dir().join(format!("copiepate-pair-{}", std::process::id()));
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)?;
+    let address = format!("unix:{}", dir.join("socket").display());
+    let server_config = dir.join("server.toml");
+    let client_config = dir.join("client.toml");
+    std::fs::write(
+        &server_config,
+        "backend = \"stdout\"\n\n[[keys]]\nid = \"laptop\"\nname = \"Laptop\"\nsecret = \"\"\n",
+    )?;
+    std::fs::write(&client_config, "secret = \"old\"\n")?;
+
+    // 1. The server displays the pairing code
+    let mut server = Command::new(env!("CARGO_BIN_EXE_copiepate"))
+        .args(["pair", "--server", "--address", &address, "--config"])
+        .arg(&server_config)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut line = String::new();
+    BufReader::new(server.stdout.take().unwrap()).read_line(&mut line)?;
+    let code = line.trim().strip_prefix("Pairing code: ").unwrap();
+
+    // 2. The client pairs with the code typed by the user
+    let client = Command::new(env!("CARGO_BIN_EXE_copiepate"))
+        .args(["pair", code, "--address", &address, "--config"])
+        .arg(&client_config)
+        .stderr(Stdio::null())
+        .status()?;
+    assert!(client.success());
+    assert!(server.wait()?.success());
+
+    // 3. Both configuration files have the same new secret, and keep their other entries
+    let server_config = std::fs::read_to_string(&server_config)?;
+    let client_config = std::fs::read_to_string(&client_config)?;
+    let secret = client_config.lines().next().unwrap();
+    assert!(secret.starts_with("secret = ") && secret != "secret = \"old\"");
+    assert_eq!(Some(secret), server_config.lines().next());
+    assert!(server_config.contains("[[keys]]\nid = \"laptop\""));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_secret_sources() -> Result<(), Box<dyn Error>> {
+    use copiepate::{client::Client, transport::Transport};
+    use std::os::unix::fs::PermissionsExt;
+
+    const TESTING_INSECURE_SECRET: &str = "X19XQVJOSU5HX1VOU0VDVVJFX0tFWV9URVNUSU5HX18=";
+    let dir = std::env::temp_dir().join(format!("copiepate-secrets-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let config = dir.join("config.toml");
+    std::fs::write(&config, "")?;
+    let secret_file = dir.join("secret");
+    std::fs::write(&secret_file, format!("{TESTING_INSECURE_SECRET}\n"))?;
+    std::fs::set_permissions(&secret_file, std::fs::Permissions::from_mode(0o600))?;
+
+    let send = |secret_option: &str| {
+        let mut client = Client::new("", TESTING_INSECURE_KEY);
+        client.transport = Transport::Command(format!(
+            "COPIEPATE_TEST_SECRET={} '{}' --stdio --config '{}' {} --backend file --output-path /dev/null",
+            TESTING_INSECURE_SECRET,
+            env!("CARGO_BIN_EXE_copiepate"),
+            config.display(),
+            secret_option,
+        ));
+        client.send(b"Secret source")
+    };
+
+    // 1. The secret is read from a file, an environment variable or a command
+    send(&format!("--secret-file '{}'", secret_file.display()))?;
+    send("--secret-env COPIEPATE_TEST_SECRET")?;
+    send(&format!(
+        "--secret-command 'echo {TESTING_INSECURE_SECRET}'"
+    ))?;
+
+    // 2. Secret files readable by every user are refused
+    std::fs::set_permissions(&secret_file, std::fs::Permissions::from_mode(0o644))?;
+    assert!(send(&format!("--secret-file '{}'", secret_file.display())).is_err());
+
+    // 3. A single secret source can be set
+    assert!(send(&format!(
+        "--secret {TESTING_INSECURE_SECRET} --secret-env COPIEPATE_TEST_SECRET"
+    ))
+    .is_err());
 
     Ok(())
 }
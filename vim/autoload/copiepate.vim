@@ -14,10 +14,31 @@ function! copiepate#copyreg()
 endfunction
 
 function! copiepate#copy(data)
-    let output = system("copiepate", a:data)
-    if v:shell_error
+    let command = "copiepate"
+    if exists('g:copiepate_selection')
+        let command .= " --selection " . shellescape(g:copiepate_selection)
+    endif
+    let output = system(command, a:data)
+    if v:shell_error == 2
+        echohl ErrorMsg
+        echo "Data rejected by server: " . output
+        echohl None
+    elseif v:shell_error == 3
+        echohl ErrorMsg
+        echo "Server did not answer in time: " . output
+        echohl None
+    elseif v:shell_error
         echo output
     else
         echo "Data copied."
     endif
 endfunction
+
+function! copiepate#paste()
+    let output = system("copiepate --paste")
+    if v:shell_error
+        echo output
+    else
+        put =output
+    endif
+endfunction
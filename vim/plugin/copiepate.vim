@@ -10,6 +10,7 @@ let g:loaded_copiepate = 1
 
 command -range=% CopiePate call copiepate#copylines(<line1>, <line2>)
 command -range=% CopiePateReg call copiepate#copyreg()
+command CopiePatePaste call copiepate#paste()
 
 noremap <leader>y :CopiePateReg<CR>
 vnoremap <leader>y :CopiePate<CR>